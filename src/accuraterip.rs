@@ -0,0 +1,434 @@
+use std::{fmt, fs, path::Path};
+
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use log::{info, warn};
+
+use crate::disc_id::DiscId;
+
+/// Stereo samples in a sector
+const SAMPLES_PER_SECTOR: u32 = 588;
+/// AccurateRip leaves out the first and last 5 sectors of the disc, drives can't agree on them
+const SKIPPED_SAMPLES: u32 = 5 * SAMPLES_PER_SECTOR;
+/// The read offsets AccurateRip can tell apart, in stereo samples either way
+pub const MAX_READ_OFFSET: i32 = SKIPPED_SAMPLES as i32;
+/// The sector of every track AccurateRip keeps a checksum of, to detect the read offset by
+pub const OFFSET_SECTOR: i32 = 450;
+
+/// The checksums of a track, as AccurateRip and CUETools compute them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrackChecksums {
+    pub accuraterip_v1: u32,
+    pub accuraterip_v2: u32,
+    /// Plain CRC32 of the whole track, as in the CUETools logs
+    pub crc32: u32,
+}
+
+/// Computes the checksums of a track while it is read, from its start to its end
+pub struct Checksums {
+    v1: u32,
+    v2: u32,
+    crc32: crc32fast::Hasher,
+    /// The 1-based position of the next stereo sample within the track
+    position: u32,
+    /// The positions taken into account
+    check_from: u32,
+    check_to: u32,
+}
+
+impl Checksums {
+    /// For the `track`th audio track out of `audio_tracks`, `sectors` long. AccurateRip counts the
+    /// audio tracks alone: a mixed mode disc starts with a data track, an enhanced CD ends with
+    /// one.
+    pub fn new(track: u8, audio_tracks: u8, sectors: u32) -> Self {
+        let samples = sectors * SAMPLES_PER_SECTOR;
+        Self {
+            v1: 0,
+            v2: 0,
+            crc32: crc32fast::Hasher::new(),
+            position: 1,
+            // Off by one in the reference implementation, which everybody has to follow
+            check_from: if track == 1 { SKIPPED_SAMPLES - 1 } else { 0 },
+            check_to: if track == audio_tracks {
+                samples.saturating_sub(SKIPPED_SAMPLES)
+            } else {
+                samples
+            },
+        }
+    }
+
+    /// Take in the following samples of the track
+    pub fn update(&mut self, pcm: &[u8]) {
+        self.crc32.update(pcm);
+        for sample in pcm.chunks_exact(4) {
+            if (self.check_from..=self.check_to).contains(&self.position) {
+                // Both channels at once, the left one in the lower half
+                let sample = u32::from_le_bytes(sample.try_into().unwrap());
+                let product = u64::from(sample) * u64::from(self.position);
+                self.v1 = self.v1.wrapping_add(product as u32);
+                self.v2 = self
+                    .v2
+                    .wrapping_add(product as u32)
+                    .wrapping_add((product >> 32) as u32);
+            }
+            self.position += 1;
+        }
+    }
+
+    pub fn finish(self) -> TrackChecksums {
+        TrackChecksums {
+            accuraterip_v1: self.v1,
+            accuraterip_v2: self.v2,
+            crc32: self.crc32.finalize(),
+        }
+    }
+}
+
+/// How a track compares to the AccurateRip database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Accuracy {
+    /// The disc isn't in the database
+    Unknown,
+    /// The checksum matches the one submitted by `confidence` other people
+    Accurate { version: u8, confidence: u32 },
+    /// The disc is in the database, but none of the checksums match
+    Inaccurate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Verification {
+    pub checksums: TrackChecksums,
+    pub accuracy: Accuracy,
+}
+
+impl Verification {
+    /// The confidence as reported over D-Bus: -1 if the disc is unknown, 0 if inaccurate
+    pub fn confidence(&self) -> i32 {
+        match self.accuracy {
+            Accuracy::Unknown => -1,
+            Accuracy::Accurate { confidence, .. } => confidence as i32,
+            Accuracy::Inaccurate => 0,
+        }
+    }
+
+    /// Log how the track has been read
+    pub fn log(&self, track: u8) {
+        let TrackChecksums {
+            accuraterip_v1: v1,
+            accuraterip_v2: v2,
+            crc32,
+        } = self.checksums;
+        let message = format!(
+            "track {track}: CRC32 {crc32:08X}, AccurateRip v1 {v1:08X} v2 {v2:08X}, {}",
+            self.accuracy
+        );
+        match self.accuracy {
+            Accuracy::Inaccurate => warn!("{message}"),
+            Accuracy::Unknown | Accuracy::Accurate { .. } => info!("{message}"),
+        }
+    }
+}
+
+impl fmt::Display for Accuracy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Accuracy::Unknown => write!(f, "not in the AccurateRip database"),
+            Accuracy::Accurate {
+                version,
+                confidence,
+            } => write!(
+                f,
+                "accurately read (AccurateRip v{version}, confidence {confidence})"
+            ),
+            Accuracy::Inaccurate => write!(f, "not matching the AccurateRip database"),
+        }
+    }
+}
+
+/// What has been submitted for a track of a pressing
+struct PressingTrack {
+    confidence: u32,
+    crc: u32,
+    /// The checksum of sector `OFFSET_SECTOR` of the track alone
+    offset_crc: u32,
+}
+
+/// The checksums submitted for a pressing of a disc
+struct Pressing {
+    tracks: Vec<PressingTrack>,
+}
+
+/// What the AccurateRip database knows about a disc
+pub struct AccurateRip {
+    pressings: Vec<Pressing>,
+}
+
+/// The IDs a disc is looked up by in AccurateRip, which knows only about its audio tracks
+fn accuraterip_ids(disc_id: &DiscId) -> (u32, u32) {
+    let offsets = disc_id
+        .audio_lsns
+        .iter()
+        .chain([&disc_id.audio_end])
+        .map(|&lsn| lsn as u32);
+    offsets
+        .zip(1..)
+        .fold((0u32, 0u32), |(id1, id2), (offset, track)| {
+            (
+                id1.wrapping_add(offset),
+                id2.wrapping_add(offset.max(1).wrapping_mul(track)),
+            )
+        })
+}
+
+/// The file name of the disc in the AccurateRip database, as on the AccurateRip server
+pub fn file_name(disc_id: &DiscId) -> String {
+    let (id1, id2) = accuraterip_ids(disc_id);
+    format!(
+        "dBAR-{:03}-{id1:08x}-{id2:08x}-{:08x}.bin",
+        disc_id.audio_lsns.len(),
+        disc_id.freedb
+    )
+}
+
+impl AccurateRip {
+    /// Load the disc from the local AccurateRip database, a directory with the files as downloaded
+    /// from the AccurateRip server, either directly in it or in the same `a/b/c/` subdirectories.
+    /// None if the disc isn't there.
+    pub fn load(database: &Path, disc_id: &DiscId) -> Result<Option<Self>> {
+        let (id1, _) = accuraterip_ids(disc_id);
+        let name = file_name(disc_id);
+        let subdirs = format!("{:x}/{:x}/{:x}", id1 & 0xf, id1 >> 4 & 0xf, id1 >> 8 & 0xf);
+        let Some(path) = [database.join(&name), database.join(subdirs).join(&name)]
+            .into_iter()
+            .find(|path| path.exists())
+        else {
+            return Ok(None);
+        };
+        let data = fs::read(&path).with_context(|| format!("unable to read {}", path.display()))?;
+        Self::parse(&data, disc_id.audio_lsns.len())
+            .with_context(|| format!("invalid AccurateRip file {}", path.display()))
+            .map(Some)
+    }
+
+    /// A file is a list of pressings, each one a header and then confidence, checksum and offset
+    /// detection checksum of every track
+    fn parse(data: &[u8], tracks: usize) -> Result<Self> {
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let mut pressings = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            let count = data[at] as usize;
+            let size = 13 + count * 9;
+            if at + size > data.len() {
+                bail!("truncated pressing");
+            }
+            if count != tracks {
+                bail!("{count} tracks instead of {tracks}");
+            }
+            let tracks = (0..count)
+                .map(|track| {
+                    let entry = at + 13 + track * 9;
+                    PressingTrack {
+                        confidence: u32::from(data[entry]),
+                        crc: u32_at(entry + 1),
+                        offset_crc: u32_at(entry + 5),
+                    }
+                })
+                .collect();
+            pressings.push(Pressing { tracks });
+            at += size;
+        }
+        Ok(Self { pressings })
+    }
+
+    /// Compare the checksums of the audio track at `position` with the submitted ones
+    pub fn verify(&self, position: u8, checksums: &TrackChecksums) -> Accuracy {
+        let mut accuracy = Accuracy::Inaccurate;
+        for pressing in &self.pressings {
+            let Some(&PressingTrack {
+                confidence, crc, ..
+            }) = pressing.tracks.get(position as usize - 1)
+            else {
+                continue;
+            };
+            let version = if crc == checksums.accuraterip_v2 {
+                2
+            } else if crc == checksums.accuraterip_v1 {
+                1
+            } else {
+                continue;
+            };
+            match accuracy {
+                Accuracy::Accurate {
+                    confidence: best, ..
+                } if best >= confidence => {}
+                _ => {
+                    accuracy = Accuracy::Accurate {
+                        version,
+                        confidence,
+                    }
+                }
+            }
+        }
+        accuracy
+    }
+
+    /// Find the read offset `pcm` has been read with. `pcm` is the audio track at `position`,
+    /// read without any offset correction from `MAX_READ_OFFSET` samples before its sector
+    /// `OFFSET_SECTOR` to as many after it. Returns the offset and the confidence of the pressing
+    /// it matches.
+    pub fn detect_offset(&self, position: u8, pcm: &[u8]) -> Option<(i32, u32)> {
+        let samples = pcm
+            .chunks_exact(4)
+            .map(|sample| u32::from_le_bytes(sample.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let mut found: Option<(i32, u32)> = None;
+        for offset in -MAX_READ_OFFSET..=MAX_READ_OFFSET {
+            let start = (offset + MAX_READ_OFFSET) as usize;
+            let Some(sector) = samples.get(start..start + SAMPLES_PER_SECTOR as usize) else {
+                break;
+            };
+            // A v1 checksum of the sector alone
+            let crc = sector
+                .iter()
+                .zip(1u32..)
+                .fold(0u32, |crc, (&sample, position)| {
+                    crc.wrapping_add(sample.wrapping_mul(position))
+                });
+            for pressing in &self.pressings {
+                let Some(submitted) = pressing.tracks.get(position as usize - 1) else {
+                    continue;
+                };
+                // Silence would match any offset
+                if submitted.offset_crc != 0
+                    && submitted.offset_crc == crc
+                    && found.is_none_or(|(_, best)| submitted.confidence > best)
+                {
+                    found = Some((offset, submitted.confidence));
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Look the disc up in the local AccurateRip database at `database`
+pub fn lookup(database: &Path, disc_id: &DiscId) -> Option<AccurateRip> {
+    match AccurateRip::load(database, disc_id) {
+        Ok(Some(accuraterip)) => {
+            info!(
+                "disc found in the AccurateRip database, {} pressings",
+                accuraterip.pressings.len()
+            );
+            Some(accuraterip)
+        }
+        Ok(None) => {
+            info!(
+                "disc not found in the AccurateRip database, looked for {}",
+                file_name(disc_id)
+            );
+            None
+        }
+        Err(err) => {
+            warn!("{err}");
+            None
+        }
+    }
+}
+
+/// Verify `checksums` of the audio track at `position` against the database, if the disc is in it
+pub fn verify(
+    accuraterip: Option<&AccurateRip>,
+    position: u8,
+    checksums: TrackChecksums,
+) -> Verification {
+    Verification {
+        checksums,
+        accuracy: accuraterip.map_or(Accuracy::Unknown, |accuraterip| {
+            accuraterip.verify(position, &checksums)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_disc::FakeDisc, toc::Toc};
+
+    fn disc_id(spec: &str) -> DiscId {
+        let mut disc: FakeDisc = spec.parse().unwrap();
+        Toc::read(&mut disc).unwrap().disc_id()
+    }
+
+    /// A database file with a single pressing, each track submitted once with its checksum
+    fn database_file(disc_id: &DiscId, crcs: &[u32]) -> Vec<u8> {
+        let (id1, id2) = accuraterip_ids(disc_id);
+        let mut data = vec![crcs.len() as u8];
+        for id in [id1, id2, disc_id.freedb] {
+            data.extend(id.to_le_bytes());
+        }
+        for crc in crcs {
+            data.push(1);
+            data.extend(crc.to_le_bytes());
+            data.extend(0u32.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn first_and_last_audio_tracks_skip_five_sectors() {
+        let sectors = 20;
+        let samples = sectors * SAMPLES_PER_SECTOR;
+        // Silent but for what AccurateRip leaves out of the first and the last tracks
+        let pcm = (1..=samples)
+            .flat_map(|position| {
+                let skipped =
+                    position < SKIPPED_SAMPLES - 1 || position > samples - SKIPPED_SAMPLES;
+                u32::from(skipped).to_le_bytes()
+            })
+            .collect::<Vec<_>>();
+        let v1 = |track, audio_tracks| {
+            let mut checksums = Checksums::new(track, audio_tracks, sectors);
+            checksums.update(&pcm);
+            checksums.finish().accuraterip_v1
+        };
+        assert_eq!(v1(1, 1), 0);
+        assert_ne!(v1(1, 2), 0);
+        assert_ne!(v1(2, 3), 0);
+        assert_ne!(v1(3, 4), 0);
+    }
+
+    #[test]
+    fn plain_cd() {
+        let disc_id = disc_id("sine:10,sine:20");
+        // Tracks starting at 0 and 750, the lead-out at 2250
+        assert_eq!(accuraterip_ids(&disc_id), (3000, 1 + 750 * 2 + 2250 * 3));
+        assert_eq!(
+            file_name(&disc_id),
+            format!("dBAR-002-00000bb8-0000203b-{}.bin", disc_id.freedb_hex())
+        );
+    }
+
+    #[test]
+    fn enhanced_cd() {
+        let plain = disc_id("sine:10,sine:20");
+        let enhanced = disc_id("sine:10,sine:20/data:30");
+        assert_eq!(accuraterip_ids(&enhanced), accuraterip_ids(&plain));
+        // The FreeDB ID in the name counts the data track
+        assert_eq!(
+            file_name(&enhanced),
+            format!("dBAR-002-00000bb8-0000203b-{}.bin", enhanced.freedb_hex())
+        );
+
+        let database = std::env::temp_dir().join(format!("accuraterip-{}", std::process::id()));
+        fs::create_dir_all(&database).unwrap();
+        let data = database_file(&enhanced, &[0x1234, 0x5678]);
+        fs::write(database.join(file_name(&enhanced)), data).unwrap();
+        let accuraterip = AccurateRip::load(&database, &enhanced);
+        fs::remove_dir_all(&database).unwrap();
+        let accuraterip = accuraterip.unwrap().unwrap();
+        assert_eq!(accuraterip.pressings[0].tracks.len(), 2);
+    }
+}
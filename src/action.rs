@@ -1,6 +1,9 @@
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Action {
     Play(u8),
     Pause(u8),
+    /// Nothing is playing but the disc is still loaded, ready to start again
     Stop,
+    /// The disc is gone or we are exiting, the reader and the player must terminate
+    Quit,
 }
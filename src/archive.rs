@@ -0,0 +1,307 @@
+use std::{path::PathBuf, thread, time::Duration};
+
+use flume::{SendTimeoutError, Sender};
+use log::{info, warn};
+
+use crate::{
+    config::RipConfig, emphasis::Deemphasis, metadata::DiscMetadata, ring::SECTOR_SIZE, rip,
+    toc::Toc,
+};
+
+/// A track read completely, to be encoded
+struct Track {
+    track: u8,
+    pcm: Vec<u8>,
+    metadata: DiscMetadata,
+}
+
+/// Rips the disc in the background out of the sectors read anyway, one complete track at a time.
+/// The tracks are encoded in a thread of their own, the reader hands them over and moves on.
+pub struct Archive {
+    /// By track number, starting from 1
+    done: Vec<bool>,
+    tracks: Sender<Track>,
+    /// A complete track waiting for the encoder to be done with the one before. The audio of a
+    /// track takes tens of megabytes, no more than one waits.
+    waiting: Option<Track>,
+}
+
+impl Archive {
+    /// Archive the audio tracks in `toc`
+    pub fn new(config: RipConfig, metadata: &DiscMetadata, toc: &Toc) -> Self {
+        let total_tracks = toc.last_track();
+        let audio_tracks = toc.audio_tracks();
+        let emphasized = toc
+            .tracks
+            .iter()
+            .filter(|track| track.preemphasis)
+            .map(|track| track.number)
+            .collect::<Vec<_>>();
+        // Tracks already in the library from a previous time are left alone
+        let mut files = (1..=total_tracks)
+            .map(|track| {
+                rip::track_path(&config, metadata, track)
+                    .ok()
+                    .filter(|path| path.exists())
+            })
+            .collect::<Vec<_>>();
+        // Data tracks are never archived
+        let done = files
+            .iter()
+            .zip(1u8..)
+            .map(|(file, track)| file.is_some() || !audio_tracks.contains(&track))
+            .collect();
+
+        // The encoder takes a track only once done with the one before
+        let (tx, rx) = flume::bounded::<Track>(0);
+        thread::spawn(move || {
+            for Track {
+                track,
+                mut pcm,
+                metadata,
+            } in rx.iter()
+            {
+                // The metadata might have been found online since the disc was loaded, naming
+                // the track after what is in the library already
+                match rip::track_path(&config, &metadata, track) {
+                    Ok(path) if path.exists() => {
+                        info!("track {track} is in the library already");
+                        files[track as usize - 1] = Some(path);
+                    }
+                    _ => {
+                        if config.deemphasis && emphasized.contains(&track) {
+                            Deemphasis::default().process(&mut pcm);
+                        }
+                        match archive_track(&config, &metadata, track, total_tracks, &pcm) {
+                            Ok(path) => files[track as usize - 1] = Some(path),
+                            Err(err) => warn!("unable to archive track {track}: {err}"),
+                        }
+                    }
+                }
+
+                // The whole album is there, finish it off with the CUE sheet
+                if config.cue
+                    && let Some(files) = audio_tracks
+                        .iter()
+                        .map(|&track| Some((track, files[track as usize - 1].clone()?)))
+                        .collect::<Option<Vec<_>>>()
+                {
+                    // Flag the tracks left as they are
+                    let emphasized = if config.deemphasis {
+                        &[][..]
+                    } else {
+                        &emphasized
+                    };
+                    match rip::write_cue(&metadata, &files, emphasized) {
+                        Ok(cue) => info!("album archived, CUE sheet written to {}", cue.display()),
+                        Err(err) => warn!("unable to write the CUE sheet: {err}"),
+                    }
+                }
+            }
+        });
+
+        Self {
+            done,
+            tracks: tx,
+            waiting: None,
+        }
+    }
+
+    /// Whether `track` still has to be archived
+    pub fn wants(&self, track: u8) -> bool {
+        self.done.get(track as usize - 1) == Some(&false)
+    }
+
+    /// The first track still to be archived, other than `skip`
+    pub fn missing(&self, skip: Option<u8>) -> Option<u8> {
+        (1..=self.done.len() as u8).find(|&track| self.wants(track) && Some(track) != skip)
+    }
+
+    /// `pcm` is the whole of `track`, encode it and write it into the library. If another track
+    /// is still waiting for the encoder, `track` is left to be read again later.
+    pub fn complete(&mut self, track: u8, pcm: Vec<u8>, metadata: DiscMetadata) {
+        if !self.wants(track) {
+            return;
+        }
+        if !self.hand_over(Duration::ZERO) {
+            info!("the encoder is busy, track {track} will be archived later");
+            return;
+        }
+        self.done[track as usize - 1] = true;
+        self.waiting = Some(Track {
+            track,
+            pcm,
+            metadata,
+        });
+        self.hand_over(Duration::ZERO);
+    }
+
+    /// Hand the track waiting over to the encoder, giving it up to `timeout` to be done with the
+    /// one before. Returns false if the track is still waiting.
+    pub fn hand_over(&mut self, timeout: Duration) -> bool {
+        let Some(track) = self.waiting.take() else {
+            return true;
+        };
+        match self.tracks.send_timeout(track, timeout) {
+            Ok(()) => true,
+            Err(SendTimeoutError::Timeout(track)) => {
+                self.waiting = Some(track);
+                false
+            }
+            // The encoder is gone, there is nothing to wait for
+            Err(SendTimeoutError::Disconnected(_)) => true,
+        }
+    }
+}
+
+fn archive_track(
+    config: &RipConfig,
+    metadata: &DiscMetadata,
+    track: u8,
+    total_tracks: u8,
+    pcm: &[u8],
+) -> color_eyre::Result<PathBuf> {
+    let path = rip::track_path(config, metadata, track)?;
+    // A second of audio at a time, the encoder converts the samples
+    let mut chunks = pcm.chunks(SECTOR_SIZE * 75);
+    let flac = rip::encode(
+        || Ok(chunks.next().map(<[u8]>::to_vec)),
+        &rip::track_tags(metadata, track, total_tracks),
+    )?;
+    rip::write_track(&path, &flac)?;
+    info!("track {track} archived to {}", path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs, io,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        action::Action,
+        config::Config,
+        disc::DiscSource,
+        fake_disc::FakeDisc,
+        output::PcmOutput,
+        play_song::Player,
+        read_cd::Reader,
+        ring,
+        state::{PlayerState, Request},
+    };
+
+    const DISC: &str = "counter:1,sine:1";
+
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn rip_config(directory: &Path) -> RipConfig {
+        RipConfig {
+            directory: directory.to_path_buf(),
+            background: true,
+            ..RipConfig::default()
+        }
+    }
+
+    /// The files of the CUE sheet in `album`, once it has been written with `tracks` of them
+    fn cue_files(album: &Path, tracks: usize) -> Vec<String> {
+        let files = || {
+            fs::read_to_string(album.join("Fake disc.cue"))
+                .unwrap_or_default()
+                .lines()
+                .filter(|line| line.starts_with("FILE "))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        wait_until(|| files().len() == tracks);
+        files()
+    }
+
+    #[test]
+    fn played_disc_is_archived() {
+        let directory = std::env::temp_dir().join(format!("archive-{}", std::process::id()));
+        let mut config = Config {
+            rip: rip_config(&directory),
+            ..Config::default()
+        };
+        let nowhere = directory.join("nowhere");
+        config.metadata.database = nowhere.clone();
+        config.metadata.providers.clear();
+        config.accuraterip.database = nowhere;
+
+        let (tx, rx) = flume::bounded(2);
+        let state = Arc::new(Mutex::new(PlayerState::new(tx, rx)));
+        let (producer, consumer) = ring::ring(16);
+        let reader = {
+            let state = state.clone();
+            thread::spawn(move || {
+                let disc: FakeDisc = DISC.parse().unwrap();
+                Reader::new(state, Box::new(disc), producer, Arc::new(config))?.handle()
+            })
+        };
+        let player = {
+            let state = state.clone();
+            thread::spawn(move || {
+                let output = PcmOutput::new(Box::new(io::sink()));
+                Player::new(state, Box::new(output), consumer)?.handle()
+            })
+        };
+        wait_until(|| state.lock().unwrap().action == Action::Stop);
+        let album = directory.join("raspi-cd-player/Fake disc");
+        let files = cue_files(&album, 2);
+        let archived = ["01 - Counter.flac", "02 - Sine 440 Hz.flac"].map(|file| album.join(file));
+        let exist = archived.iter().all(|file| file.exists());
+        state.lock().unwrap().handle_request(Request::Quit);
+        player.join().unwrap().unwrap();
+        reader.join().unwrap().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(exist);
+        assert_eq!(
+            files,
+            [
+                "FILE \"01 - Counter.flac\" WAVE",
+                "FILE \"02 - Sine 440 Hz.flac\" WAVE"
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_in_the_library_are_left_alone() {
+        let directory = std::env::temp_dir().join(format!("library-{}", std::process::id()));
+        let mut disc: FakeDisc = DISC.parse().unwrap();
+        let toc = Toc::read(&mut disc).unwrap();
+        let cdtext = DiscMetadata::from_cdtext(disc.cdtext().unwrap());
+        // Only named after the disc once it has been looked up online
+        let mut unknown = cdtext.clone();
+        unknown.album = None;
+        let mut archive = Archive::new(rip_config(&directory), &unknown, &toc);
+        assert!(archive.wants(1) && archive.wants(2));
+
+        let album = directory.join("raspi-cd-player/Fake disc");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(album.join("01 - Counter.flac"), "archived before").unwrap();
+        let pcm = vec![0; SECTOR_SIZE * 75];
+        archive.complete(1, pcm.clone(), cdtext.clone());
+        wait_until(|| archive.hand_over(Duration::ZERO));
+        archive.complete(2, pcm, cdtext);
+        wait_until(|| archive.hand_over(Duration::ZERO));
+        let files = cue_files(&album, 2);
+        let first = fs::read_to_string(album.join("01 - Counter.flac")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(first, "archived before");
+        assert_eq!(files[0], "FILE \"01 - Counter.flac\" WAVE");
+        assert!(!archive.wants(1) && !archive.wants(2));
+    }
+}
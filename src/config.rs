@@ -0,0 +1,548 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use evdev::Key;
+use log::info;
+use serde::Deserialize;
+
+use crate::{accuraterip::MAX_READ_OFFSET, input, rip, state::Request};
+
+/// Where the configuration is looked up when none is given on the command line, in order
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => paths.push(PathBuf::from(dir)),
+        _ => {
+            if let Some(home) = std::env::var_os("HOME") {
+                paths.push(PathBuf::from(home).join(".config"));
+            }
+        }
+    }
+    paths.push(PathBuf::from("/etc"));
+    paths
+        .into_iter()
+        .map(|dir| dir.join("raspi-cd-player/config.toml"))
+        .collect()
+}
+
+/// Where the data of the player is kept
+fn data_dir() -> PathBuf {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".local/share"),
+    };
+    data_dir.join("raspi-cd-player")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub drive: DriveConfig,
+    pub paranoia: ParanoiaConfig,
+    pub playback: PlaybackConfig,
+    pub keys: KeysConfig,
+    pub metadata: MetadataConfig,
+    pub rip: RipConfig,
+    pub accuraterip: AccurateRipConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriveConfig {
+    /// The CD-ROM drive to play from
+    pub device: PathBuf,
+    /// Reading speed, as a multiple of the audio CD speed
+    pub speed: i32,
+    /// How many sectors are read from the drive at once
+    pub read_sectors: u32,
+    /// The read offset correction of the drive, in stereo samples, as listed by AccurateRip.
+    /// `raspi-cd-player offset` detects it with a disc in the AccurateRip database.
+    pub read_offset: i32,
+}
+
+impl Default for DriveConfig {
+    fn default() -> Self {
+        Self {
+            device: PathBuf::from("/dev/sr0"),
+            speed: 1,
+            read_sectors: 52,
+            read_offset: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParanoiaConfig {
+    /// Check the reads for errors and jitter, re-reading and concealing what is damaged
+    pub enabled: bool,
+    /// Sectors every read overlaps the previous one by, to detect jitter; 0 to not check
+    pub overlap: u32,
+    /// Re-read every sector until two reads agree, for drives that don't report C2 errors
+    pub verify: bool,
+    /// Re-read the sectors the drive reports C2 errors in
+    pub c2: bool,
+    /// How many times a read can be retried before the damaged sectors are concealed
+    pub retries: u32,
+}
+
+impl Default for ParanoiaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            overlap: 1,
+            verify: false,
+            c2: true,
+            retries: 20,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    /// Start playing the first track as soon as a disc is inserted
+    pub autoplay: bool,
+    /// How far seeking forward and backward moves, in seconds
+    pub seek_step: u64,
+    /// How much audio the reader can read ahead of the player, in seconds
+    pub read_ahead: u64,
+    /// Play the audio hidden before the first track as track 0, reached with Previous from it
+    pub hidden_track: bool,
+    /// Play the pregaps between the tracks, at the end of the track before them as CD players
+    /// do; otherwise they are skipped
+    pub pregaps: bool,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self {
+            autoplay: true,
+            seek_step: 10,
+            read_ahead: 30,
+            hidden_track: false,
+            pregaps: true,
+        }
+    }
+}
+
+impl PlaybackConfig {
+    pub fn seek_step(&self) -> Duration {
+        Duration::from_secs(self.seek_step)
+    }
+
+    /// The capacity of the ring between the reader and the player
+    pub fn read_ahead_sectors(&self) -> usize {
+        self.read_ahead as usize * 75
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataConfig {
+    /// The offline metadata database, a directory with a TOML file per disc
+    pub database: PathBuf,
+    /// The online services asked about discs missing from the database, in order
+    pub providers: Vec<ProviderKind>,
+    pub musicbrainz_url: String,
+    /// A server speaking the CDDB protocol over HTTP
+    pub gnudb_url: String,
+    /// How long an online service has to answer, in seconds
+    pub timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    MusicBrainz,
+    Gnudb,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        Self {
+            database: data_dir().join("discs"),
+            providers: vec![ProviderKind::MusicBrainz, ProviderKind::Gnudb],
+            musicbrainz_url: "https://musicbrainz.org".to_string(),
+            gnudb_url: "https://gnudb.gnudb.org".to_string(),
+            timeout: 15,
+        }
+    }
+}
+
+impl MetadataConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RipConfig {
+    /// Where the ripped discs are written
+    pub directory: PathBuf,
+    /// The path of every track within `directory`, without the extension. Placeholders:
+    /// {albumartist}, {album}, {artist}, {title}, {track}, {year}, {genre}, {discid}
+    pub template: String,
+    /// Write a CUE sheet next to the tracks
+    pub cue: bool,
+    /// Keep the tracks read while playing and archive them into `directory`
+    pub background: bool,
+    /// De-emphasize the tracks mastered with pre-emphasis, instead of flagging them in the CUE
+    /// sheet
+    pub deemphasis: bool,
+}
+
+impl Default for RipConfig {
+    fn default() -> Self {
+        let music_dir = match std::env::var_os("XDG_MUSIC_DIR") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join("Music"),
+        };
+        Self {
+            directory: music_dir,
+            template: "{albumartist}/{album}/{track} - {title}".to_string(),
+            cue: true,
+            background: false,
+            deemphasis: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccurateRipConfig {
+    /// The local AccurateRip database, a directory with the `dBAR-*.bin` files downloaded from
+    /// the AccurateRip server
+    pub database: PathBuf,
+}
+
+impl Default for AccurateRipConfig {
+    fn default() -> Self {
+        Self {
+            database: data_dir().join("accuraterip"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// Characters typed in the Wayland window
+    pub window: HashMap<String, Binding>,
+    /// Keys of the keyboards and remotes read through evdev, by their evdev name (`KEY_*`)
+    pub input: HashMap<String, Binding>,
+    /// Same as `input`, but only used when there is no Wayland window which would get these keys
+    /// already
+    pub keyboard: HashMap<String, Binding>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        let bindings = |bindings: &[(&str, Binding)]| {
+            bindings
+                .iter()
+                .map(|(key, binding)| (key.to_string(), *binding))
+                .collect()
+        };
+        Self {
+            window: bindings(&[
+                (" ", Binding::TogglePlay),
+                ("<", Binding::Previous),
+                (">", Binding::Next),
+                ("[", Binding::PreviousIndex),
+                ("]", Binding::NextIndex),
+                (",", Binding::SeekBackward),
+                (".", Binding::SeekForward),
+                ("q", Binding::Quit),
+            ]),
+            input: bindings(&[
+                ("KEY_PLAYPAUSE", Binding::TogglePlay),
+                ("KEY_PLAY", Binding::Play),
+                ("KEY_PLAYCD", Binding::Play),
+                ("KEY_PAUSE", Binding::Pause),
+                ("KEY_PAUSECD", Binding::Pause),
+                ("KEY_STOP", Binding::Stop),
+                ("KEY_STOPCD", Binding::Stop),
+                ("KEY_NEXTSONG", Binding::Next),
+                ("KEY_PREVIOUSSONG", Binding::Previous),
+                // Remotes send these for their skip and replay buttons
+                ("KEY_NEXT", Binding::Next),
+                ("KEY_PREVIOUS", Binding::Previous),
+                ("KEY_FASTFORWARD", Binding::SeekForward),
+                ("KEY_REWIND", Binding::SeekBackward),
+                ("KEY_EJECTCD", Binding::Eject),
+                ("KEY_EJECTCLOSECD", Binding::Eject),
+            ]),
+            keyboard: bindings(&[
+                ("KEY_SPACE", Binding::TogglePlay),
+                ("KEY_LEFT", Binding::Previous),
+                ("KEY_RIGHT", Binding::Next),
+                ("KEY_LEFTBRACE", Binding::PreviousIndex),
+                ("KEY_RIGHTBRACE", Binding::NextIndex),
+                ("KEY_COMMA", Binding::SeekBackward),
+                ("KEY_DOT", Binding::SeekForward),
+                ("KEY_Q", Binding::Quit),
+            ]),
+        }
+    }
+}
+
+impl KeysConfig {
+    /// The evdev bindings, `with_keyboard` when there is no window getting the keyboard keys
+    pub fn input_bindings(&self, with_keyboard: bool) -> HashMap<Key, Binding> {
+        let keyboard = with_keyboard
+            .then_some(&self.keyboard)
+            .into_iter()
+            .flatten();
+        self.input
+            .iter()
+            .chain(keyboard)
+            // The names have been checked when loading the configuration
+            .filter_map(|(name, binding)| Some((input::key_by_name(name)?, *binding)))
+            .collect()
+    }
+}
+
+/// What a key does when pressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Binding {
+    TogglePlay,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    NextIndex,
+    PreviousIndex,
+    SeekForward,
+    SeekBackward,
+    Eject,
+    Rip,
+    Quit,
+}
+
+impl Binding {
+    pub fn request(self) -> Request {
+        match self {
+            Binding::TogglePlay => Request::TogglePlay,
+            Binding::Play => Request::Play,
+            Binding::Pause => Request::Pause,
+            Binding::Stop => Request::Stop,
+            Binding::Next => Request::NextTrack,
+            Binding::Previous => Request::PreviousTrack,
+            Binding::NextIndex => Request::NextIndex,
+            Binding::PreviousIndex => Request::PreviousIndex,
+            Binding::SeekForward => Request::SeekForward,
+            Binding::SeekBackward => Request::SeekBackward,
+            Binding::Eject => Request::Eject,
+            Binding::Rip => Request::Rip,
+            Binding::Quit => Request::Quit,
+        }
+    }
+}
+
+/// Settings given on the command line, taking precedence over the configuration file
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub device: Option<PathBuf>,
+    pub speed: Option<i32>,
+    pub autoplay: Option<bool>,
+}
+
+impl Config {
+    /// Load the configuration from `path`, or from the first one found in the usual places.
+    /// Without any configuration file the defaults are used.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match config_paths().into_iter().find(|path| path.exists()) {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+        info!("loading the configuration from {}", path.display());
+        let config = fs::read_to_string(&path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        toml::from_str(&config).with_context(|| format!("invalid configuration {}", path.display()))
+    }
+
+    /// Replace the values of the configuration file with those given on the command line
+    pub fn apply(&mut self, overrides: Overrides) {
+        if let Some(device) = overrides.device {
+            self.drive.device = device;
+        }
+        if let Some(speed) = overrides.speed {
+            self.drive.speed = speed;
+        }
+        if let Some(autoplay) = overrides.autoplay {
+            self.playback.autoplay = autoplay;
+        }
+    }
+
+    /// Check the values that can't be checked by their type alone, after any override has been
+    /// applied
+    pub fn validate(&self) -> Result<()> {
+        if !self.drive.device.is_absolute() {
+            bail!(
+                "drive.device: {} must be an absolute path",
+                self.drive.device.display()
+            );
+        }
+        if self.drive.speed < 1 {
+            bail!("drive.speed: the speed must be at least 1");
+        }
+        // A second of audio at most, larger reads are refused by most drives
+        if !(1..=75).contains(&self.drive.read_sectors) {
+            bail!("drive.read_sectors: must be between 1 and 75");
+        }
+        if self.drive.read_offset.abs() > MAX_READ_OFFSET {
+            bail!("drive.read_offset: must be between -{MAX_READ_OFFSET} and {MAX_READ_OFFSET}");
+        }
+        // Shifting the samples takes a sector more than those returned
+        if self.drive.read_offset != 0 && self.drive.read_sectors < 2 {
+            bail!("drive.read_sectors: must be at least 2 with a read offset");
+        }
+        if self.paranoia.overlap > 75 {
+            bail!("paranoia.overlap: must be 75 sectors at most");
+        }
+        // Verifying takes a second read of every sector, without it everything is concealed
+        if self.paranoia.verify && self.paranoia.retries == 0 {
+            bail!("paranoia.retries: must be at least 1 to verify the reads");
+        }
+        if self.playback.seek_step == 0 {
+            bail!("playback.seek_step: must be at least 1 second");
+        }
+        if self.playback.read_ahead == 0 {
+            bail!("playback.read_ahead: must be at least 1 second");
+        }
+        for (key, url) in [
+            ("musicbrainz_url", &self.metadata.musicbrainz_url),
+            ("gnudb_url", &self.metadata.gnudb_url),
+        ] {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("metadata.{key}: {url} must be an http or https URL");
+            }
+        }
+        if self.metadata.timeout == 0 {
+            bail!("metadata.timeout: must be at least 1 second");
+        }
+        if let Err(err) = rip::check_template(&self.rip.template) {
+            bail!("rip.template: {err}");
+        }
+        for key in self.keys.window.keys() {
+            if key.chars().count() != 1 {
+                bail!("keys.window.\"{key}\": a window binding must be a single character");
+            }
+        }
+        for (table, bindings) in [
+            ("input", &self.keys.input),
+            ("keyboard", &self.keys.keyboard),
+        ] {
+            for key in bindings.keys() {
+                if input::key_by_name(key).is_none() {
+                    bail!("keys.{table}.{key}: unknown key, expected an evdev name like KEY_PLAYPAUSE");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_takes_a_retry() {
+        let mut config = Config::default();
+        config.paranoia.verify = true;
+        assert!(config.validate().is_ok());
+        config.paranoia.retries = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[drive]\ndevice = \"/dev/sr1\"\nspeed = 4\n\n[playback]\nautoplay = false\n",
+        )
+        .unwrap();
+        let mut config = Config::load(Some(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.drive.device, Path::new("/dev/sr1"));
+        assert_eq!(config.drive.speed, 4);
+        assert!(!config.playback.autoplay);
+
+        // Nothing given on the command line
+        config.apply(Overrides::default());
+        assert_eq!(config.drive.device, Path::new("/dev/sr1"));
+        assert_eq!(config.drive.speed, 4);
+        assert!(!config.playback.autoplay);
+
+        config.apply(Overrides {
+            device: Some(PathBuf::from("/dev/sr2")),
+            speed: Some(8),
+            autoplay: Some(true),
+        });
+        assert_eq!(config.drive.device, Path::new("/dev/sr2"));
+        assert_eq!(config.drive.speed, 8);
+        assert!(config.playback.autoplay);
+        assert!(config.validate().is_ok());
+
+        // The overrides are validated as well
+        config.apply(Overrides {
+            device: Some(PathBuf::from("sr0")),
+            ..Overrides::default()
+        });
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn bad_template() {
+        for template in ["{album}/{track} - {name}", "{album}/{track", "{album}/{}"] {
+            let mut config = Config::default();
+            config.rip.template = template.to_string();
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.starts_with("rip.template: "), "{template}: {err}");
+        }
+    }
+
+    #[test]
+    fn bad_bindings() {
+        let mut config = Config::default();
+        config.keys.window.insert("ab".to_string(), Binding::Play);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("keys.window."), "{err}");
+
+        let mut config = Config::default();
+        config
+            .keys
+            .input
+            .insert("KEY_NOPE".to_string(), Binding::Play);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("keys.input.KEY_NOPE: "), "{err}");
+
+        let mut config = Config::default();
+        config
+            .keys
+            .keyboard
+            .insert("space".to_string(), Binding::TogglePlay);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("keys.keyboard.space: "), "{err}");
+
+        // An unknown action is refused when reading the file
+        assert!(toml::from_str::<Config>("[keys.input]\nKEY_PLAY = \"dance\"\n").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{disc_id::DiscId, import};
+
+/// What the database knows about a disc
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Release {
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    /// The cover art image, relative to the database directory if not absolute
+    pub cover: Option<PathBuf>,
+    /// Starting from the first track
+    pub tracks: Vec<ReleaseTrack>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReleaseTrack {
+    pub title: Option<String>,
+    /// Only when different from the artist of the whole release
+    pub artist: Option<String>,
+}
+
+/// An offline metadata store: a directory with a TOML file per disc, named after either its
+/// MusicBrainz or its FreeDB disc ID
+#[derive(Clone)]
+pub struct Database {
+    dir: PathBuf,
+}
+
+impl Database {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.toml"))
+    }
+
+    /// Look up a disc by its MusicBrainz ID first, then by its FreeDB one
+    pub fn lookup(&self, disc_id: &DiscId) -> Result<Option<Release>> {
+        for key in [disc_id.musicbrainz.clone(), disc_id.freedb_hex()] {
+            let path = self.path(&key);
+            if !path.exists() {
+                continue;
+            }
+            let release = fs::read_to_string(&path)
+                .with_context(|| format!("unable to read {}", path.display()))?;
+            let mut release: Release = toml::from_str(&release)
+                .with_context(|| format!("invalid disc metadata {}", path.display()))?;
+            release.cover = release.cover.map(|cover| self.dir.join(cover));
+            return Ok(Some(release));
+        }
+        Ok(None)
+    }
+
+    /// Store `release` under `key`, a MusicBrainz or FreeDB disc ID
+    pub fn store(&self, key: &str, release: &Release) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("unable to create {}", self.dir.display()))?;
+        let path = self.path(key);
+        fs::write(&path, toml::to_string_pretty(release)?)
+            .with_context(|| format!("unable to write {}", path.display()))
+    }
+
+    /// Store every disc found in a MusicBrainz release JSON or an xmcd file, returning how many
+    pub fn import(&self, path: &Path) -> Result<usize> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        let releases = if content.trim_start().starts_with('{') {
+            import::musicbrainz(&content)
+        } else {
+            import::xmcd(&content)
+        }
+        .with_context(|| format!("unable to import {}", path.display()))?;
+
+        for (key, release) in &releases {
+            info!(
+                "importing {} by {} as {key}",
+                release.album.as_deref().unwrap_or("unknown album"),
+                release.artist.as_deref().unwrap_or("unknown artist")
+            );
+            self.store(key, release)?;
+        }
+        Ok(releases.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(name: &str) -> Database {
+        let dir = std::env::temp_dir().join(format!("database-{name}-{}", std::process::id()));
+        Database::new(dir)
+    }
+
+    fn disc_id() -> DiscId {
+        let lsns = [0, 18751];
+        DiscId::new(1, &lsns, 30000, &lsns, 30000)
+    }
+
+    #[test]
+    fn round_trip() {
+        let database = database("round-trip");
+        let release = Release {
+            album: Some("Album".to_string()),
+            artist: Some("Artist".to_string()),
+            year: Some(1999),
+            genre: Some("Rock".to_string()),
+            cover: Some(PathBuf::from("covers/album.jpg")),
+            tracks: vec![
+                ReleaseTrack {
+                    title: Some("One".to_string()),
+                    artist: None,
+                },
+                ReleaseTrack {
+                    title: None,
+                    artist: Some("Guest".to_string()),
+                },
+            ],
+        };
+        let disc_id = disc_id();
+        let missing = database.lookup(&disc_id).unwrap();
+        database.store(&disc_id.musicbrainz, &release).unwrap();
+        let found = database.lookup(&disc_id).unwrap();
+        fs::remove_dir_all(&database.dir).unwrap();
+
+        assert!(missing.is_none());
+        let found = found.unwrap();
+        assert_eq!(found.album, release.album);
+        assert_eq!(found.artist, release.artist);
+        assert_eq!(found.year, release.year);
+        assert_eq!(found.genre, release.genre);
+        // Relative to the database
+        assert_eq!(found.cover, Some(database.dir.join("covers/album.jpg")));
+        let tracks = found
+            .tracks
+            .iter()
+            .map(|track| (track.title.as_deref(), track.artist.as_deref()));
+        assert!(tracks.eq([(Some("One"), None), (None, Some("Guest"))]));
+    }
+
+    #[test]
+    fn freedb_fallback() {
+        let database = database("freedb");
+        let disc_id = disc_id();
+        fs::create_dir_all(&database.dir).unwrap();
+        // Written by hand, whatever is missing is left out
+        fs::write(
+            database.path(&disc_id.freedb_hex()),
+            "album = \"Album\"\n\n[[tracks]]\ntitle = \"One\"\n",
+        )
+        .unwrap();
+        let found = database.lookup(&disc_id);
+        fs::write(database.path(&disc_id.musicbrainz), "album = 1\n").unwrap();
+        let invalid = database.lookup(&disc_id);
+        fs::remove_dir_all(&database.dir).unwrap();
+
+        let found = found.unwrap().unwrap();
+        assert_eq!(found.album.as_deref(), Some("Album"));
+        assert_eq!(found.artist, None);
+        assert_eq!(found.tracks[0].title.as_deref(), Some("One"));
+        // The MusicBrainz one comes first
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn import_xmcd() {
+        let database = database("import");
+        fs::create_dir_all(&database.dir).unwrap();
+        let file = database.dir.join("disc.xmcd");
+        fs::write(
+            &file,
+            "# xmcd\nDISCID=830abf0a,0a0b0c0d\nDTITLE=Artist / Album\nTTITLE0=One\n",
+        )
+        .unwrap();
+        let imported = database.import(&file).unwrap();
+        let stored = ["830abf0a", "0a0b0c0d"].map(|key| database.path(key).exists());
+        fs::remove_dir_all(&database.dir).unwrap();
+
+        assert_eq!(imported, 2);
+        assert_eq!(stored, [true, true]);
+    }
+}
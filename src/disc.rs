@@ -0,0 +1,628 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+    os::unix::prelude::OsStrExt,
+    path::Path,
+};
+
+use color_eyre::{eyre::bail, Result};
+use libcdio_sys::*;
+use log::info;
+
+use crate::{
+    config::Config,
+    fake_disc::FakeDisc,
+    metadata::{CdText, TextFields},
+    paranoia::Paranoia,
+    ring::SECTOR_SIZE,
+};
+
+/// The C2 error pointers of a sector, a bit for every byte
+pub const C2_SIZE: usize = SECTOR_SIZE / 8;
+/// Sectors between the last track of a session and the first one of the next: the lead-out of
+/// the first session, the lead-in of the next one and the pregap of its first track
+pub const SESSION_GAP: i32 = 11400;
+/// The formatted Q subchannel of a sector, as returned by the drive
+const SUBCHANNEL_Q_SIZE: usize = 16;
+
+/// Something we can read CD-DA from: a physical drive or a ripped disc image.
+pub trait DiscSource {
+    fn first_track_num(&self) -> Result<u8>;
+    fn last_track_num(&self) -> Result<u8>;
+    fn num_tracks(&self) -> Result<u8>;
+    /// Logical sector number where `track` starts
+    fn track_lsn(&self, track: u8) -> Result<i32>;
+    /// The last logical sector number of `track`
+    fn track_last_lsn(&self, track: u8) -> Result<i32>;
+    /// Whether `track` is an audio track, rather than a data one
+    fn track_is_audio(&self, track: u8) -> Result<bool>;
+    /// Where the first track of the last session starts, 0 if there is a single session
+    fn last_session_lsn(&self) -> Result<i32>;
+    /// Where the pregap (INDEX 00) of `track` starts, None if unknown
+    fn track_pregap_lsn(&self, _track: u8) -> Result<Option<i32>> {
+        Ok(None)
+    }
+    /// The track and index the sector at `lsn` belongs to, as told by its Q subchannel. None if
+    /// the subchannels can't be read.
+    fn track_index(&mut self, _lsn: i32) -> Result<Option<(u8, u8)>> {
+        Ok(None)
+    }
+    /// Whether `track` was mastered with pre-emphasis, and has to be de-emphasized
+    fn track_preemphasis(&self, _track: u8) -> Result<bool> {
+        Ok(false)
+    }
+    /// The Media Catalog Number of the disc, its UPC/EAN barcode, None if it has none
+    fn mcn(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// The International Standard Recording Code of `track`, None if it has none
+    fn track_isrc(&self, _track: u8) -> Result<Option<String>> {
+        Ok(None)
+    }
+    /// Read `sectors` raw audio sectors starting at `lsn` into `buf`
+    fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()>;
+    /// Same as `read_audio_sectors`, also filling `c2` with the C2 error pointers of the sectors
+    /// read, a bit per byte. Returns false if the C2 error pointers aren't available.
+    fn read_audio_sectors_c2(
+        &mut self,
+        buf: &mut [u8],
+        _c2: &mut [u8],
+        lsn: i32,
+        sectors: u32,
+    ) -> Result<bool> {
+        self.read_audio_sectors(buf, lsn, sectors)?;
+        Ok(false)
+    }
+    fn eject(&mut self) -> Result<()>;
+    /// Returns true if the media has changed since the last call
+    fn media_changed(&mut self) -> Result<bool>;
+    /// Every CD-Text block on the disc, one per language
+    fn cdtext(&self) -> Result<Vec<CdText>>;
+}
+
+/// Open the disc image at `image` if any, otherwise the configured drive.
+/// An image named `fake:<spec>` is a synthetic disc generated in memory.
+/// The reads go through the paranoia layer, unless disabled.
+pub fn open(image: Option<&Path>, config: &Config) -> Result<Box<dyn DiscSource>> {
+    let source: Box<dyn DiscSource> = match image {
+        Some(image) => match image.to_str().and_then(|image| image.strip_prefix("fake:")) {
+            Some(spec) => Box::new(spec.parse::<FakeDisc>()?),
+            None => Box::new(Image::open(image)?),
+        },
+        None => Box::new(Drive::open(&config.drive.device, config.drive.speed)?),
+    };
+    if !config.paranoia.enabled {
+        return Ok(source);
+    }
+    Ok(Box::new(Paranoia::new(source, config.paranoia.clone())?))
+}
+
+/// Thin owning wrapper around a libcdio handle, shared by every libcdio driver
+struct Cdio(*mut _CdIo);
+
+impl Cdio {
+    fn open(source: *const i8, driver_id: driver_id_t) -> Result<Self> {
+        let cdio = unsafe { cdio_open(source, driver_id) };
+        if cdio.is_null() {
+            bail!("unable to open the CD");
+        }
+        Ok(Self(cdio))
+    }
+
+    fn first_track_num(&self) -> Result<u8> {
+        match unsafe { cdio_get_first_track_num(self.0) } {
+            0xFF => bail!("invalid CD"),
+            track => Ok(track),
+        }
+    }
+
+    fn last_track_num(&self) -> Result<u8> {
+        match unsafe { cdio_get_last_track_num(self.0) } {
+            0xFF => bail!("invalid CD"),
+            track => Ok(track),
+        }
+    }
+
+    fn num_tracks(&self) -> Result<u8> {
+        match unsafe { cdio_get_num_tracks(self.0) } {
+            0xFF => bail!("invalid CD"),
+            tracks => Ok(tracks),
+        }
+    }
+
+    fn track_lsn(&self, track: u8) -> Result<i32> {
+        match unsafe { cdio_get_track_lsn(self.0, track) } {
+            lsn if lsn == CDIO_INVALID_LSN => bail!("error reading cd"),
+            lsn => Ok(lsn),
+        }
+    }
+
+    fn track_last_lsn(&self, track: u8) -> Result<i32> {
+        match unsafe { cdio_get_track_last_lsn(self.0, track) } {
+            lsn if lsn == CDIO_INVALID_LSN => bail!("error reading cd"),
+            lsn => Ok(lsn),
+        }
+    }
+
+    fn track_is_audio(&self, track: u8) -> Result<bool> {
+        match unsafe { cdio_get_track_format(self.0, track) } {
+            track_format_t_TRACK_FORMAT_ERROR => bail!("error reading cd"),
+            format => Ok(format == track_format_t_TRACK_FORMAT_AUDIO),
+        }
+    }
+
+    fn last_session_lsn(&self) -> Result<i32> {
+        let mut lsn = 0;
+        match unsafe { cdio_get_last_session(self.0, &mut lsn) } {
+            driver_return_code_t_DRIVER_OP_SUCCESS => Ok(lsn),
+            // Disc images don't know about sessions
+            driver_return_code_t_DRIVER_OP_UNSUPPORTED => Ok(0),
+            _ => bail!("unable to read the sessions"),
+        }
+    }
+
+    fn track_pregap_lsn(&self, track: u8) -> Result<Option<i32>> {
+        // Only the disc images list the pregaps
+        match unsafe { cdio_get_track_pregap_lsn(self.0, track) } {
+            lsn if lsn == CDIO_INVALID_LSN => Ok(None),
+            lsn => Ok(Some(lsn)),
+        }
+    }
+
+    fn track_preemphasis(&self, track: u8) -> Result<bool> {
+        match unsafe { cdio_get_track_preemphasis(self.0, track) } {
+            track_flag_t_CDIO_TRACK_FLAG_ERROR => bail!("error reading cd"),
+            flag => Ok(flag == track_flag_t_CDIO_TRACK_FLAG_TRUE),
+        }
+    }
+
+    fn mcn(&self) -> Result<Option<String>> {
+        let mcn = unsafe { cdio_get_mcn(self.0) };
+        // Discs without one have it all zeros
+        Ok(owned_c_string(mcn).filter(|mcn| mcn.bytes().any(|digit| digit != b'0')))
+    }
+
+    fn track_isrc(&self, track: u8) -> Result<Option<String>> {
+        let isrc = unsafe { cdio_get_track_isrc(self.0, track) };
+        Ok(owned_c_string(isrc))
+    }
+
+    fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()> {
+        assert!(buf.len() >= (CDIO_CD_FRAMESIZE_RAW * sectors) as usize);
+        if unsafe {
+            cdio_read_audio_sectors(
+                self.0,
+                buf.as_mut_ptr() as *mut std::ffi::c_void,
+                lsn,
+                sectors,
+            )
+        } != driver_return_code_t_DRIVER_OP_SUCCESS
+        {
+            bail!("error reading sector");
+        }
+        Ok(())
+    }
+
+    fn cdtext(&self) -> Result<Vec<CdText>> {
+        // The CD-Text is owned by the handle, it must not be freed
+        let cdtext = unsafe { cdio_get_cdtext(self.0) };
+        if cdtext.is_null() {
+            return Ok(Vec::new());
+        }
+        let languages = unsafe { cdtext_list_languages(cdtext) };
+        if languages.is_null() {
+            return Ok(Vec::new());
+        }
+
+        let mut blocks = Vec::new();
+        // There are at most 8 blocks, the unused ones have an unknown language
+        for i in 0..8 {
+            let language = unsafe { *languages.add(i) };
+            if (i > 0 && language == cdtext_lang_t_CDTEXT_LANGUAGE_UNKNOWN)
+                || !unsafe { cdtext_select_language(cdtext, language) }
+            {
+                continue;
+            }
+            let block = cdtext_block(cdtext, language);
+            if !block.disc.is_empty() || !block.tracks.is_empty() {
+                blocks.push(block);
+            }
+        }
+        Ok(blocks)
+    }
+}
+
+/// Read the currently selected CD-Text block
+fn cdtext_block(cdtext: *mut cdtext_t, language: cdtext_lang_t) -> CdText {
+    let first_track = unsafe { cdtext_get_first_track(cdtext) };
+    let last_track = unsafe { cdtext_get_last_track(cdtext) };
+    let tracks = (first_track.max(1)..=last_track)
+        .map(|track| (track, cdtext_fields(cdtext, track)))
+        .filter(|(_, fields)| !fields.is_empty())
+        .collect::<BTreeMap<_, _>>();
+
+    // Prefer the free text genre, if any, to the generic genre code
+    let genre = cdtext_field(cdtext, cdtext_field_t_CDTEXT_FIELD_GENRE, 0).or_else(|| {
+        match unsafe { cdtext_get_genre(cdtext) } {
+            cdtext_genre_t_CDTEXT_GENRE_UNUSED | cdtext_genre_t_CDTEXT_GENRE_UNDEFINED => None,
+            genre => c_string(unsafe { cdtext_genre2str(genre) }),
+        }
+    });
+
+    CdText {
+        language: c_string(unsafe { cdtext_lang2str(language) }).unwrap_or_default(),
+        genre,
+        disc: cdtext_fields(cdtext, 0),
+        tracks,
+    }
+}
+
+/// The text fields of `track`, or of the whole disc for track 0
+fn cdtext_fields(cdtext: *mut cdtext_t, track: u8) -> TextFields {
+    let field = |field| cdtext_field(cdtext, field, track);
+    TextFields {
+        title: field(cdtext_field_t_CDTEXT_FIELD_TITLE),
+        performer: field(cdtext_field_t_CDTEXT_FIELD_PERFORMER),
+        songwriter: field(cdtext_field_t_CDTEXT_FIELD_SONGWRITER),
+        composer: field(cdtext_field_t_CDTEXT_FIELD_COMPOSER),
+        arranger: field(cdtext_field_t_CDTEXT_FIELD_ARRANGER),
+        message: field(cdtext_field_t_CDTEXT_FIELD_MESSAGE),
+    }
+}
+
+fn cdtext_field(cdtext: *mut cdtext_t, field: cdtext_field_t, track: u8) -> Option<String> {
+    // libcdio has already converted the text to UTF-8
+    c_string(unsafe { cdtext_get_const(cdtext, field, track) })
+}
+
+/// A copy of a C string owned by libcdio, None if null or blank
+fn c_string(text: *const i8) -> Option<String> {
+    if text.is_null() {
+        return None;
+    }
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Same as `c_string`, for a C string that libcdio hands over, freeing it
+fn owned_c_string(text: *mut i8) -> Option<String> {
+    let string = c_string(text);
+    if !text.is_null() {
+        unsafe { cdio_free(text as *mut std::ffi::c_void) };
+    }
+    string
+}
+
+impl Drop for Cdio {
+    fn drop(&mut self) {
+        // The handle is already gone if the media has been ejected
+        if !self.0.is_null() {
+            unsafe { cdio_destroy(self.0) };
+        }
+    }
+}
+
+/// A physical CD-ROM drive, accessed through libcdio
+pub struct Drive {
+    cdio: Cdio,
+    /// Cleared the first time the drive refuses to return the C2 error pointers
+    c2: bool,
+    /// Cleared the first time the drive refuses to return the Q subchannel
+    subchannel_q: bool,
+}
+
+impl Drive {
+    /// Open `device`, reading at `speed`
+    pub fn open(device: &Path, speed: i32) -> Result<Self> {
+        if !Drive::has_audio_cd(device) {
+            bail!("There is no CD-DA in {}", device.display());
+        }
+        let device = CString::new(device.as_os_str().as_bytes())?;
+        let cdio = Cdio::open(device.as_ptr(), driver_id_t_DRIVER_LINUX)?;
+        unsafe {
+            cdio_set_speed(cdio.0, speed);
+        }
+
+        Ok(Self {
+            cdio,
+            c2: true,
+            subchannel_q: true,
+        })
+    }
+
+    /// Whether `device` is a CD-ROM drive with a CD-DA in it
+    pub fn has_audio_cd(device: &Path) -> bool {
+        let mut driver_id = Box::new(driver_id_t_DRIVER_LINUX);
+        let all_cd_drives = unsafe { cdio_get_devices_ret(&mut *driver_id) };
+        let cdda_drives = unsafe {
+            cdio_get_devices_with_cap(
+                all_cd_drives,
+                cdio_fs_t_CDIO_FS_AUDIO.try_into().unwrap(),
+                0,
+            )
+        };
+        unsafe { cdio_free_device_list(all_cd_drives) };
+        if cdda_drives.is_null() {
+            return false;
+        }
+
+        let mut found = false;
+        let mut drive = cdda_drives;
+        while !found && !unsafe { *drive }.is_null() {
+            found = unsafe { CStr::from_ptr(*drive) }.to_bytes() == device.as_os_str().as_bytes();
+            drive = unsafe { drive.add(1) };
+        }
+        unsafe { cdio_free_device_list(cdda_drives) };
+        found
+    }
+
+    /// Read `sectors` sectors from `lsn` into `blocks`, each followed by its formatted Q
+    /// subchannel. Returns false if the drive fails to.
+    fn read_subchannel_q(&self, blocks: &mut [u8], lsn: i32, sectors: u32) -> bool {
+        let result = unsafe {
+            mmc_read_cd(
+                self.cdio.0,
+                blocks.as_mut_ptr() as *mut std::ffi::c_void,
+                lsn,
+                // CD-DA sectors only
+                1,
+                false,
+                false,
+                0,
+                true,
+                false,
+                0,
+                // The formatted Q subchannel
+                2,
+                (SECTOR_SIZE + SUBCHANNEL_Q_SIZE) as u16,
+                sectors,
+            )
+        };
+        result == driver_return_code_t_DRIVER_OP_SUCCESS
+    }
+}
+
+impl DiscSource for Drive {
+    fn first_track_num(&self) -> Result<u8> {
+        self.cdio.first_track_num()
+    }
+
+    fn last_track_num(&self) -> Result<u8> {
+        self.cdio.last_track_num()
+    }
+
+    fn num_tracks(&self) -> Result<u8> {
+        self.cdio.num_tracks()
+    }
+
+    fn track_lsn(&self, track: u8) -> Result<i32> {
+        self.cdio.track_lsn(track)
+    }
+
+    fn track_last_lsn(&self, track: u8) -> Result<i32> {
+        self.cdio.track_last_lsn(track)
+    }
+
+    fn track_is_audio(&self, track: u8) -> Result<bool> {
+        self.cdio.track_is_audio(track)
+    }
+
+    fn last_session_lsn(&self) -> Result<i32> {
+        self.cdio.last_session_lsn()
+    }
+
+    fn track_pregap_lsn(&self, track: u8) -> Result<Option<i32>> {
+        self.cdio.track_pregap_lsn(track)
+    }
+
+    fn track_preemphasis(&self, track: u8) -> Result<bool> {
+        self.cdio.track_preemphasis(track)
+    }
+
+    fn mcn(&self) -> Result<Option<String>> {
+        self.cdio.mcn()
+    }
+
+    fn track_isrc(&self, track: u8) -> Result<Option<String>> {
+        self.cdio.track_isrc(track)
+    }
+
+    fn track_index(&mut self, lsn: i32) -> Result<Option<(u8, u8)>> {
+        if !self.subchannel_q {
+            return Ok(None);
+        }
+
+        // Now and then the Q subchannel carries the catalog number or an ISRC instead of the
+        // position, never in two sectors in a row: read the next one as well. The last sector
+        // before the lead-out or the session gap has to do alone.
+        let block_size = SECTOR_SIZE + SUBCHANNEL_Q_SIZE;
+        let mut blocks = vec![0u8; block_size * 2];
+        let read = if self.read_subchannel_q(&mut blocks, lsn, 2) {
+            2
+        } else if self.read_subchannel_q(&mut blocks, lsn, 1) {
+            1
+        } else {
+            // Tell apart an unreadable sector from a drive that can't read the subchannels
+            let mut sector = [0u8; SECTOR_SIZE];
+            self.cdio.read_audio_sectors(&mut sector, lsn, 1)?;
+            info!("the drive doesn't read the Q subchannel");
+            self.subchannel_q = false;
+            return Ok(None);
+        };
+        let from_bcd = |byte: u8| (byte >> 4) * 10 + (byte & 0xf);
+        Ok(blocks
+            .chunks_exact(block_size)
+            .take(read)
+            .map(|block| &block[SECTOR_SIZE..])
+            // ADR 1, the position
+            .find(|q| q[0] & 0xf == 1)
+            .map(|q| (from_bcd(q[1]), from_bcd(q[2]))))
+    }
+
+    fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()> {
+        self.cdio.read_audio_sectors(buf, lsn, sectors)
+    }
+
+    fn read_audio_sectors_c2(
+        &mut self,
+        buf: &mut [u8],
+        c2: &mut [u8],
+        lsn: i32,
+        sectors: u32,
+    ) -> Result<bool> {
+        if !self.c2 {
+            self.cdio.read_audio_sectors(buf, lsn, sectors)?;
+            return Ok(false);
+        }
+
+        // Every block is the sector followed by its C2 error pointers
+        let block_size = SECTOR_SIZE + C2_SIZE;
+        let mut blocks = vec![0u8; block_size * sectors as usize];
+        let result = unsafe {
+            mmc_read_cd(
+                self.cdio.0,
+                blocks.as_mut_ptr() as *mut std::ffi::c_void,
+                lsn,
+                // CD-DA sectors only
+                1,
+                false,
+                false,
+                0,
+                true,
+                false,
+                // The C2 error bits, without the block error byte
+                1,
+                0,
+                block_size as u16,
+                sectors,
+            )
+        };
+        if result != driver_return_code_t_DRIVER_OP_SUCCESS {
+            // Either the drive doesn't support it, or the sectors are unreadable: tell apart
+            // with a plain read, which doesn't give up on the C2 error pointers if it fails too
+            self.cdio.read_audio_sectors(buf, lsn, sectors)?;
+            info!("the drive doesn't report C2 errors");
+            self.c2 = false;
+            return Ok(false);
+        }
+        for ((block, sector), c2) in blocks
+            .chunks_exact(block_size)
+            .zip(buf.chunks_exact_mut(SECTOR_SIZE))
+            .zip(c2.chunks_exact_mut(C2_SIZE))
+        {
+            sector.copy_from_slice(&block[..SECTOR_SIZE]);
+            c2.copy_from_slice(&block[SECTOR_SIZE..]);
+        }
+        Ok(true)
+    }
+
+    fn eject(&mut self) -> Result<()> {
+        // cdio_eject_media frees the handle and sets it to null
+        if unsafe { cdio_eject_media(&mut self.cdio.0) } != driver_return_code_t_DRIVER_OP_SUCCESS {
+            bail!("unable to eject the CD");
+        }
+        Ok(())
+    }
+
+    fn media_changed(&mut self) -> Result<bool> {
+        match unsafe { cdio_get_media_changed(self.cdio.0) } {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => bail!("unable to get the media status"),
+        }
+    }
+
+    fn cdtext(&self) -> Result<Vec<CdText>> {
+        self.cdio.cdtext()
+    }
+}
+
+/// A BIN/CUE, NRG or cdrdao TOC disc image, read through libcdio image drivers
+pub struct Image {
+    cdio: Cdio,
+}
+
+impl Image {
+    pub fn open(path: &Path) -> Result<Self> {
+        let driver_id = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cue") => driver_id_t_DRIVER_BINCUE,
+            Some("nrg") => driver_id_t_DRIVER_NRG,
+            Some("toc") => driver_id_t_DRIVER_CDRDAO,
+            _ => bail!("unsupported disc image {}", path.display()),
+        };
+        let source = CString::new(path.as_os_str().as_bytes())?;
+        let cdio = Cdio::open(source.as_ptr(), driver_id)?;
+
+        Ok(Self { cdio })
+    }
+}
+
+impl DiscSource for Image {
+    fn first_track_num(&self) -> Result<u8> {
+        self.cdio.first_track_num()
+    }
+
+    fn last_track_num(&self) -> Result<u8> {
+        self.cdio.last_track_num()
+    }
+
+    fn num_tracks(&self) -> Result<u8> {
+        self.cdio.num_tracks()
+    }
+
+    fn track_lsn(&self, track: u8) -> Result<i32> {
+        self.cdio.track_lsn(track)
+    }
+
+    fn track_last_lsn(&self, track: u8) -> Result<i32> {
+        self.cdio.track_last_lsn(track)
+    }
+
+    fn track_is_audio(&self, track: u8) -> Result<bool> {
+        self.cdio.track_is_audio(track)
+    }
+
+    fn last_session_lsn(&self) -> Result<i32> {
+        self.cdio.last_session_lsn()
+    }
+
+    fn track_pregap_lsn(&self, track: u8) -> Result<Option<i32>> {
+        // Read from the INDEX 00 of the CUE sheet
+        self.cdio.track_pregap_lsn(track)
+    }
+
+    fn track_preemphasis(&self, track: u8) -> Result<bool> {
+        // Read from the FLAGS PRE of the CUE sheet
+        self.cdio.track_preemphasis(track)
+    }
+
+    fn mcn(&self) -> Result<Option<String>> {
+        // Read from the CATALOG of the CUE sheet
+        self.cdio.mcn()
+    }
+
+    fn track_isrc(&self, track: u8) -> Result<Option<String>> {
+        // Read from the ISRC of the CUE sheet
+        self.cdio.track_isrc(track)
+    }
+
+    fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()> {
+        self.cdio.read_audio_sectors(buf, lsn, sectors)
+    }
+
+    fn eject(&mut self) -> Result<()> {
+        // There is nothing to eject
+        Ok(())
+    }
+
+    fn media_changed(&mut self) -> Result<bool> {
+        // An image never changes under us
+        Ok(false)
+    }
+
+    fn cdtext(&self) -> Result<Vec<CdText>> {
+        // Read from the CUE sheet or the CD-Text file it references
+        self.cdio.cdtext()
+    }
+}
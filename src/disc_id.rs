@@ -0,0 +1,125 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+/// The sectors of the lead-in, before LSN 0. Disc IDs are computed on absolute LBAs.
+pub const LEAD_IN: i32 = 150;
+pub const SECTORS_PER_SECOND: i32 = 75;
+
+/// The identifiers a disc is looked up by in the metadata databases
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscId {
+    /// MusicBrainz disc ID, 28 characters of base64
+    pub musicbrainz: String,
+    /// CDDB/FreeDB disc ID
+    pub freedb: u32,
+    /// The TOC the IDs have been computed from, some lookups need it
+    pub track_lsns: Vec<i32>,
+    pub lead_out: i32,
+    /// The audio tracks alone, and where their audio ends
+    pub audio_lsns: Vec<i32>,
+    pub audio_end: i32,
+}
+
+impl DiscId {
+    /// Compute the IDs of a disc whose tracks start at `track_lsns`, starting from `first_track`,
+    /// and whose lead-out starts at `lead_out`. The audio tracks start at `audio_lsns` and end at
+    /// `audio_end`: MusicBrainz leaves out the data session of an enhanced CD, as if the disc
+    /// ended with its audio, while FreeDB counts every track.
+    pub fn new(
+        first_track: u8,
+        track_lsns: &[i32],
+        lead_out: i32,
+        audio_lsns: &[i32],
+        audio_end: i32,
+    ) -> Self {
+        let audio_session = track_lsns
+            .iter()
+            .take_while(|&&lsn| lsn < audio_end)
+            .count();
+        Self {
+            musicbrainz: musicbrainz(first_track, &track_lsns[..audio_session], audio_end),
+            freedb: freedb(track_lsns, lead_out),
+            track_lsns: track_lsns.to_vec(),
+            lead_out,
+            audio_lsns: audio_lsns.to_vec(),
+            audio_end,
+        }
+    }
+
+    /// The FreeDB ID as usually written, 8 lowercase hex digits
+    pub fn freedb_hex(&self) -> String {
+        format!("{:08x}", self.freedb)
+    }
+}
+
+/// SHA-1 of the TOC written in hex, encoded in base64 with an URL friendly alphabet
+fn musicbrainz(first_track: u8, track_lsns: &[i32], lead_out: i32) -> String {
+    let last_track = first_track as usize + track_lsns.len() - 1;
+    let mut offsets = [0; 100];
+    offsets[0] = lead_out + LEAD_IN;
+    for (i, lsn) in track_lsns.iter().enumerate() {
+        offsets[first_track as usize + i] = lsn + LEAD_IN;
+    }
+
+    let mut sha1 = Sha1::new();
+    sha1.update(format!("{first_track:02X}{last_track:02X}"));
+    for offset in offsets {
+        sha1.update(format!("{offset:08X}"));
+    }
+    STANDARD
+        .encode(sha1.finalize())
+        .replace('+', ".")
+        .replace('/', "_")
+        .replace('=', "-")
+}
+
+/// Checksum of the track start times, total length in seconds and number of tracks
+fn freedb(track_lsns: &[i32], lead_out: i32) -> u32 {
+    let seconds = |lsn: i32| ((lsn + LEAD_IN) / SECTORS_PER_SECOND) as u32;
+    let digit_sum = |mut n: u32| {
+        let mut sum = 0;
+        while n > 0 {
+            sum += n % 10;
+            n /= 10;
+        }
+        sum
+    };
+
+    let checksum = track_lsns
+        .iter()
+        .map(|&lsn| digit_sum(seconds(lsn)))
+        .sum::<u32>();
+    let length = seconds(lead_out) - seconds(track_lsns[0]);
+    (checksum % 0xff) << 24 | length << 8 | track_lsns.len() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame offsets as published, counting the lead-in
+    fn lsns(offsets: &[i32]) -> Vec<i32> {
+        offsets.iter().map(|offset| offset - LEAD_IN).collect()
+    }
+
+    #[test]
+    fn musicbrainz_documentation() {
+        // The example of the MusicBrainz disc ID calculation documentation
+        let offsets = [150, 15363, 32314, 46592, 63414, 80489];
+        let lsns = lsns(&offsets);
+        let disc_id = DiscId::new(1, &lsns, 95462 - LEAD_IN, &lsns, 95462 - LEAD_IN);
+        assert_eq!(disc_id.musicbrainz, "49HHV7Eb8UKF3aQiNmu1GR8vKTY-");
+    }
+
+    #[test]
+    fn libdiscid() {
+        // The TOC libdiscid is tested with
+        let offsets = [
+            150, 18901, 39738, 59557, 79152, 100126, 124833, 147278, 166336, 182560,
+        ];
+        let lsns = lsns(&offsets);
+        let disc_id = DiscId::new(1, &lsns, 206535 - LEAD_IN, &lsns, 206535 - LEAD_IN);
+        assert_eq!(disc_id.musicbrainz, "Wn8eRBtfLDfM0qjYPdxrz.Zjs_U-");
+        assert_eq!(disc_id.freedb_hex(), "830abf0a");
+    }
+}
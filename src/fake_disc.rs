@@ -0,0 +1,467 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    f32::consts::PI,
+    ops::Range,
+    str::FromStr,
+};
+
+use color_eyre::{
+    eyre::{bail, eyre, ContextCompat},
+    Report, Result,
+};
+
+use crate::{
+    disc::{DiscSource, C2_SIZE, SESSION_GAP},
+    metadata::{CdText, TextFields},
+    ring::SECTOR_SIZE,
+};
+
+/// Stereo samples contained in a single CD-DA sector
+const SAMPLES_PER_SECTOR: i32 = 588;
+const SECTORS_PER_SECOND: i32 = 75;
+/// How many times a damaged sector is read wrong before it is read right
+const DAMAGED_READS: u32 = 2;
+
+/// The audio generated for the sectors of a fake track
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Silence,
+    /// A sine tone at the given frequency, the same on both channels
+    Sine(f32),
+    /// Each stereo sample holds its absolute position on the disc, the left channel the lower
+    /// 16 bits and the right one the upper 16 bits
+    Counter,
+    /// A data track; reading it as audio returns a fixed byte pattern
+    Data,
+}
+
+#[derive(Clone, Debug)]
+pub struct FakeTrack {
+    pub signal: Signal,
+    pub sectors: i32,
+    /// Sectors of silence before the track start (INDEX 00)
+    pub pregap: i32,
+    /// Where INDEX 02 onwards start, in sectors from the track start
+    pub indexes: Vec<i32>,
+    /// The track is flagged as mastered with pre-emphasis
+    pub preemphasis: bool,
+    /// The track starts a new session, as the data track of an enhanced CD
+    pub new_session: bool,
+}
+
+impl FakeTrack {
+    pub fn new(signal: Signal, sectors: i32) -> Self {
+        Self {
+            signal,
+            sectors,
+            pregap: 0,
+            indexes: Vec::new(),
+            preemphasis: false,
+            new_session: false,
+        }
+    }
+
+    pub fn with_pregap(mut self, pregap: i32) -> Self {
+        self.pregap = pregap;
+        self
+    }
+
+    pub fn with_indexes(mut self, indexes: Vec<i32>) -> Self {
+        self.indexes = indexes;
+        self
+    }
+
+    pub fn with_preemphasis(mut self) -> Self {
+        self.preemphasis = true;
+        self
+    }
+
+    pub fn in_new_session(mut self) -> Self {
+        self.new_session = true;
+        self
+    }
+}
+
+/// What goes wrong when reading a fake disc, to exercise the paranoia layer
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// Sectors that can never be read
+    pub unreadable: Vec<Range<i32>>,
+    /// Sectors read with some wrong samples, flagged by the C2 error pointers, the first times
+    pub damaged: Vec<Range<i32>>,
+    /// Every third read starts where it should, the next one this many stereo samples after and
+    /// the one after that as many before
+    pub jitter: i32,
+}
+
+/// A disc living in memory, with a configurable TOC and generated audio.
+///
+/// It can be built from a spec such as `sine=440:180,silence:2,counter:60+2@20@40,data:300`:
+/// tracks are separated by commas and have the form
+/// `signal[=frequency]:seconds[+pregap seconds][@index seconds...][:pre]`, the index points
+/// (INDEX 02 onwards) being in seconds from the track start and `:pre` flagging the track as
+/// pre-emphasized.
+/// Sessions are separated by slashes, an enhanced CD is `sine:180,sine:200/data:300`; the sectors
+/// between them can't be read. Faults can follow, separated by semicolons: `error=FIRST-LAST` and
+/// `damaged=FIRST-LAST` sector ranges and `jitter=SAMPLES`.
+pub struct FakeDisc {
+    tracks: Vec<FakeTrack>,
+    /// The LSN of every track start, plus the lead-out
+    starts: Vec<i32>,
+    /// Where the first track of the last session starts
+    last_session: i32,
+    faults: Faults,
+    /// Reads so far, for the jitter
+    reads: u32,
+    /// How many times every damaged sector has been read
+    damaged_reads: HashMap<i32, u32>,
+    ejected: bool,
+    media_changed: bool,
+}
+
+impl FakeDisc {
+    pub fn new(tracks: Vec<FakeTrack>) -> Result<Self> {
+        if tracks.is_empty() || tracks.len() > 99 {
+            bail!("a disc must have between 1 and 99 tracks");
+        }
+        let mut starts = Vec::with_capacity(tracks.len() + 1);
+        let mut last_session = 0;
+        let mut lsn = 0;
+        for track in &tracks {
+            if track.new_session && lsn > 0 {
+                // The pregap of the track is part of the gap
+                lsn += SESSION_GAP;
+                last_session = lsn;
+            } else {
+                lsn += track.pregap;
+            }
+            starts.push(lsn);
+            lsn += track.sectors;
+        }
+        starts.push(lsn);
+
+        Ok(Self {
+            tracks,
+            starts,
+            last_session,
+            faults: Faults::default(),
+            reads: 0,
+            damaged_reads: HashMap::new(),
+            ejected: false,
+            media_changed: true,
+        })
+    }
+
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Index of the track containing `lsn`, pregap included; None between the sessions
+    fn track_at(&self, lsn: i32) -> Option<usize> {
+        if lsn < 0 || lsn >= *self.starts.last().unwrap() {
+            return None;
+        }
+        let index = self
+            .tracks
+            .iter()
+            .zip(&self.starts[..self.tracks.len()])
+            .rposition(|(track, start)| start - track.pregap <= lsn)?;
+        (lsn < self.starts[index] + self.tracks[index].sectors).then_some(index)
+    }
+
+    /// Generate the sector at `lsn`, as if read `shift` stereo samples later
+    fn fill_sector(&self, sector: &mut [u8], lsn: i32, shift: i32) -> Result<()> {
+        let index = self.track_at(lsn).context("error reading sector")?;
+        let track = &self.tracks[index];
+        let start = self.starts[index];
+
+        if track.signal == Signal::Data {
+            sector.iter_mut().for_each(|byte| *byte = 0x55);
+            return Ok(());
+        }
+
+        for (i, frame) in sector.chunks_exact_mut(4).enumerate() {
+            let position = lsn * SAMPLES_PER_SECTOR + i as i32 + shift;
+            let (left, right) = match track.signal {
+                // The pregap is always silent
+                _ if lsn < start => (0, 0),
+                Signal::Silence | Signal::Data => (0, 0),
+                Signal::Sine(frequency) => {
+                    let n = (position - start * SAMPLES_PER_SECTOR) as f32;
+                    let sample = (f32::from(i16::MAX) * (2. * PI * frequency * n / 44100.).sin())
+                        .round() as i16;
+                    (sample, sample)
+                }
+                Signal::Counter => (position as i16, (position >> 16) as i16),
+            };
+            frame[..2].copy_from_slice(&left.to_le_bytes());
+            frame[2..].copy_from_slice(&right.to_le_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        buf: &mut [u8],
+        mut c2: Option<&mut [u8]>,
+        lsn: i32,
+        sectors: u32,
+    ) -> Result<()> {
+        let read = lsn..lsn + sectors as i32;
+        if self.ejected
+            || self
+                .faults
+                .unreadable
+                .iter()
+                .any(|bad| bad.start < read.end && read.start < bad.end)
+        {
+            bail!("error reading sector");
+        }
+
+        let shift = match self.reads % 3 {
+            0 => 0,
+            1 => self.faults.jitter,
+            _ => -self.faults.jitter,
+        };
+        self.reads += 1;
+        if let Some(c2) = &mut c2 {
+            c2.fill(0);
+        }
+        for (i, lsn) in read.enumerate() {
+            let sector = &mut buf[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE];
+            self.fill_sector(sector, lsn, shift)?;
+
+            if !self
+                .faults
+                .damaged
+                .iter()
+                .any(|damaged| damaged.contains(&lsn))
+            {
+                continue;
+            }
+            let reads = self.damaged_reads.entry(lsn).or_default();
+            *reads += 1;
+            if *reads > DAMAGED_READS {
+                continue;
+            }
+            // Every 16th byte is wrong, differently every time
+            for byte in (0..SECTOR_SIZE).step_by(16) {
+                sector[byte] ^= 0x50 + *reads as u8;
+                if let Some(c2) = &mut c2 {
+                    c2[i * C2_SIZE + byte / 8] |= 0x80 >> (byte % 8);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A sector range, `FIRST-LAST` or a single sector
+fn parse_sectors(range: &str) -> Result<Range<i32>> {
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    Ok(first.parse()?..last.parse::<i32>()? + 1)
+}
+
+impl FromStr for Faults {
+    type Err = Report;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut faults = Faults::default();
+        for fault in spec.split(';') {
+            match fault.split_once('=') {
+                Some(("error", range)) => faults.unreadable.push(parse_sectors(range)?),
+                Some(("damaged", range)) => faults.damaged.push(parse_sectors(range)?),
+                Some(("jitter", samples)) => faults.jitter = samples.parse()?,
+                _ => bail!("unknown fault {fault}"),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+impl FromStr for FakeDisc {
+    type Err = Report;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (spec, faults) = match spec.split_once(';') {
+            Some((tracks, faults)) => (tracks, faults.parse()?),
+            None => (spec, Faults::default()),
+        };
+        let tracks = spec
+            .split('/')
+            .enumerate()
+            .flat_map(|(session, tracks)| {
+                tracks
+                    .split(',')
+                    .enumerate()
+                    .map(move |(i, track)| (session > 0 && i == 0, track))
+            })
+            .map(|(new_session, track)| -> Result<FakeTrack> {
+                let (signal, length) = track
+                    .split_once(':')
+                    .with_context(|| format!("missing length in fake track {track}"))?;
+                let signal = match signal.split_once('=') {
+                    Some(("sine", frequency)) => Signal::Sine(frequency.parse()?),
+                    None if signal == "sine" => Signal::Sine(440.),
+                    None if signal == "silence" => Signal::Silence,
+                    None if signal == "counter" => Signal::Counter,
+                    None if signal == "data" => Signal::Data,
+                    _ => return Err(eyre!("unknown signal {signal} in fake track {track}")),
+                };
+                let (length, preemphasis) = match length.strip_suffix(":pre") {
+                    Some(length) => (length, true),
+                    None => (length, false),
+                };
+                let mut indexes = length.split('@');
+                let length = indexes.next().unwrap_or_default();
+                let indexes = indexes
+                    .map(|seconds| Ok(seconds.parse::<i32>()? * SECTORS_PER_SECOND))
+                    .collect::<Result<Vec<_>>>()?;
+                let (seconds, pregap) = length.split_once('+').unwrap_or((length, "0"));
+                let mut track =
+                    FakeTrack::new(signal, seconds.parse::<i32>()? * SECTORS_PER_SECOND)
+                        .with_pregap(pregap.parse::<i32>()? * SECTORS_PER_SECOND)
+                        .with_indexes(indexes);
+                if preemphasis {
+                    track = track.with_preemphasis();
+                }
+                Ok(if new_session {
+                    track.in_new_session()
+                } else {
+                    track
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(FakeDisc::new(tracks)?.with_faults(faults))
+    }
+}
+
+impl DiscSource for FakeDisc {
+    fn first_track_num(&self) -> Result<u8> {
+        Ok(1)
+    }
+
+    fn last_track_num(&self) -> Result<u8> {
+        Ok(self.tracks.len() as u8)
+    }
+
+    fn num_tracks(&self) -> Result<u8> {
+        Ok(self.tracks.len() as u8)
+    }
+
+    fn track_lsn(&self, track: u8) -> Result<i32> {
+        // Track 0xAA is the lead-out, as in libcdio
+        match track {
+            0xAA => Ok(*self.starts.last().unwrap()),
+            1..=99 if (track as usize) <= self.tracks.len() => Ok(self.starts[track as usize - 1]),
+            _ => bail!("error reading cd"),
+        }
+    }
+
+    fn track_last_lsn(&self, track: u8) -> Result<i32> {
+        let index = (track as usize).wrapping_sub(1);
+        match (self.tracks.get(index), self.tracks.get(index + 1)) {
+            // The pregap of the next track is part of this one, as in libcdio
+            (Some(_), Some(next)) if !next.new_session => Ok(self.starts[index + 1] - 1),
+            (Some(fake), _) => Ok(self.starts[index] + fake.sectors - 1),
+            _ => bail!("error reading cd"),
+        }
+    }
+
+    fn track_is_audio(&self, track: u8) -> Result<bool> {
+        match self.tracks.get((track as usize).wrapping_sub(1)) {
+            Some(track) => Ok(track.signal != Signal::Data),
+            None => bail!("error reading cd"),
+        }
+    }
+
+    fn last_session_lsn(&self) -> Result<i32> {
+        Ok(self.last_session)
+    }
+
+    fn track_preemphasis(&self, track: u8) -> Result<bool> {
+        match self.tracks.get((track as usize).wrapping_sub(1)) {
+            Some(track) => Ok(track.preemphasis),
+            None => bail!("error reading cd"),
+        }
+    }
+
+    fn track_index(&mut self, lsn: i32) -> Result<Option<(u8, u8)>> {
+        let index = self.track_at(lsn).context("error reading sector")?;
+        let start = self.starts[index];
+        // The pregap is INDEX 00
+        let track_index = if lsn < start {
+            0
+        } else {
+            1 + self.tracks[index]
+                .indexes
+                .iter()
+                .filter(|&&sector| start + sector <= lsn)
+                .count() as u8
+        };
+        Ok(Some((index as u8 + 1, track_index)))
+    }
+
+    fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()> {
+        self.read(buf, None, lsn, sectors)
+    }
+
+    fn read_audio_sectors_c2(
+        &mut self,
+        buf: &mut [u8],
+        c2: &mut [u8],
+        lsn: i32,
+        sectors: u32,
+    ) -> Result<bool> {
+        self.read(buf, Some(c2), lsn, sectors)?;
+        Ok(true)
+    }
+
+    fn eject(&mut self) -> Result<()> {
+        self.ejected = true;
+        self.media_changed = true;
+        Ok(())
+    }
+
+    fn media_changed(&mut self) -> Result<bool> {
+        Ok(std::mem::take(&mut self.media_changed))
+    }
+
+    fn cdtext(&self) -> Result<Vec<CdText>> {
+        // Every track is titled after what it contains
+        let tracks = self
+            .tracks
+            .iter()
+            .zip(1..)
+            .map(|(track, number)| {
+                let title = match track.signal {
+                    Signal::Silence => "Silence".to_string(),
+                    Signal::Sine(frequency) => format!("Sine {frequency} Hz"),
+                    Signal::Counter => "Counter".to_string(),
+                    Signal::Data => "Data".to_string(),
+                };
+                (
+                    number,
+                    TextFields {
+                        title: Some(title),
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(vec![CdText {
+            language: "English".to_string(),
+            genre: None,
+            disc: TextFields {
+                title: Some("Fake disc".to_string()),
+                performer: Some("raspi-cd-player".to_string()),
+                ..Default::default()
+            },
+            tracks,
+        }])
+    }
+}
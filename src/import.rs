@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+
+use color_eyre::{eyre::bail, Result};
+use serde::Deserialize;
+
+use crate::database::{Release, ReleaseTrack};
+
+#[derive(Deserialize)]
+struct MbRelease {
+    title: Option<String>,
+    date: Option<String>,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<MbArtistCredit>,
+    #[serde(default)]
+    media: Vec<MbMedium>,
+}
+
+#[derive(Deserialize)]
+struct MbArtistCredit {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(Deserialize)]
+struct MbMedium {
+    #[serde(default)]
+    discs: Vec<MbDisc>,
+    #[serde(default)]
+    tracks: Vec<MbTrack>,
+}
+
+#[derive(Deserialize)]
+struct MbDisc {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct MbTrack {
+    title: Option<String>,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<MbArtistCredit>,
+}
+
+/// "Artist A feat. Artist B", as MusicBrainz credits them
+fn credited(credits: &[MbArtistCredit]) -> Option<String> {
+    (!credits.is_empty()).then(|| {
+        credits
+            .iter()
+            .map(|credit| format!("{}{}", credit.name, credit.joinphrase))
+            .collect()
+    })
+}
+
+/// The response of the MusicBrainz web service to a disc ID lookup
+#[derive(Deserialize)]
+struct MbDiscIdLookup {
+    #[serde(default)]
+    releases: Vec<MbRelease>,
+}
+
+/// Every disc ID of every medium of `release`, with the release as seen from that medium
+fn musicbrainz_discs(release: &MbRelease) -> Vec<(String, Release)> {
+    let artist = credited(&release.artist_credit);
+    let year = release
+        .date
+        .as_deref()
+        .and_then(|date| date.get(..4))
+        .and_then(|year| year.parse().ok());
+
+    let mut discs = Vec::new();
+    for medium in &release.media {
+        let tracks = medium
+            .tracks
+            .iter()
+            .map(|track| ReleaseTrack {
+                title: track.title.clone(),
+                artist: credited(&track.artist_credit)
+                    .filter(|credit| Some(credit) != artist.as_ref()),
+            })
+            .collect::<Vec<_>>();
+        for disc in &medium.discs {
+            discs.push((
+                disc.id.clone(),
+                Release {
+                    album: release.title.clone(),
+                    artist: artist.clone(),
+                    year,
+                    genre: None,
+                    cover: None,
+                    tracks: tracks.clone(),
+                },
+            ));
+        }
+    }
+    discs
+}
+
+/// Parse MusicBrainz release JSON, as returned by the web service (with
+/// `inc=recordings+artist-credits+discids`) or one release per line as in the JSON dumps.
+pub fn musicbrainz(json: &str) -> Result<Vec<(String, Release)>> {
+    let mut releases = Vec::new();
+    for release in serde_json::Deserializer::from_str(json).into_iter::<MbRelease>() {
+        releases.extend(musicbrainz_discs(&release?));
+    }
+    if releases.is_empty() {
+        bail!("no disc ID in the MusicBrainz release, it must include the discids");
+    }
+    Ok(releases)
+}
+
+/// Parse the response of the MusicBrainz web service to a disc ID lookup
+pub fn musicbrainz_lookup(json: &str) -> Result<Vec<(String, Release)>> {
+    let lookup: MbDiscIdLookup = serde_json::from_str(json)?;
+    Ok(lookup.releases.iter().flat_map(musicbrainz_discs).collect())
+}
+
+/// Parse a FreeDB/CDDB xmcd file; the release is returned once for every disc ID it lists
+pub fn xmcd(xmcd: &str) -> Result<Vec<(String, Release)>> {
+    // Long values are split across several lines with the same key
+    let mut fields = BTreeMap::<&str, String>::new();
+    for line in xmcd.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            fields.entry(key.trim()).or_default().push_str(value);
+        }
+    }
+
+    let Some(disc_ids) = fields.get("DISCID") else {
+        bail!("not an xmcd file, DISCID is missing");
+    };
+    let field = |key: &str| {
+        fields
+            .get(key)
+            .map(|value| unescape(value.trim()))
+            .filter(|value| !value.is_empty())
+    };
+
+    // DTITLE is "Artist / Album", or just the album when artist and album are the same
+    let (artist, album) = match field("DTITLE") {
+        Some(title) => match title.split_once(" / ") {
+            Some((artist, album)) => (Some(artist.to_string()), Some(album.to_string())),
+            None => (Some(title.clone()), Some(title)),
+        },
+        None => (None, None),
+    };
+
+    let mut tracks = Vec::new();
+    while let Some(title) = fields.get(format!("TTITLE{}", tracks.len()).as_str()) {
+        let title = unescape(title.trim());
+        // Compilations have "Artist / Title" track titles
+        let track = match title.split_once(" / ") {
+            Some((track_artist, track_title)) if artist.as_deref() == Some("Various") => {
+                ReleaseTrack {
+                    title: Some(track_title.to_string()),
+                    artist: Some(track_artist.to_string()),
+                }
+            }
+            _ => ReleaseTrack {
+                title: (!title.is_empty()).then_some(title),
+                artist: None,
+            },
+        };
+        tracks.push(track);
+    }
+
+    let release = Release {
+        album,
+        artist,
+        year: field("DYEAR").and_then(|year| year.parse().ok()),
+        genre: field("DGENRE"),
+        cover: None,
+        tracks,
+    };
+    Ok(disc_ids
+        .split(',')
+        .map(|id| id.trim().to_lowercase())
+        .filter(|id| !id.is_empty())
+        .map(|id| (id, release.clone()))
+        .collect())
+}
+
+/// xmcd values escape newlines, tabs and backslashes
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            },
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc_id::{DiscId, LEAD_IN, SECTORS_PER_SECOND};
+
+    fn titles(release: &Release) -> Vec<Option<&str>> {
+        release
+            .tracks
+            .iter()
+            .map(|track| track.title.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn xmcd_disc_id() {
+        // The TOC libdiscid is tested with, as an xmcd file lists it
+        let offsets = [
+            150, 18901, 39738, 59557, 79152, 100126, 124833, 147278, 166336, 182560,
+        ];
+        let mut xmcd = "# xmcd\n#\n# Track frame offsets:\n".to_string();
+        for offset in offsets {
+            xmcd.push_str(&format!("#\t{offset}\n"));
+        }
+        xmcd.push_str(
+            "#\n# Disc length: 2753 seconds\n#\nDISCID=830abf0a\nDTITLE=Artist / Album\n",
+        );
+        let releases = super::xmcd(&xmcd).unwrap();
+
+        // The disc length is in seconds only
+        let lsns = offsets.map(|offset| offset - LEAD_IN);
+        let lead_out = 2753 * SECTORS_PER_SECOND - LEAD_IN;
+        let disc_id = DiscId::new(1, &lsns, lead_out, &lsns, lead_out);
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].0, disc_id.freedb_hex());
+        assert_eq!(releases[0].1.album.as_deref(), Some("Album"));
+        assert_eq!(releases[0].1.artist.as_deref(), Some("Artist"));
+    }
+
+    #[test]
+    fn xmcd_fields() {
+        let xmcd = "# xmcd\r\nDISCID=830ABF0A,0a0b0c0d\r\nDTITLE=Various / Best of\r\n\
+                    DYEAR=1999\r\nDGENRE=Pop\r\nTTITLE0=First / One\r\n\
+                    TTITLE1=Second / A very long title split \r\nTTITLE1=across lines\r\n\
+                    TTITLE2=Escaped\\ttab\\nnewline\\\\\r\nTTITLE3=\r\nEXTD=\r\n";
+        let releases = super::xmcd(xmcd).unwrap();
+        let keys = releases.iter().map(|(key, _)| key.as_str());
+        assert!(keys.eq(["830abf0a", "0a0b0c0d"]));
+        let release = &releases[0].1;
+        assert_eq!(release.album.as_deref(), Some("Best of"));
+        assert_eq!(release.artist.as_deref(), Some("Various"));
+        assert_eq!(release.year, Some(1999));
+        assert_eq!(release.genre.as_deref(), Some("Pop"));
+        // Compilations credit every track
+        assert_eq!(
+            titles(release),
+            [
+                Some("One"),
+                Some("A very long title split across lines"),
+                Some("Escaped\ttab\nnewline\\"),
+                None
+            ]
+        );
+        let artists = release.tracks.iter().map(|track| track.artist.as_deref());
+        assert!(artists.eq([Some("First"), Some("Second"), None, None]));
+    }
+
+    #[test]
+    fn xmcd_single_artist() {
+        let releases =
+            super::xmcd("DISCID=830abf0a\nDTITLE=Eponymous\nTTITLE0=Part 1 / Part 2\n").unwrap();
+        let release = &releases[0].1;
+        // The artist and the album have the same name
+        assert_eq!(release.album.as_deref(), Some("Eponymous"));
+        assert_eq!(release.artist.as_deref(), Some("Eponymous"));
+        assert_eq!(titles(release), [Some("Part 1 / Part 2")]);
+        assert_eq!(release.tracks[0].artist, None);
+    }
+
+    #[test]
+    fn not_xmcd() {
+        assert!(super::xmcd("DTITLE=Artist / Album\n").is_err());
+    }
+
+    /// A release with two discs, the first one known under two disc IDs
+    const RELEASE: &str = r#"{
+        "title": "Album",
+        "date": "1999-04-01",
+        "artist-credit": [{"name": "Artist", "joinphrase": ""}],
+        "media": [
+            {
+                "discs": [{"id": "first-disc-id"}, {"id": "other-pressing-id"}],
+                "tracks": [
+                    {"title": "One", "artist-credit": [{"name": "Artist"}]},
+                    {
+                        "title": "Two",
+                        "artist-credit": [
+                            {"name": "Artist", "joinphrase": " feat. "},
+                            {"name": "Guest"}
+                        ]
+                    }
+                ]
+            },
+            {"discs": [{"id": "second-disc-id"}], "tracks": [{"title": "Three"}]}
+        ]
+    }"#;
+
+    #[test]
+    fn musicbrainz_release() {
+        let releases = musicbrainz(RELEASE).unwrap();
+        let keys = releases.iter().map(|(key, _)| key.as_str());
+        assert!(keys.eq(["first-disc-id", "other-pressing-id", "second-disc-id"]));
+
+        let release = &releases[0].1;
+        assert_eq!(release.album.as_deref(), Some("Album"));
+        assert_eq!(release.artist.as_deref(), Some("Artist"));
+        assert_eq!(release.year, Some(1999));
+        assert_eq!(titles(release), [Some("One"), Some("Two")]);
+        // Credited only when not the artist of the release
+        let artists = release.tracks.iter().map(|track| track.artist.as_deref());
+        assert!(artists.eq([None, Some("Artist feat. Guest")]));
+        assert_eq!(titles(&releases[2].1), [Some("Three")]);
+    }
+
+    #[test]
+    fn musicbrainz_dump() {
+        // One release per line
+        let dump = format!(
+            "{}\n{}\n",
+            RELEASE.replace('\n', ""),
+            RELEASE.replace('\n', "")
+        );
+        assert_eq!(musicbrainz(&dump).unwrap().len(), 6);
+        // Without the disc IDs there is nothing to import
+        let err = musicbrainz(r#"{"title": "Album", "media": [{"tracks": []}]}"#).unwrap_err();
+        assert!(err.to_string().contains("discids"));
+    }
+
+    #[test]
+    fn musicbrainz_disc_id_lookup() {
+        let lookup = format!(r#"{{"id": "first-disc-id", "releases": [{RELEASE}]}}"#);
+        let releases = musicbrainz_lookup(&lookup).unwrap();
+        assert_eq!(releases.len(), 3);
+        assert!(musicbrainz_lookup(r#"{"releases": []}"#)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    os::unix::prelude::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use calloop::{generic::Generic, Interest, LoopHandle, PostAction, RegistrationToken};
+use evdev::{Device, InputEvent, InputEventKind, Key};
+use log::{info, warn};
+
+use crate::{config::Binding, state::PlayerState};
+
+/// Keyboards and remotes read straight from `/dev/input/event*`, so that the player can be
+/// controlled without any graphical session
+pub struct Inputs<'l> {
+    handle: LoopHandle<'l, ()>,
+    devices: HashMap<PathBuf, (Device, RegistrationToken)>,
+    bindings: HashMap<Key, Binding>,
+}
+
+impl<'l> Inputs<'l> {
+    pub fn new(handle: LoopHandle<'l, ()>, bindings: HashMap<Key, Binding>) -> Self {
+        Self {
+            handle,
+            devices: HashMap::new(),
+            bindings,
+        }
+    }
+
+    /// Open every input device already plugged in
+    pub fn scan(&mut self) {
+        for (path, device) in evdev::enumerate() {
+            self.insert(path, device);
+        }
+    }
+
+    /// A new input device has been plugged in
+    pub fn add(&mut self, path: &Path) {
+        match Device::open(path) {
+            Ok(device) => self.insert(path.to_path_buf(), device),
+            Err(err) => warn!("unable to open {}: {err}", path.display()),
+        }
+    }
+
+    /// An input device has been unplugged
+    pub fn remove(&mut self, path: &Path) {
+        if let Some((device, token)) = self.devices.remove(path) {
+            info!(
+                "input device {} removed",
+                device.name().unwrap_or("unknown")
+            );
+            self.handle.remove(token);
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, device: Device) {
+        let has_bindings = device
+            .supported_keys()
+            .is_some_and(|keys| keys.iter().any(|key| self.bindings.contains_key(&key)));
+        if !has_bindings || self.devices.contains_key(&path) {
+            return;
+        }
+
+        // The events are read in `dispatch`, we only need the loop to wake up
+        let token = match self.handle.insert_source(
+            Generic::new(device.as_raw_fd(), Interest::READ, calloop::Mode::Level),
+            |_, _, _| Ok(PostAction::Continue),
+        ) {
+            Ok(token) => token,
+            Err(err) => {
+                warn!("unable to watch {}: {err}", path.display());
+                return;
+            }
+        };
+        info!(
+            "using input device {} at {}",
+            device.name().unwrap_or("unknown"),
+            path.display()
+        );
+        self.devices.insert(path, (device, token));
+    }
+
+    /// Handle the keys pressed since the last call
+    pub fn dispatch(&mut self, state: &Mutex<PlayerState>) {
+        let mut gone = Vec::new();
+        for (path, (device, _)) in &mut self.devices {
+            let events = match device.fetch_events() {
+                Ok(events) => events,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err) => {
+                    warn!("unable to read from {}: {err}", path.display());
+                    gone.push(path.clone());
+                    continue;
+                }
+            };
+            for event in events {
+                if let Some(binding) = pressed(&self.bindings, &event) {
+                    state.lock().unwrap().handle_request(binding.request());
+                }
+            }
+        }
+        for path in gone {
+            self.remove(&path);
+        }
+    }
+}
+
+/// The binding of the key pressed in `event`, if any
+fn pressed(bindings: &HashMap<Key, Binding>, event: &InputEvent) -> Option<Binding> {
+    match event.kind() {
+        // Only key presses, not releases nor autorepeat
+        InputEventKind::Key(key) if event.value() == 1 => bindings.get(&key).copied(),
+        _ => None,
+    }
+}
+
+/// Whether the device node is one of the event devices read through evdev, not a legacy mouse or
+/// joystick node nor one of the symlinks to them
+pub fn is_event_device(devnode: &Path) -> bool {
+    devnode.to_string_lossy().starts_with("/dev/input/event")
+}
+
+/// The key with the given evdev name, such as `KEY_PLAYPAUSE`
+pub fn key_by_name(name: &str) -> Option<Key> {
+    // KEY_MAX is 0x2ff
+    (0..0x300)
+        .map(Key::new)
+        .find(|key| format!("{key:?}") == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev::EventType;
+
+    use super::*;
+    use crate::{config::KeysConfig, state::Request};
+
+    fn event(kind: EventType, code: u16, value: i32) -> InputEvent {
+        InputEvent::new(kind, code, value)
+    }
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_by_name("KEY_PLAYPAUSE"), Some(Key::KEY_PLAYPAUSE));
+        assert_eq!(key_by_name("KEY_EJECTCD"), Some(Key::KEY_EJECTCD));
+        assert_eq!(key_by_name("KEY_Q"), Some(Key::KEY_Q));
+        assert_eq!(key_by_name("key_playpause"), None);
+        assert_eq!(key_by_name("PLAYPAUSE"), None);
+        assert_eq!(key_by_name("KEY_NOPE"), None);
+    }
+
+    #[test]
+    fn configured_names() {
+        let keys = KeysConfig::default();
+        let bindings = keys.input_bindings(false);
+        assert_eq!(bindings.len(), keys.input.len());
+        assert_eq!(bindings[&Key::KEY_PLAYPAUSE], Binding::TogglePlay);
+        assert_eq!(bindings[&Key::KEY_EJECTCLOSECD], Binding::Eject);
+        // The keyboard keys go to the window when there is one
+        assert!(!bindings.contains_key(&Key::KEY_SPACE));
+        let bindings = keys.input_bindings(true);
+        assert_eq!(bindings.len(), keys.input.len() + keys.keyboard.len());
+        assert_eq!(bindings[&Key::KEY_SPACE], Binding::TogglePlay);
+        assert_eq!(bindings[&Key::KEY_RIGHTBRACE], Binding::NextIndex);
+    }
+
+    #[test]
+    fn key_presses() {
+        let bindings = KeysConfig::default().input_bindings(false);
+        let press = |key: Key| pressed(&bindings, &event(EventType::KEY, key.code(), 1));
+        assert!(matches!(
+            press(Key::KEY_PLAYPAUSE).map(Binding::request),
+            Some(Request::TogglePlay)
+        ));
+        assert!(matches!(
+            press(Key::KEY_NEXTSONG).map(Binding::request),
+            Some(Request::NextTrack)
+        ));
+        assert!(matches!(
+            press(Key::KEY_REWIND).map(Binding::request),
+            Some(Request::SeekBackward)
+        ));
+        assert!(matches!(
+            press(Key::KEY_EJECTCD).map(Binding::request),
+            Some(Request::Eject)
+        ));
+        // Not bound without the keyboard
+        assert_eq!(press(Key::KEY_SPACE), None);
+
+        // Releases and autorepeat
+        let code = Key::KEY_PLAYPAUSE.code();
+        assert_eq!(pressed(&bindings, &event(EventType::KEY, code, 0)), None);
+        assert_eq!(pressed(&bindings, &event(EventType::KEY, code, 2)), None);
+        // Another kind of event with the same code
+        assert_eq!(pressed(&bindings, &event(EventType::MISC, code, 1)), None);
+    }
+
+    #[test]
+    fn event_devices() {
+        assert!(is_event_device(Path::new("/dev/input/event0")));
+        assert!(is_event_device(Path::new("/dev/input/event12")));
+        assert!(!is_event_device(Path::new("/dev/input/mouse0")));
+        assert!(!is_event_device(Path::new("/dev/input/js0")));
+        assert!(!is_event_device(Path::new(
+            "/dev/input/by-id/usb-remote-event-kbd"
+        )));
+        assert!(!is_event_device(Path::new("/dev/sr0")));
+    }
+}
@@ -2,90 +2,143 @@
 #![feature(arbitrary_self_types)]
 #![feature(let_chains)]
 
+mod accuraterip;
 mod action;
+mod archive;
+mod config;
+mod database;
+mod disc;
+mod disc_id;
+mod emphasis;
+mod fake_disc;
+mod import;
+mod input;
+mod metadata;
+mod mpris;
 mod output;
+mod paranoia;
 mod play_song;
+mod provider;
 mod read_cd;
+mod ring;
+mod rip;
 mod state;
+mod toc;
+#[cfg(feature = "wayland")]
+mod window;
 
 use std::{
     os::unix::prelude::AsRawFd,
+    path::{Path, PathBuf},
+    process,
     sync::{Arc, Mutex},
     thread,
 };
 
-use std::convert::TryInto;
-
 use calloop::{generic::Generic, Interest, PostAction};
-use color_eyre::{eyre::Context, Result};
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Result,
+};
+use config::{Config, Overrides};
+use database::Database;
+use input::Inputs;
 use log::{info, warn};
 use play_song::Player;
+use provider::MetadataLookup;
 use read_cd::Reader;
-use smithay_client_toolkit::{
-    compositor::{CompositorHandler, CompositorState},
-    delegate_compositor, delegate_keyboard, delegate_output, delegate_registry, delegate_seat,
-    delegate_shm, delegate_xdg_shell, delegate_xdg_window,
-    event_loop::WaylandSource,
-    output::{OutputHandler, OutputState},
-    reexports::client::{
-        protocol::{wl_keyboard, wl_output, wl_seat, wl_shm, wl_surface},
-        Connection, QueueHandle,
-    },
-    registry::{ProvidesRegistryState, RegistryState},
-    registry_handlers,
-    seat::{
-        keyboard::{KeyEvent, KeyboardHandler, Modifiers},
-        Capability, SeatHandler, SeatState,
-    },
-    shell::xdg::{
-        window::{Window, WindowConfigure, WindowHandler, XdgWindowState},
-        XdgShellHandler, XdgShellState,
-    },
-    shm::{
-        slot::{Buffer, SlotPool},
-        ShmHandler, ShmState,
-    },
-};
-use state::Request;
-use udev::MonitorBuilder;
-use zbus::dbus_interface;
-
-use crate::{action::Action, state::PlayerState};
+use toc::Toc;
+use udev::{EventType, MonitorBuilder};
 
-struct MprisInterface;
+use crate::{action::Action, disc::Drive, state::PlayerState};
 
-#[dbus_interface(name = "org.mpris.MediaPlayer2")]
-impl MprisInterface {}
-
-struct MprisPlayerInterface {
-    player_state: Arc<Mutex<PlayerState>>,
-}
+fn main() -> Result<()> {
+    env_logger::init();
 
-#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
-impl MprisPlayerInterface {
-    async fn next(&self) {
-        self.player_state
-            .lock()
-            .unwrap()
-            .handle_request(Request::NextTrack);
+    // Play a BIN/CUE, NRG or TOC disc image (or a fake disc) instead of the CD-ROM drive
+    // and optionally dump the raw samples into a file instead of playing them.
+    // The other options override the configuration file.
+    // `import FILE...` fills the metadata database instead of playing anything.
+    // `rip [IMAGE]` rips the disc (or the image) to FLAC instead of playing it.
+    // `offset [IMAGE]` detects the read offset of the drive with a disc known to AccurateRip.
+    let mut positional = Vec::new();
+    let mut pcm_output = None;
+    let mut config_path = None;
+    let mut overrides = Overrides::default();
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} requires a value", arg.to_string_lossy()))
+        };
+        match arg.to_str() {
+            Some("--output") => pcm_output = Some(PathBuf::from(value()?)),
+            Some("--config") => config_path = Some(PathBuf::from(value()?)),
+            Some("--device") => overrides.device = Some(PathBuf::from(value()?)),
+            Some("--speed") => {
+                let value = value()?;
+                overrides.speed = Some(
+                    value
+                        .to_string_lossy()
+                        .parse()
+                        .context("--speed requires a number")?,
+                );
+            }
+            Some("--autoplay") => overrides.autoplay = Some(true),
+            Some("--no-autoplay") => overrides.autoplay = Some(false),
+            Some(option) if option.starts_with("--") => bail!("unknown option {option}"),
+            _ => positional.push(PathBuf::from(&arg)),
+        }
     }
 
-    async fn play_pause(&self) {
-        println!("HERE");
-        self.player_state
-            .lock()
-            .unwrap()
-            .handle_request(Request::TogglePlay);
+    let mut config = Config::load(config_path.as_deref())?;
+    config.apply(overrides);
+    config.validate()?;
+    let config = Arc::new(config);
+
+    if positional
+        .first()
+        .is_some_and(|command| command.as_os_str() == "import")
+    {
+        let database = Database::new(config.metadata.database.clone());
+        for file in &positional[1..] {
+            let discs = database.import(file)?;
+            info!("imported {discs} discs from {}", file.display());
+        }
+        return Ok(());
     }
-}
+    if positional
+        .first()
+        .is_some_and(|command| command.as_os_str() == "rip")
+    {
+        if positional.len() > 2 {
+            bail!("only a single disc image can be ripped");
+        }
+        return rip(positional.get(1).map(PathBuf::as_path), &config);
+    }
+    if positional
+        .first()
+        .is_some_and(|command| command.as_os_str() == "offset")
+    {
+        if positional.len() > 2 {
+            bail!("only a single disc image can be used");
+        }
+        return detect_offset(positional.get(1).map(PathBuf::as_path), &config);
+    }
+    if positional.len() > 1 {
+        bail!("only a single disc image can be played");
+    }
+    let image = positional.pop();
 
-fn main() -> Result<()> {
-    env_logger::init();
+    let has_disc = || image.is_some() || Drive::has_audio_cd(&config.drive.device);
 
     let mut socket = MonitorBuilder::new()
         .context("monitor build failed")?
         .match_subsystem("block")
         .context("subsystem filter failed")?
+        // Keyboards and remotes being plugged in and out
+        .match_subsystem("input")
+        .context("subsystem filter failed")?
         // .match_tag("ID_CDROM_CD_R=1")
         // .context("tag filter failed")?
         .listen()
@@ -94,22 +147,14 @@ fn main() -> Result<()> {
     let (tx, rx) = flume::bounded(2);
 
     let state = Arc::new(Mutex::new(PlayerState::new(tx, rx)));
+    state.lock().unwrap().seek_step = config.playback.seek_step();
 
-    let mpris_player = MprisPlayerInterface {
-        player_state: state.clone(),
-    };
-    let dbus = zbus::blocking::ConnectionBuilder::session()?
-        .serve_at("/org/mpris/MediaPlayer2", MprisInterface)?
-        .serve_at("/org/mpris/MediaPlayer2/Player", mpris_player)?
-        .build()?;
-    dbus.request_name("org.mpris.MediaPlayer2.raspicdplayer")?;
-
-    std::fs::create_dir_all("/tmp/raspi-cd-player").unwrap();
-
-    let spawn_player = |state| {
-        thread::spawn(|| {
+    let spawn_player = |state, ring| {
+        let pcm_output = pcm_output.clone();
+        thread::spawn(move || {
             let rtry = || -> Result<()> {
-                let mut player = Player::new(state)?;
+                let audio_output = Player::open_output(pcm_output.as_deref())?;
+                let mut player = Player::new(state, audio_output, ring)?;
                 player.handle()?;
                 Ok(())
             };
@@ -119,10 +164,13 @@ fn main() -> Result<()> {
         })
     };
 
-    let spawn_reader = |state| {
-        thread::spawn(|| {
+    let spawn_reader = |state, ring| {
+        let image = image.clone();
+        let config = config.clone();
+        thread::spawn(move || {
             let rtry = || -> Result<()> {
-                let mut reader = Reader::new(state)?;
+                let source = disc::open(image.as_deref(), &config)?;
+                let mut reader = Reader::new(state, source, ring, config)?;
                 reader.handle()?;
                 Ok(())
             };
@@ -132,26 +180,45 @@ fn main() -> Result<()> {
         })
     };
 
-    let (mut reader_thread, mut player_thread) = if Reader::get_drive().is_some() {
+    // Both threads are started together, sharing a new ring for every disc
+    let spawn_threads = |state: &Arc<Mutex<PlayerState>>| {
+        let (producer, consumer) = ring::ring(config.playback.read_ahead_sectors());
         (
-            Some(spawn_player(state.clone())),
-            Some(spawn_reader(state.clone())),
+            Some(spawn_reader(state.clone(), producer)),
+            Some(spawn_player(state.clone(), consumer)),
         )
-    } else {
+    };
+
+    // Wait for a key press before playing, unless the configuration says otherwise
+    if !has_disc() || !config.playback.autoplay {
         state.lock().unwrap().change_action(Action::Stop);
+    }
+    let (mut reader_thread, mut player_thread) = if has_disc() {
+        spawn_threads(&state)
+    } else {
         (None, None)
     };
 
-    let conn = Connection::connect_to_env().unwrap();
+    let mut event_loop = calloop::EventLoop::<()>::try_new()?;
 
-    let event_queue = conn.new_event_queue();
-    let qh = event_queue.handle();
+    let _dbus = mpris::serve(state.clone(), event_loop.get_signal())?;
 
-    let mut event_loop = calloop::EventLoop::<SimpleWindow>::try_new()?;
-
-    WaylandSource::new(event_queue)?
-        .insert(event_loop.handle())
-        .unwrap();
+    // The Wayland window is optional, the player is usually controlled by a remote on a
+    // headless machine
+    #[cfg(feature = "wayland")]
+    let has_window = match window::spawn(
+        state.clone(),
+        event_loop.get_signal(),
+        config.keys.window.clone(),
+    ) {
+        Ok(_) => true,
+        Err(err) => {
+            info!("running without a window: {err}");
+            false
+        }
+    };
+    #[cfg(not(feature = "wayland"))]
+    let has_window = false;
 
     event_loop
         .handle()
@@ -161,63 +228,37 @@ fn main() -> Result<()> {
         )
         .unwrap();
 
-    let mut simple_window = SimpleWindow {
-        registry_state: RegistryState::new(&conn, &qh),
-        seat_state: SeatState::new(),
-        output_state: OutputState::new(),
-        compositor_state: CompositorState::new(),
-        shm_state: ShmState::new(),
-        xdg_shell_state: XdgShellState::new(),
-        xdg_window_state: XdgWindowState::new(),
-
-        exit: false,
-        first_configure: true,
-        pool: None,
-        width: 256,
-        height: 256,
-        buffer: None,
-        window: None,
-        keyboard: None,
-
-        player_state: state.clone(),
-    };
-
-    while !simple_window.registry_state.ready() {
-        event_loop.dispatch(None, &mut simple_window).unwrap();
-    }
-
-    let pool = SlotPool::new(
-        simple_window.width as usize * simple_window.height as usize * 4,
-        &simple_window.shm_state,
-    )
-    .expect("Failed to create pool");
-    simple_window.pool = Some(pool);
-
-    let surface = simple_window.compositor_state.create_surface(&qh).unwrap();
-
-    let window = Window::builder()
-        .title("raspi-cd-player")
-        // GitHub does not let projects use the `org.github` domain but the `io.github` domain is fine.
-        .min_size((256, 256))
-        .map(
-            &qh,
-            &simple_window.xdg_shell_state,
-            &mut simple_window.xdg_window_state,
-            surface,
-        )
-        .expect("window creation");
+    let mut inputs = Inputs::new(event_loop.handle(), config.keys.input_bindings(!has_window));
+    inputs.scan();
 
-    simple_window.window = Some(window);
+    loop {
+        event_loop.dispatch(None, &mut ()).unwrap();
 
-    // We don't draw immediately, the configure will notify us when to first draw.
+        inputs.dispatch(&state);
 
-    loop {
-        event_loop.dispatch(None, &mut simple_window).unwrap();
+        let mut disc_changed = false;
+        while let Some(udev_event) = socket.next() {
+            let Some(devnode) = udev_event.devnode() else {
+                continue;
+            };
+            if devnode == config.drive.device {
+                disc_changed = true;
+            } else if udev_event
+                .subsystem()
+                .is_some_and(|subsystem| subsystem == "input")
+                && input::is_event_device(devnode)
+            {
+                match udev_event.event_type() {
+                    EventType::Add => inputs.add(devnode),
+                    EventType::Remove => inputs.remove(devnode),
+                    _ => {}
+                }
+            }
+        }
 
-        if let Some(udev_event) = socket.next() && udev_event.devnode().unwrap().ends_with("sr0") {
-            while socket.next().is_some() {}
-            if Reader::get_drive().is_some() {
-                state.lock().unwrap().change_action(Action::Stop);
+        if disc_changed {
+            if has_disc() {
+                state.lock().unwrap().change_action(Action::Quit);
                 if let Some(thread) = reader_thread {
                     thread.join();
                 }
@@ -225,12 +266,15 @@ fn main() -> Result<()> {
                     thread.join();
                 }
 
-                state.lock().unwrap().change_action(Action::Play(1));
-                player_thread = Some(spawn_player(state.clone()));
-                reader_thread = Some(spawn_reader(state.clone()));
+                if config.playback.autoplay {
+                    state.lock().unwrap().play_track(1);
+                } else {
+                    state.lock().unwrap().change_action(Action::Stop);
+                }
+                (reader_thread, player_thread) = spawn_threads(&state);
             } else {
                 // The cd has been removed
-                state.lock().unwrap().change_action(Action::Stop);
+                state.lock().unwrap().change_action(Action::Quit);
                 if let Some(thread) = reader_thread {
                     thread.join();
                 }
@@ -238,13 +282,13 @@ fn main() -> Result<()> {
                     thread.join();
                 }
                 reader_thread = None;
-                player_thread= None;
+                player_thread = None;
             }
         }
 
-        if simple_window.exit {
+        if state.lock().unwrap().exiting {
             info!("exiting");
-            state.lock().unwrap().change_action(Action::Stop);
+            state.lock().unwrap().change_action(Action::Quit);
             if let Some(thread) = reader_thread {
                 thread.join();
             }
@@ -258,325 +302,73 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-struct SimpleWindow {
-    registry_state: RegistryState,
-    seat_state: SeatState,
-    output_state: OutputState,
-    compositor_state: CompositorState,
-    shm_state: ShmState,
-    xdg_shell_state: XdgShellState,
-    xdg_window_state: XdgWindowState,
-
-    exit: bool,
-    first_configure: bool,
-    pool: Option<SlotPool>,
-    width: u32,
-    height: u32,
-    buffer: Option<Buffer>,
-    window: Option<Window>,
-    keyboard: Option<wl_keyboard::WlKeyboard>,
-
-    player_state: Arc<Mutex<PlayerState>>,
-}
-
-impl CompositorHandler for SimpleWindow {
-    fn compositor_state(&mut self) -> &mut CompositorState {
-        &mut self.compositor_state
-    }
-
-    fn scale_factor_changed(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
-        _new_factor: i32,
-    ) {
-        // Not needed for this example.
+/// Rip the disc in the drive, or `image`, without playing it
+fn rip(image: Option<&Path>, config: &Config) -> Result<()> {
+    let mut source = disc::open(image, config)?;
+    let toc = Toc::read(source.as_mut())?;
+    let lookup = MetadataLookup::new(&config.metadata);
+    let (mut metadata, missing) = read_cd::load_metadata(source.as_ref(), &toc, &lookup)?;
+    // There is no hurry, wait for the online providers
+    if missing
+        && let Some(disc_id) = &metadata.disc_id
+        && let Some(release) = lookup.online(disc_id)
+    {
+        metadata.apply(release, &toc);
     }
 
-    fn frame(
-        &mut self,
-        conn: &Connection,
-        qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
-        _time: u32,
-    ) {
-        self.draw(conn, qh);
-    }
-}
-
-impl OutputHandler for SimpleWindow {
-    fn output_state(&mut self) -> &mut OutputState {
-        &mut self.output_state
+    let mut buf = vec![0; ring::SECTOR_SIZE * config.drive.read_sectors as usize];
+    let files = rip::rip_disc(source.as_mut(), &toc, &metadata, config, &mut buf, || false)?;
+    for file in &files {
+        println!("{}", file.display());
     }
-
-    fn new_output(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
-    ) {
-    }
-
-    fn update_output(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
-    ) {
-    }
-
-    fn output_destroyed(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
-    ) {
-    }
-}
-
-impl XdgShellHandler for SimpleWindow {
-    fn xdg_shell_state(&mut self) -> &mut XdgShellState {
-        &mut self.xdg_shell_state
-    }
-}
-
-impl WindowHandler for SimpleWindow {
-    fn xdg_window_state(&mut self) -> &mut XdgWindowState {
-        &mut self.xdg_window_state
-    }
-
-    fn request_close(&mut self, _: &Connection, _: &QueueHandle<Self>, _: &Window) {
-        self.exit = true;
-    }
-
-    fn configure(
-        &mut self,
-        conn: &Connection,
-        qh: &QueueHandle<Self>,
-        _window: &Window,
-        configure: WindowConfigure,
-        _serial: u32,
-    ) {
-        match configure.new_size {
-            Some(size) => {
-                self.width = size.0;
-                self.height = size.1;
-                self.buffer = None;
-            }
-            None => {
-                self.width = 256;
-                self.height = 256;
-                self.buffer = None;
-            }
-        }
-
-        // Initiate the first draw.
-        if self.first_configure {
-            self.first_configure = false;
-            self.draw(conn, qh);
-        }
-    }
-}
-
-impl SeatHandler for SimpleWindow {
-    fn seat_state(&mut self) -> &mut SeatState {
-        &mut self.seat_state
-    }
-
-    fn new_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_seat::WlSeat) {}
-
-    fn new_capability(
-        &mut self,
-        _conn: &Connection,
-        qh: &QueueHandle<Self>,
-        seat: wl_seat::WlSeat,
-        capability: Capability,
-    ) {
-        if capability == Capability::Keyboard && self.keyboard.is_none() {
-            let keyboard = self
-                .seat_state
-                .get_keyboard(qh, &seat, None)
-                .expect("Failed to create keyboard");
-            self.keyboard = Some(keyboard);
-        }
-    }
-
-    fn remove_capability(
-        &mut self,
-        _conn: &Connection,
-        _: &QueueHandle<Self>,
-        _: wl_seat::WlSeat,
-        capability: Capability,
-    ) {
-        if capability == Capability::Keyboard && self.keyboard.is_some() {
-            self.keyboard.take().unwrap().release();
-        }
-    }
-
-    fn remove_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_seat::WlSeat) {}
+    println!("Ripped {} tracks", files.len());
+    Ok(())
 }
 
-impl KeyboardHandler for SimpleWindow {
-    fn enter(
-        &mut self,
-        _: &Connection,
-        _: &QueueHandle<Self>,
-        _: &wl_keyboard::WlKeyboard,
-        _surface: &wl_surface::WlSurface,
-        _: u32,
-        _: &[u32],
-        _keysyms: &[u32],
-    ) {
-    }
-
-    fn leave(
-        &mut self,
-        _: &Connection,
-        _: &QueueHandle<Self>,
-        _: &wl_keyboard::WlKeyboard,
-        _surface: &wl_surface::WlSurface,
-        _: u32,
-    ) {
-    }
-
-    fn press_key(
-        &mut self,
-        _conn: &Connection,
-        _qh: &QueueHandle<Self>,
-        _: &wl_keyboard::WlKeyboard,
-        _: u32,
-        event: KeyEvent,
-    ) {
-        // println!("{:?}", event);
-        let req = match event {
-            KeyEvent {
-                time: _,
-                raw_code: _,
-                keysym: _,
-                utf8,
-            } => match utf8 {
-                Some(key) => match key.as_str() {
-                    " " => Request::TogglePlay,
-                    "<" => Request::PreviousTrack,
-                    ">" => Request::NextTrack,
-                    "q" => Request::Quit,
-                    &_ => Request::None,
-                },
-                None => Request::None,
-            },
-        };
+/// Find the read offset of the drive, comparing a sector of every track with the one AccurateRip
+/// keeps for offset detection
+fn detect_offset(image: Option<&Path>, config: &Config) -> Result<()> {
+    let mut source = disc::open(image, config)?;
+    let toc = Toc::read(source.as_mut())?;
+    let Some(accuraterip) = accuraterip::lookup(&config.accuraterip.database, &toc.disc_id())
+    else {
+        println!("The disc isn't in the AccurateRip database, try with another one");
+        process::exit(1);
+    };
 
-        if matches!(req, Request::Quit) {
-            self.exit = true;
+    // The sector checked, with as many samples around it as the offset can be
+    let margin = accuraterip::MAX_READ_OFFSET as usize * 4;
+    let around = margin.div_ceil(ring::SECTOR_SIZE) as i32;
+    let mut buf = vec![0; ring::SECTOR_SIZE * (2 * around as usize + 1)];
+    let mut offsets = Vec::new();
+    // AccurateRip counts the audio tracks alone
+    let audio_tracks = toc.tracks.iter().filter(|track| track.audio);
+    for (toc_track, position) in audio_tracks.zip(1..) {
+        let track = toc_track.number;
+        let first = toc_track.start + accuraterip::OFFSET_SECTOR - around;
+        if first < 0 || first + 2 * around + 1 > toc_track.end {
+            continue;
         }
-        self.player_state.lock().unwrap().handle_request(req);
-    }
-
-    fn release_key(
-        &mut self,
-        _: &Connection,
-        _: &QueueHandle<Self>,
-        _: &wl_keyboard::WlKeyboard,
-        _: u32,
-        event: KeyEvent,
-    ) {
-    }
-
-    fn update_modifiers(
-        &mut self,
-        _: &Connection,
-        _: &QueueHandle<Self>,
-        _: &wl_keyboard::WlKeyboard,
-        _serial: u32,
-        modifiers: Modifiers,
-    ) {
-    }
-}
-
-impl ShmHandler for SimpleWindow {
-    fn shm_state(&mut self) -> &mut ShmState {
-        &mut self.shm_state
-    }
-}
-
-impl SimpleWindow {
-    pub fn draw(&mut self, _conn: &Connection, qh: &QueueHandle<Self>) {
-        if let Some(window) = self.window.as_ref() {
-            let width = self.width;
-            let height = self.height;
-            let stride = self.width as i32 * 4;
-            let pool = self.pool.as_mut().unwrap();
-
-            if self.buffer.is_none() {
-                self.buffer = Some(
-                    pool.create_buffer(
-                        width as i32,
-                        height as i32,
-                        stride,
-                        wl_shm::Format::Argb8888,
-                    )
-                    .expect("create buffer")
-                    .0,
-                );
-                let buffer = self.buffer.as_ref().unwrap();
-
-                let canvas = pool.canvas(buffer).unwrap();
-
-                canvas
-                    .chunks_exact_mut(4)
-                    .enumerate()
-                    .for_each(|(_, chunk)| {
-                        let a = 0xFF;
-                        let r = 0;
-                        let g = 0;
-                        let b = 0;
-                        let color: i32 = (a << 24) + (r << 16) + (g << 8) + b;
-
-                        let array: &mut [u8; 4] = chunk.try_into().unwrap();
-                        *array = color.to_le_bytes();
-                    });
-
-                // Damage the entire window
-                window
-                    .wl_surface()
-                    .damage_buffer(0, 0, self.width as i32, self.height as i32);
-
-                // Request our next frame
-                window.wl_surface().frame(qh, window.wl_surface().clone());
-
-                // Attach and commit to present.
-                buffer
-                    .attach_to(window.wl_surface())
-                    .expect("buffer attach");
-                window.wl_surface().commit();
+        source.read_audio_sectors(&mut buf, first, 2 * around as u32 + 1)?;
+        let start = around as usize * ring::SECTOR_SIZE - margin;
+        let end = (around as usize + 1) * ring::SECTOR_SIZE + margin;
+        match accuraterip.detect_offset(position, &buf[start..end]) {
+            Some((offset, confidence)) => {
+                println!("Track {track}: read offset {offset} (confidence {confidence})");
+                offsets.push(offset);
             }
+            None => println!("Track {track}: no read offset matches"),
         }
     }
-}
 
-delegate_compositor!(SimpleWindow);
-delegate_output!(SimpleWindow);
-delegate_shm!(SimpleWindow);
-
-delegate_seat!(SimpleWindow);
-delegate_keyboard!(SimpleWindow);
-
-delegate_xdg_shell!(SimpleWindow);
-delegate_xdg_window!(SimpleWindow);
-
-delegate_registry!(SimpleWindow);
-
-impl ProvidesRegistryState for SimpleWindow {
-    fn registry(&mut self) -> &mut RegistryState {
-        &mut self.registry_state
-    }
-    registry_handlers![
-        CompositorState,
-        OutputState,
-        ShmState,
-        SeatState,
-        XdgShellState,
-        XdgWindowState,
-    ];
+    // The offset most tracks agree on
+    let Some(offset) = offsets
+        .iter()
+        .max_by_key(|&offset| offsets.iter().filter(|&other| other == offset).count())
+    else {
+        println!("Unable to detect the read offset with this disc, try with another one");
+        process::exit(1);
+    };
+    println!("The read offset of the drive is {offset}, set drive.read_offset = {offset}");
+    Ok(())
 }
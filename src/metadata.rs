@@ -0,0 +1,187 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{database::Release, disc_id::DiscId, toc::Toc};
+
+/// The text fields CD-Text carries, for the whole disc or for a single track
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TextFields {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub composer: Option<String>,
+    pub arranger: Option<String>,
+    pub message: Option<String>,
+}
+
+impl TextFields {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A CD-Text block, in a single language
+#[derive(Clone, Debug, Default)]
+pub struct CdText {
+    pub language: String,
+    pub genre: Option<String>,
+    pub disc: TextFields,
+    /// By track number
+    pub tracks: BTreeMap<u8, TextFields>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub songwriter: Option<String>,
+    pub composer: Option<String>,
+    /// The International Standard Recording Code, as read from the disc
+    pub isrc: Option<String>,
+}
+
+/// Everything known about the disc being played
+#[derive(Clone, Debug, Default)]
+pub struct DiscMetadata {
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    /// The cover art image
+    pub cover: Option<PathBuf>,
+    /// By track number
+    pub tracks: BTreeMap<u8, TrackMetadata>,
+    /// Every CD-Text block found on the disc, the first one is the default language
+    pub cdtext: Vec<CdText>,
+    /// Computed from the TOC, None if it couldn't be read
+    pub disc_id: Option<DiscId>,
+    /// The Media Catalog Number, the UPC/EAN barcode of the disc, as read from it
+    pub mcn: Option<String>,
+}
+
+impl DiscMetadata {
+    pub fn from_cdtext(cdtext: Vec<CdText>) -> Self {
+        let mut metadata = Self::default();
+        if let Some(block) = cdtext.first() {
+            metadata.album = block.disc.title.clone();
+            metadata.artist = block.disc.performer.clone();
+            metadata.genre = block.genre.clone();
+            metadata.tracks = block
+                .tracks
+                .iter()
+                .map(|(&track, text)| {
+                    (
+                        track,
+                        TrackMetadata {
+                            title: text.title.clone(),
+                            artist: text.performer.clone(),
+                            songwriter: text.songwriter.clone(),
+                            composer: text.composer.clone(),
+                            isrc: None,
+                        },
+                    )
+                })
+                .collect();
+        }
+        metadata.cdtext = cdtext;
+        metadata
+    }
+
+    /// Fill in what is known about the disc from a metadata database, which takes precedence over
+    /// the CD-Text. MusicBrainz lists only the audio tracks of `toc`, CDDB every one of them.
+    pub fn apply(&mut self, release: Release, toc: &Toc) {
+        let numbers = if release.tracks.len() == toc.tracks.len() {
+            toc.tracks.iter().map(|track| track.number).collect()
+        } else {
+            toc.audio_tracks()
+        };
+        self.album = release.album.or(self.album.take());
+        self.artist = release.artist.or(self.artist.take());
+        self.genre = release.genre.or(self.genre.take());
+        self.year = release.year.or(self.year);
+        self.cover = release.cover.or(self.cover.take());
+        for (release_track, track) in release.tracks.into_iter().zip(numbers) {
+            let metadata = self.tracks.entry(track).or_default();
+            metadata.title = release_track.title.or(metadata.title.take());
+            metadata.artist = release_track.artist.or(metadata.artist.take());
+        }
+    }
+
+    pub fn track(&self, track: u8) -> Option<&TrackMetadata> {
+        self.tracks.get(&track)
+    }
+
+    /// The title of `track`, or a generic one if unknown
+    pub fn track_title(&self, track: u8) -> String {
+        self.track(track)
+            .and_then(|metadata| metadata.title.clone())
+            .unwrap_or_else(|| format!("Track {track}"))
+    }
+
+    /// The ISRC of `track`, if the disc has it
+    pub fn track_isrc(&self, track: u8) -> Option<String> {
+        self.track(track).and_then(|metadata| metadata.isrc.clone())
+    }
+
+    /// The artist of `track`, falling back to the one of the whole disc
+    pub fn track_artist(&self, track: u8) -> Option<String> {
+        self.track(track)
+            .and_then(|metadata| metadata.artist.clone())
+            .or_else(|| self.artist.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::ReleaseTrack, fake_disc::FakeDisc};
+
+    fn release(titles: &[&str]) -> Release {
+        Release {
+            album: Some("Album".to_string()),
+            tracks: titles
+                .iter()
+                .map(|title| ReleaseTrack {
+                    title: Some(title.to_string()),
+                    artist: None,
+                })
+                .collect(),
+            ..Release::default()
+        }
+    }
+
+    fn titles(metadata: &DiscMetadata) -> Vec<String> {
+        (1..=3).map(|track| metadata.track_title(track)).collect()
+    }
+
+    #[test]
+    fn release_tracks_of_a_mixed_mode_disc() {
+        let mut disc: FakeDisc = "data:10,sine:10,sine:10".parse().unwrap();
+        let toc = Toc::read(&mut disc).unwrap();
+
+        // As MusicBrainz lists them
+        let mut metadata = DiscMetadata::default();
+        metadata.apply(release(&["One", "Two"]), &toc);
+        assert_eq!(titles(&metadata), ["Track 1", "One", "Two"]);
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+
+        // As CDDB lists them
+        let mut metadata = DiscMetadata::default();
+        metadata.apply(release(&["Data", "One", "Two"]), &toc);
+        assert_eq!(titles(&metadata), ["Data", "One", "Two"]);
+    }
+
+    #[test]
+    fn release_takes_precedence_over_the_cd_text() {
+        let mut disc: FakeDisc = "sine:10,sine:10".parse().unwrap();
+        let toc = Toc::read(&mut disc).unwrap();
+        let mut metadata = DiscMetadata::default();
+        metadata.tracks.entry(1).or_default().title = Some("CD-Text".to_string());
+        metadata.tracks.entry(2).or_default().title = Some("CD-Text".to_string());
+        let mut release = release(&["One"]);
+        release.tracks.push(ReleaseTrack::default());
+        metadata.apply(release, &toc);
+        assert_eq!(metadata.track_title(1), "One");
+        // Unless it doesn't know
+        assert_eq!(metadata.track_title(2), "CD-Text");
+    }
+}
@@ -0,0 +1,637 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use calloop::LoopSignal;
+use color_eyre::Result;
+use log::warn;
+use zbus::{
+    blocking::{Connection, ConnectionBuilder},
+    dbus_interface,
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+    SignalContext,
+};
+
+use crate::{
+    action::Action,
+    state::{LoopStatus, PlayerState, Position, Request, FRAMES_PER_SECOND, FRAMES_PER_SECTOR},
+};
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Serve the MPRIS interfaces on the session bus and keep them updated as the state changes
+pub fn serve(state: Arc<Mutex<PlayerState>>, signal: LoopSignal) -> Result<Connection> {
+    let changes = state.lock().unwrap().watch();
+    let dbus = ConnectionBuilder::session()?
+        .serve_at(
+            MPRIS_PATH,
+            MprisInterface {
+                player_state: state.clone(),
+                signal,
+            },
+        )?
+        .serve_at(
+            MPRIS_PATH,
+            MprisPlayerInterface {
+                player_state: state.clone(),
+            },
+        )?
+        .serve_at(
+            MPRIS_PATH,
+            MprisTrackListInterface {
+                player_state: state.clone(),
+            },
+        )?
+        .serve_at(
+            MPRIS_PATH,
+            PlayerInterface {
+                player_state: state.clone(),
+            },
+        )?
+        .serve_at(
+            MPRIS_PATH,
+            RipperInterface {
+                player_state: state.clone(),
+            },
+        )?
+        .build()?;
+    dbus.request_name("org.mpris.MediaPlayer2.raspicdplayer")?;
+
+    let connection = dbus.clone();
+    thread::spawn(move || {
+        if let Err(err) = emit_changes(&connection, &state, changes) {
+            warn!("{err}");
+        }
+    });
+
+    Ok(dbus)
+}
+
+/// Emit PropertiesChanged (and Seeked) every time the state changes
+fn emit_changes(
+    dbus: &Connection,
+    state: &Mutex<PlayerState>,
+    changes: flume::Receiver<()>,
+) -> zbus::Result<()> {
+    let player = dbus
+        .object_server()
+        .interface::<_, MprisPlayerInterface>(MPRIS_PATH)?;
+    let track_list = dbus
+        .object_server()
+        .interface::<_, MprisTrackListInterface>(MPRIS_PATH)?;
+    let status = dbus
+        .object_server()
+        .interface::<_, PlayerInterface>(MPRIS_PATH)?;
+    let ripper = dbus
+        .object_server()
+        .interface::<_, RipperInterface>(MPRIS_PATH)?;
+    let (mut last_action, mut last_seek, mut last_disc) = {
+        let state = state.lock().unwrap();
+        (state.action, state.seek.id, state.disc_generation)
+    };
+
+    while changes.recv().is_ok() {
+        let (action, seek, position, disc, audio_tracks) = {
+            let state = state.lock().unwrap();
+            (
+                state.action,
+                state.seek,
+                *state.position.read().unwrap(),
+                state.disc_generation,
+                state.toc.audio_tracks(),
+            )
+        };
+        let ctxt = player.signal_context();
+        let iface = player.get();
+        zbus::block_on(async {
+            // A new disc has been inserted
+            if disc != last_disc {
+                let tracks = audio_tracks
+                    .iter()
+                    .copied()
+                    .map(track_object_path)
+                    .collect();
+                let current = track_of(action).map_or_else(no_track, track_object_path);
+                MprisTrackListInterface::track_list_replaced(
+                    track_list.signal_context(),
+                    tracks,
+                    current,
+                )
+                .await?;
+                track_list
+                    .get()
+                    .tracks_changed(track_list.signal_context())
+                    .await?;
+            }
+            iface.playback_status_changed(ctxt).await?;
+            iface.metadata_changed(ctxt).await?;
+            iface.loop_status_changed(ctxt).await?;
+            iface.shuffle_changed(ctxt).await?;
+            iface.volume_changed(ctxt).await?;
+            iface.can_go_next_changed(ctxt).await?;
+            iface.can_go_previous_changed(ctxt).await?;
+            iface.can_play_changed(ctxt).await?;
+            iface.can_pause_changed(ctxt).await?;
+            iface.can_seek_changed(ctxt).await?;
+            status.get().index_changed(status.signal_context()).await?;
+            ripper
+                .get()
+                .verification_changed(ripper.signal_context())
+                .await?;
+            // Seeking within the same track
+            if seek.id != last_seek && track_of(last_action) == Some(seek.track) {
+                MprisPlayerInterface::seeked(ctxt, frames_to_micros(position)).await?;
+            }
+            Ok::<_, zbus::Error>(())
+        })?;
+        (last_action, last_seek, last_disc) = (action, seek.id, disc);
+    }
+
+    Ok(())
+}
+
+fn track_of(action: Action) -> Option<u8> {
+    match action {
+        Action::Play(track) | Action::Pause(track) => Some(track),
+        Action::Stop | Action::Quit => None,
+    }
+}
+
+fn frames_to_micros(frames: u64) -> i64 {
+    (frames * 1_000_000 / FRAMES_PER_SECOND) as i64
+}
+
+fn track_path(track: u8) -> String {
+    format!("/org/raspicdplayer/track/{track}")
+}
+
+fn track_object_path(track: u8) -> OwnedObjectPath {
+    ObjectPath::try_from(track_path(track)).unwrap().into()
+}
+
+fn no_track() -> OwnedObjectPath {
+    ObjectPath::from_static_str_unchecked(NO_TRACK).into()
+}
+
+/// The track number behind one of our track paths, if it is on the disc
+fn track_from_path(state: &PlayerState, path: &ObjectPath<'_>) -> Option<u8> {
+    path.as_str()
+        .strip_prefix("/org/raspicdplayer/track/")?
+        .parse()
+        .ok()
+        .filter(|&track| state.toc.is_audio(track))
+}
+
+fn track_frames(state: &PlayerState, track: u8) -> u64 {
+    let (start, end) = state.toc.sectors(track);
+    (end - start) as u64 * FRAMES_PER_SECTOR
+}
+
+fn track_metadata(state: &PlayerState, track: u8) -> HashMap<String, OwnedValue> {
+    let mut metadata = HashMap::new();
+    metadata.insert(
+        "mpris:trackid".to_string(),
+        Value::from(track_object_path(track)).into(),
+    );
+    if state.toc.is_audio(track) {
+        metadata.insert(
+            "mpris:length".to_string(),
+            Value::from(frames_to_micros(track_frames(state, track))).into(),
+        );
+    }
+    metadata.insert(
+        "xesam:trackNumber".to_string(),
+        Value::from(track as i32).into(),
+    );
+
+    let disc = &state.metadata;
+    metadata.insert(
+        "xesam:title".to_string(),
+        Value::from(disc.track_title(track)).into(),
+    );
+    let mut insert = |key: &str, value: Option<String>, list: bool| {
+        if let Some(value) = value {
+            let value = if list {
+                Value::from(vec![value])
+            } else {
+                Value::from(value)
+            };
+            metadata.insert(key.to_string(), value.into());
+        }
+    };
+    insert("xesam:album", disc.album.clone(), false);
+    insert("xesam:albumArtist", disc.artist.clone(), true);
+    insert("xesam:genre", disc.genre.clone(), true);
+    insert("xesam:artist", disc.track_artist(track), true);
+    insert(
+        "xesam:contentCreated",
+        disc.year.map(|year| year.to_string()),
+        false,
+    );
+    insert(
+        "mpris:artUrl",
+        disc.cover
+            .as_ref()
+            .map(|cover| format!("file://{}", cover.display())),
+        false,
+    );
+    if let Some(info) = disc.track(track) {
+        insert("xesam:composer", info.composer.clone(), true);
+        insert("xesam:lyricist", info.songwriter.clone(), true);
+    }
+    // Not in the specification, the codes identifying the recording and the release
+    insert("xesam:isrc", disc.track_isrc(track), false);
+    insert("xesam:mcn", disc.mcn.clone(), false);
+    // Not in the specification, the index point being played
+    if track_of(state.action) == Some(track) {
+        metadata.insert(
+            "xesam:trackIndex".to_string(),
+            Value::from(i32::from(state.index)).into(),
+        );
+    }
+    metadata
+}
+
+struct MprisInterface {
+    player_state: Arc<Mutex<PlayerState>>,
+    signal: LoopSignal,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl MprisInterface {
+    fn raise(&self) {}
+
+    fn quit(&self) {
+        self.player_state
+            .lock()
+            .unwrap()
+            .handle_request(Request::Quit);
+        // Let the main loop notice that we are exiting
+        self.signal.wakeup();
+    }
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> String {
+        "raspi-cd-player".to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn desktop_entry(&self) -> String {
+        "raspi-cd-player".to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        // Only the inserted disc can be played, OpenUri isn't supported
+        Vec::new()
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+struct MprisPlayerInterface {
+    player_state: Arc<Mutex<PlayerState>>,
+}
+
+impl MprisPlayerInterface {
+    fn request(&self, req: Request) {
+        self.player_state.lock().unwrap().handle_request(req);
+    }
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MprisPlayerInterface {
+    async fn next(&self) {
+        self.request(Request::NextTrack);
+    }
+
+    async fn previous(&self) {
+        self.request(Request::PreviousTrack);
+    }
+
+    async fn pause(&self) {
+        self.request(Request::Pause);
+    }
+
+    async fn play_pause(&self) {
+        self.request(Request::TogglePlay);
+    }
+
+    async fn stop(&self) {
+        self.request(Request::Stop);
+    }
+
+    async fn play(&self) {
+        self.request(Request::Play);
+    }
+
+    async fn seek(&self, offset: i64) {
+        self.request(Request::Seek(offset));
+    }
+
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        let length = {
+            let state = self.player_state.lock().unwrap();
+            // The request is stale if it is not about the current track
+            match track_of(state.action) {
+                Some(track) if track_path(track) == track_id.as_str() => {
+                    frames_to_micros(track_frames(&state, track))
+                }
+                _ => return,
+            }
+        };
+        // Positions are in microseconds, negative ones and those past the end of the track must
+        // be ignored
+        if position <= length
+            && let Ok(position) = u64::try_from(position)
+        {
+            self.request(Request::SetPosition(Position::Time(Duration::from_micros(
+                position,
+            ))));
+        }
+    }
+
+    async fn open_uri(&self, _uri: String) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported(
+            "raspi-cd-player can only play the inserted disc".to_string(),
+        ))
+    }
+
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> String {
+        match self.player_state.lock().unwrap().action {
+            Action::Play(_) => "Playing",
+            Action::Pause(_) => "Paused",
+            Action::Stop | Action::Quit => "Stopped",
+        }
+        .to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn loop_status(&self) -> String {
+        match self.player_state.lock().unwrap().loop_status {
+            LoopStatus::None => "None",
+            LoopStatus::Track => "Track",
+            LoopStatus::Playlist => "Playlist",
+        }
+        .to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn set_loop_status(&mut self, loop_status: String) {
+        let loop_status = match loop_status.as_str() {
+            "None" => LoopStatus::None,
+            "Track" => LoopStatus::Track,
+            "Playlist" => LoopStatus::Playlist,
+            _ => return,
+        };
+        self.player_state
+            .lock()
+            .unwrap()
+            .set_loop_status(loop_status);
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn set_rate(&mut self, _rate: f64) {
+        // A CD only plays at its own rate
+    }
+
+    #[dbus_interface(property)]
+    fn shuffle(&self) -> bool {
+        self.player_state.lock().unwrap().shuffle
+    }
+
+    #[dbus_interface(property)]
+    fn set_shuffle(&mut self, shuffle: bool) {
+        self.player_state.lock().unwrap().set_shuffle(shuffle);
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let state = self.player_state.lock().unwrap();
+        match track_of(state.action) {
+            Some(track) => track_metadata(&state, track),
+            None => HashMap::from([("mpris:trackid".to_string(), Value::from(no_track()).into())]),
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        self.player_state.lock().unwrap().volume
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&mut self, volume: f64) {
+        self.player_state.lock().unwrap().set_volume(volume);
+    }
+
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        let position = *self.player_state.lock().unwrap().position.read().unwrap();
+        frames_to_micros(position)
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        // Not on the last track, unless the whole disc loops
+        self.player_state.lock().unwrap().has_following_track()
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        let state = self.player_state.lock().unwrap();
+        state.total_tracks > 0 && !matches!(state.action, Action::Quit)
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        let state = self.player_state.lock().unwrap();
+        state.total_tracks > 0 && !matches!(state.action, Action::Quit)
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        // There is nothing to pause without a track
+        track_of(self.player_state.lock().unwrap().action).is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        track_of(self.player_state.lock().unwrap().action).is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+struct MprisTrackListInterface {
+    player_state: Arc<Mutex<PlayerState>>,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.TrackList")]
+impl MprisTrackListInterface {
+    async fn get_tracks_metadata(
+        &self,
+        track_ids: Vec<OwnedObjectPath>,
+    ) -> Vec<HashMap<String, OwnedValue>> {
+        let state = self.player_state.lock().unwrap();
+        track_ids
+            .iter()
+            .filter_map(|path| track_from_path(&state, path))
+            .map(|track| track_metadata(&state, track))
+            .collect()
+    }
+
+    async fn add_track(
+        &self,
+        _uri: String,
+        _after_track: ObjectPath<'_>,
+        _set_as_current: bool,
+    ) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported(
+            "the tracks of a disc can't be edited".to_string(),
+        ))
+    }
+
+    async fn remove_track(&self, _track_id: ObjectPath<'_>) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported(
+            "the tracks of a disc can't be edited".to_string(),
+        ))
+    }
+
+    async fn go_to(&self, track_id: ObjectPath<'_>) {
+        let state = self.player_state.lock().unwrap();
+        if let Some(track) = track_from_path(&state, &track_id) {
+            state.play_track(track);
+        }
+    }
+
+    #[dbus_interface(signal)]
+    async fn track_list_replaced(
+        ctxt: &SignalContext<'_>,
+        tracks: Vec<OwnedObjectPath>,
+        current_track: OwnedObjectPath,
+    ) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn tracks(&self) -> Vec<OwnedObjectPath> {
+        let audio_tracks = self.player_state.lock().unwrap().toc.audio_tracks();
+        audio_tracks.into_iter().map(track_object_path).collect()
+    }
+
+    #[dbus_interface(property)]
+    fn can_edit_tracks(&self) -> bool {
+        false
+    }
+}
+
+/// What MPRIS has no place for while playing
+struct PlayerInterface {
+    player_state: Arc<Mutex<PlayerState>>,
+}
+
+#[dbus_interface(name = "org.raspicdplayer.Player")]
+impl PlayerInterface {
+    /// Move to the next index point of the current track
+    async fn next_index(&self) {
+        self.player_state
+            .lock()
+            .unwrap()
+            .handle_request(Request::NextIndex);
+    }
+
+    /// Move to the start of the index point before the current one
+    async fn previous_index(&self) {
+        self.player_state
+            .lock()
+            .unwrap()
+            .handle_request(Request::PreviousIndex);
+    }
+
+    /// The index point of the current track being played, 0 in a pregap
+    #[dbus_interface(property)]
+    fn index(&self) -> u8 {
+        self.player_state.lock().unwrap().index
+    }
+}
+
+/// What MPRIS has no place for
+struct RipperInterface {
+    player_state: Arc<Mutex<PlayerState>>,
+}
+
+#[dbus_interface(name = "org.raspicdplayer.Ripper")]
+impl RipperInterface {
+    /// Stop playing and rip the whole disc to FLAC
+    async fn rip(&self) {
+        self.player_state
+            .lock()
+            .unwrap()
+            .handle_request(Request::Rip);
+    }
+
+    /// The tracks read from start to end so far: track number, AccurateRip v1 and v2 checksums,
+    /// CRC32 and AccurateRip confidence, -1 if the disc isn't in the database and 0 if the
+    /// checksums don't match it
+    #[dbus_interface(property)]
+    fn verification(&self) -> Vec<(u8, u32, u32, u32, i32)> {
+        self.player_state
+            .lock()
+            .unwrap()
+            .verification
+            .iter()
+            .map(|(&track, verification)| {
+                let checksums = &verification.checksums;
+                (
+                    track,
+                    checksums.accuraterip_v1,
+                    checksums.accuraterip_v2,
+                    checksums.crc32,
+                    verification.confidence(),
+                )
+            })
+            .collect()
+    }
+}
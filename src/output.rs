@@ -7,9 +7,10 @@
 
 //! Platform-dependant Audio Outputs
 
+use std::io::Write;
 use std::result;
 
-use symphonia::core::audio::{AudioBufferRef, SignalSpec};
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
 use symphonia::core::units::Duration;
 
 pub trait AudioOutput {
@@ -28,6 +29,50 @@ pub enum AudioOutputError {
 
 pub type Result<T> = result::Result<T, AudioOutputError>;
 
+/// Writes the interleaved samples as signed 16-bit little endian PCM to any writer instead of a
+/// sound card, so that what has been played can be checked sample by sample.
+pub struct PcmOutput {
+    writer: Box<dyn Write + Send>,
+    sample_buf: Option<SampleBuffer<i16>>,
+}
+
+impl PcmOutput {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer,
+            sample_buf: None,
+        }
+    }
+}
+
+impl AudioOutput for PcmOutput {
+    fn write(&mut self, decoded: AudioBufferRef<'_>) -> Result<()> {
+        // Do nothing if there are no audio frames.
+        if decoded.frames() == 0 {
+            return Ok(());
+        }
+
+        let sample_buf = self
+            .sample_buf
+            .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+        sample_buf.copy_interleaved_ref(decoded);
+
+        let bytes = sample_buf
+            .samples()
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.writer
+            .write_all(&bytes)
+            .map_err(|_| AudioOutputError::StreamClosedError)
+    }
+
+    fn flush(&mut self) {
+        // Flush is best-effort, ignore the returned result.
+        let _ = self.writer.flush();
+    }
+}
+
 #[cfg(target_os = "linux")]
 mod pulseaudio {
     use super::{AudioOutput, AudioOutputError, Result};
@@ -0,0 +1,582 @@
+use color_eyre::{eyre::bail, Result};
+use log::{info, warn};
+
+use crate::{
+    config::ParanoiaConfig,
+    disc::{DiscSource, C2_SIZE},
+    metadata::CdText,
+    ring::SECTOR_SIZE,
+};
+
+/// Bytes of a stereo sample
+const SAMPLE_SIZE: usize = 4;
+const SAMPLES_PER_SECTOR: usize = SECTOR_SIZE / SAMPLE_SIZE;
+
+/// Consecutive sectors as returned by a single read
+struct Read {
+    data: Vec<u8>,
+    /// The C2 error pointers, if the drive reports them
+    c2: Option<Vec<u8>>,
+}
+
+impl Read {
+    fn sector(&self, i: usize) -> &[u8] {
+        &self.data[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE]
+    }
+
+    /// Which stereo samples of sector `i` have a C2 error, None if the drive doesn't tell
+    fn c2_samples(&self, i: usize) -> Option<Vec<bool>> {
+        let c2 = &self.c2.as_ref()?[i * C2_SIZE..(i + 1) * C2_SIZE];
+        Some(
+            (0..SAMPLES_PER_SECTOR)
+                .map(|sample| {
+                    (sample * SAMPLE_SIZE..(sample + 1) * SAMPLE_SIZE).any(|byte| bit(c2, byte))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The C2 error pointers have a bit for every byte, the most significant first
+fn bit(c2: &[u8], byte: usize) -> bool {
+    c2[byte / 8] & (0x80 >> (byte % 8)) != 0
+}
+
+/// The C2 error pointers of `len` bytes starting from `start`, which might not be byte aligned
+fn c2_range(c2: &[u8], start: usize, len: usize) -> Vec<u8> {
+    let mut range = vec![0; len / 8];
+    for byte in (0..len).filter(|byte| bit(c2, start + byte)) {
+        range[byte / 8] |= 0x80 >> (byte % 8);
+    }
+    range
+}
+
+/// What is known about a sector being read
+#[derive(Clone, Default)]
+struct SectorReads {
+    /// Every different content read without C2 error pointers
+    seen: Vec<Vec<u8>>,
+    /// The last content read with C2 errors, along with the samples they are in
+    flagged: Option<(Vec<u8>, Vec<bool>)>,
+    /// The content we trust
+    verified: Option<Vec<u8>>,
+}
+
+/// Error detection and correction on top of a disc source, in the spirit of cdparanoia.
+///
+/// Every read overlaps the sectors returned by the previous one, to find where the drive has
+/// actually started reading. Sectors with read errors or C2 errors, or that two reads don't agree
+/// on when verifying, are read again until the retries are exhausted; what is left is concealed by
+/// interpolating the samples around.
+pub struct Paranoia {
+    inner: Box<dyn DiscSource>,
+    config: ParanoiaConfig,
+    lead_out: i32,
+    /// The LSN following the last sectors returned, and the sectors the next read is aligned to
+    tail: Option<(i32, Vec<u8>)>,
+}
+
+impl Paranoia {
+    pub fn new(inner: Box<dyn DiscSource>, config: ParanoiaConfig) -> Result<Self> {
+        let lead_out = inner.track_lsn(0xAA)?;
+        Ok(Self {
+            inner,
+            config,
+            lead_out,
+            tail: None,
+        })
+    }
+
+    /// Read `sectors` from `lsn` as the drive returns them
+    fn read_raw(&mut self, lsn: i32, sectors: usize) -> Result<Read> {
+        let mut data = vec![0; sectors * SECTOR_SIZE];
+        if !self.config.c2 {
+            self.inner
+                .read_audio_sectors(&mut data, lsn, sectors as u32)?;
+            return Ok(Read { data, c2: None });
+        }
+        let mut c2 = vec![0; sectors * C2_SIZE];
+        let has_c2 = self
+            .inner
+            .read_audio_sectors_c2(&mut data, &mut c2, lsn, sectors as u32)?;
+        Ok(Read {
+            data,
+            c2: has_c2.then_some(c2),
+        })
+    }
+
+    /// Read `sectors` from `lsn` along with the sectors known to precede them, to find where the
+    /// drive has really started reading: most of them are off by a few samples every time
+    fn read_aligned(&mut self, lsn: i32, sectors: usize, tail: Option<Vec<u8>>) -> Result<Read> {
+        let Some(tail) = tail else {
+            return self.read_raw(lsn, sectors);
+        };
+        let overlap = tail.len() / SECTOR_SIZE;
+        // A sector more on both sides, as far as the disc goes, to look around for the overlap
+        let before = usize::from(lsn > overlap as i32);
+        let mut after = usize::from(lsn + (sectors as i32) < self.lead_out);
+        let start = lsn - (before + overlap) as i32;
+        let read = loop {
+            match self.read_raw(start, before + overlap + sectors + after) {
+                Ok(read) => break read,
+                // The sector after might be an unreadable one
+                Err(_) if after > 0 => after = 0,
+                Err(err) => return Err(err),
+            }
+        };
+
+        let expected = (before * SECTOR_SIZE) as isize;
+        let max_shift = (SAMPLES_PER_SECTOR * before.max(after)) as isize;
+        let found = (0..=max_shift)
+            .flat_map(|shift| [shift, -shift])
+            .map(|shift| expected + shift * SAMPLE_SIZE as isize)
+            .filter(|&at| {
+                at >= 0 && at as usize + tail.len() + sectors * SECTOR_SIZE <= read.data.len()
+            })
+            .find(|&at| read.data[at as usize..at as usize + tail.len()] == tail[..]);
+        let Some(at) = found else {
+            bail!("lost the sync");
+        };
+        if at != expected {
+            info!(
+                "corrected a jitter of {} samples at sector {lsn}",
+                (at - expected) / SAMPLE_SIZE as isize
+            );
+        }
+
+        let start = at as usize + tail.len();
+        let len = sectors * SECTOR_SIZE;
+        Ok(Read {
+            data: read.data[start..start + len].to_vec(),
+            c2: read.c2.map(|c2| c2_range(&c2, start, len)),
+        })
+    }
+
+    /// Read far away from `lsn`, so that the drive reads the disc again instead of its cache
+    fn flush_cache(&mut self, lsn: i32) {
+        let far = if lsn < self.lead_out / 2 {
+            self.lead_out - 1
+        } else {
+            0
+        };
+        let mut sector = [0; SECTOR_SIZE];
+        let _ = self.inner.read_audio_sectors(&mut sector, far, 1);
+    }
+
+    /// Take in what has been read for a sector
+    fn check(&self, sector: &mut SectorReads, data: &[u8], c2: Option<Vec<bool>>) {
+        match c2 {
+            Some(samples) if samples.contains(&true) => {
+                sector.flagged = Some((data.to_vec(), samples));
+            }
+            // The drive vouches for it
+            Some(_) => sector.verified = Some(data.to_vec()),
+            None if !self.config.verify => sector.verified = Some(data.to_vec()),
+            // Trust what has been read twice
+            None if sector.seen.iter().any(|seen| seen == data) => {
+                sector.verified = Some(data.to_vec());
+            }
+            None => sector.seen.push(data.to_vec()),
+        }
+    }
+}
+
+impl DiscSource for Paranoia {
+    fn first_track_num(&self) -> Result<u8> {
+        self.inner.first_track_num()
+    }
+
+    fn last_track_num(&self) -> Result<u8> {
+        self.inner.last_track_num()
+    }
+
+    fn num_tracks(&self) -> Result<u8> {
+        self.inner.num_tracks()
+    }
+
+    fn track_lsn(&self, track: u8) -> Result<i32> {
+        self.inner.track_lsn(track)
+    }
+
+    fn track_last_lsn(&self, track: u8) -> Result<i32> {
+        self.inner.track_last_lsn(track)
+    }
+
+    fn track_is_audio(&self, track: u8) -> Result<bool> {
+        self.inner.track_is_audio(track)
+    }
+
+    fn last_session_lsn(&self) -> Result<i32> {
+        self.inner.last_session_lsn()
+    }
+
+    fn track_pregap_lsn(&self, track: u8) -> Result<Option<i32>> {
+        self.inner.track_pregap_lsn(track)
+    }
+
+    fn track_index(&mut self, lsn: i32) -> Result<Option<(u8, u8)>> {
+        self.inner.track_index(lsn)
+    }
+
+    fn track_preemphasis(&self, track: u8) -> Result<bool> {
+        self.inner.track_preemphasis(track)
+    }
+
+    fn mcn(&self) -> Result<Option<String>> {
+        self.inner.mcn()
+    }
+
+    fn track_isrc(&self, track: u8) -> Result<Option<String>> {
+        self.inner.track_isrc(track)
+    }
+
+    /// Never fails because of the disc: what can't be read is concealed
+    fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()> {
+        let count = sectors as usize;
+        let mut reads = vec![SectorReads::default(); count];
+        let mut attempts = 0;
+        // After a read error the sectors are read one at a time, to find the damaged ones
+        let mut one_by_one = false;
+        let mut read_error = false;
+        loop {
+            let pending = (0..count)
+                .filter(|&i| reads[i].verified.is_none())
+                .collect::<Vec<_>>();
+            let (Some(&first), Some(&last)) = (pending.first(), pending.last()) else {
+                break;
+            };
+            if attempts > self.config.retries {
+                break;
+            }
+            // Retry with the disc itself rather than the cache of the drive, once something has
+            // been read wrong
+            let misread = pending
+                .iter()
+                .any(|&i| reads[i].flagged.is_some() || reads[i].seen.len() > 1);
+            if read_error || misread {
+                self.flush_cache(lsn + first as i32);
+            }
+            read_error = false;
+            attempts += 1;
+            // Drives that keep losing the sync are read as they are the last time
+            let align = attempts <= self.config.retries;
+
+            let ranges = if one_by_one {
+                pending.iter().map(|&i| (i, 1)).collect()
+            } else {
+                vec![(first, last - first + 1)]
+            };
+            for (from, len) in ranges {
+                // What comes before, to align the read to
+                let tail = match from {
+                    _ if !align => None,
+                    0 => self
+                        .tail
+                        .as_ref()
+                        .filter(|(next, _)| *next == lsn)
+                        .map(|(_, tail)| tail.clone()),
+                    _ => reads[from - 1].verified.clone(),
+                };
+                let read = self.read_aligned(lsn + from as i32, len, tail);
+                match read {
+                    Ok(read) => {
+                        for i in 0..len {
+                            self.check(&mut reads[from + i], read.sector(i), read.c2_samples(i));
+                        }
+                    }
+                    Err(err) => {
+                        info!("sector {}: {err}", lsn + from as i32);
+                        one_by_one = true;
+                        read_error = true;
+                    }
+                }
+            }
+        }
+        if attempts > 1 {
+            info!(
+                "sectors {lsn} to {}: read {attempts} times",
+                lsn + sectors as i32 - 1
+            );
+        }
+
+        let pcm = &mut buf[..count * SECTOR_SIZE];
+        let mut bad = vec![false; count * SAMPLES_PER_SECTOR];
+        for (i, sector) in reads.into_iter().enumerate() {
+            let data = &mut pcm[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE];
+            let flags = &mut bad[i * SAMPLES_PER_SECTOR..(i + 1) * SAMPLES_PER_SECTOR];
+            match sector {
+                SectorReads {
+                    verified: Some(verified),
+                    ..
+                } => data.copy_from_slice(&verified),
+                // Only the samples the drive knows to be wrong have to be concealed
+                SectorReads {
+                    flagged: Some((flagged, samples)),
+                    ..
+                } => {
+                    data.copy_from_slice(&flagged);
+                    flags.copy_from_slice(&samples);
+                    warn!(
+                        "sector {}: concealed {} samples with C2 errors",
+                        lsn + i as i32,
+                        samples.iter().filter(|&&bad| bad).count()
+                    );
+                }
+                _ => {
+                    flags.fill(true);
+                    warn!("sector {}: unable to read it, concealed", lsn + i as i32);
+                }
+            }
+        }
+        if bad.contains(&true) {
+            let before = match &self.tail {
+                Some((next, tail)) if *next == lsn => {
+                    Some(sample_at(tail, tail.len() / SAMPLE_SIZE - 1))
+                }
+                _ => None,
+            };
+            conceal(pcm, &bad, before);
+        }
+
+        // The next read is aligned to the last sectors, unless they had to be concealed
+        let overlap = (self.config.overlap as usize).min(count);
+        let tail = count - overlap;
+        self.tail = (overlap > 0 && !bad[tail * SAMPLES_PER_SECTOR..].contains(&true))
+            .then(|| (lsn + sectors as i32, pcm[tail * SECTOR_SIZE..].to_vec()));
+
+        Ok(())
+    }
+
+    fn eject(&mut self) -> Result<()> {
+        self.tail = None;
+        self.inner.eject()
+    }
+
+    fn media_changed(&mut self) -> Result<bool> {
+        self.inner.media_changed()
+    }
+
+    fn cdtext(&self) -> Result<Vec<CdText>> {
+        self.inner.cdtext()
+    }
+}
+
+/// The stereo sample `i` of `pcm`
+fn sample_at(pcm: &[u8], i: usize) -> [i16; 2] {
+    let at = i * SAMPLE_SIZE;
+    [
+        i16::from_le_bytes([pcm[at], pcm[at + 1]]),
+        i16::from_le_bytes([pcm[at + 2], pcm[at + 3]]),
+    ]
+}
+
+/// Replace the `bad` samples of `pcm` by interpolating linearly between the good ones around.
+/// `before` is the sample preceding `pcm`, if known.
+fn conceal(pcm: &mut [u8], bad: &[bool], before: Option<[i16; 2]>) {
+    let mut i = 0;
+    while i < bad.len() {
+        if !bad[i] {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < bad.len() && bad[i] {
+            i += 1;
+        }
+
+        // Samples from start to i are bad
+        let from = if start > 0 {
+            Some(sample_at(pcm, start - 1))
+        } else {
+            before
+        };
+        let to = (i < bad.len()).then(|| sample_at(pcm, i));
+        let (from, to) = match (from, to) {
+            (Some(from), Some(to)) => (from, to),
+            (Some(edge), None) | (None, Some(edge)) => (edge, edge),
+            (None, None) => ([0; 2], [0; 2]),
+        };
+        let steps = (i - start + 1) as f32;
+        for (step, sample) in (start..i).enumerate() {
+            let position = (step + 1) as f32 / steps;
+            for channel in 0..2 {
+                let (from, to) = (f32::from(from[channel]), f32::from(to[channel]));
+                let value = (from + (to - from) * position).round() as i16;
+                let at = sample * SAMPLE_SIZE + channel * 2;
+                pcm[at..at + 2].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::fake_disc::FakeDisc;
+
+    /// Read the whole of `spec`, a second of counter, through the paranoia layer in reads of
+    /// `sectors`, returning the position on the disc every stereo sample holds
+    fn read(spec: &str, config: ParanoiaConfig, sectors: u32) -> Vec<u32> {
+        let disc: FakeDisc = spec.parse().unwrap();
+        let mut paranoia = Paranoia::new(Box::new(disc), config).unwrap();
+        let mut buf = vec![0; sectors as usize * SECTOR_SIZE];
+        let mut pcm = Vec::new();
+        for lsn in (0..75).step_by(sectors as usize) {
+            let sectors = sectors.min(75 - lsn);
+            let buf = &mut buf[..sectors as usize * SECTOR_SIZE];
+            paranoia
+                .read_audio_sectors(buf, lsn as i32, sectors)
+                .unwrap();
+            pcm.extend_from_slice(buf);
+        }
+        pcm.chunks_exact(SAMPLE_SIZE)
+            .map(|sample| u32::from_le_bytes(sample.try_into().unwrap()))
+            .collect()
+    }
+
+    fn counter() -> Vec<u32> {
+        (0..75 * SAMPLES_PER_SECTOR as u32).collect()
+    }
+
+    #[test]
+    fn jitter_is_corrected() {
+        assert_eq!(
+            read("counter:1;jitter=100", ParanoiaConfig::default(), 7),
+            counter()
+        );
+        // As far as a whole sector either way
+        let jitter = format!("counter:1;jitter={SAMPLES_PER_SECTOR}");
+        assert_eq!(read(&jitter, ParanoiaConfig::default(), 7), counter());
+    }
+
+    #[test]
+    fn jitter_is_not_checked_without_overlap() {
+        let config = ParanoiaConfig {
+            overlap: 0,
+            ..Default::default()
+        };
+        assert_ne!(read("counter:1;jitter=100", config, 7), counter());
+    }
+
+    #[test]
+    fn damaged_sectors_are_read_again() {
+        // Flagged by the C2 error pointers
+        assert_eq!(
+            read("counter:1;damaged=20-22", ParanoiaConfig::default(), 7),
+            counter()
+        );
+        // Read until two reads agree
+        let config = ParanoiaConfig {
+            verify: true,
+            c2: false,
+            ..Default::default()
+        };
+        assert_eq!(read("counter:1;damaged=20-22", config, 7), counter());
+    }
+
+    #[test]
+    fn unreadable_sectors_are_concealed() {
+        // Sector 20 ends a read, sectors 21 and 22 start the next one
+        let played = read("counter:1;error=20-22", ParanoiaConfig::default(), 7);
+        let expected = counter();
+        let concealed = 20 * SAMPLES_PER_SECTOR..23 * SAMPLES_PER_SECTOR;
+        assert_eq!(played[..concealed.start], expected[..concealed.start]);
+        assert_eq!(played[concealed.end..], expected[concealed.end..]);
+        // Nothing is known past the end of a read, the samples are held from the other side
+        let (before, after) = (concealed.start as u32 - 1, concealed.end as u32);
+        let held = [
+            vec![before; SAMPLES_PER_SECTOR],
+            vec![after; 2 * SAMPLES_PER_SECTOR],
+        ];
+        assert_eq!(played[concealed], held.concat());
+    }
+
+    #[test]
+    fn damaged_sectors_are_concealed_once_out_of_retries() {
+        let config = ParanoiaConfig {
+            retries: 1,
+            ..Default::default()
+        };
+        let played = read("counter:1;damaged=20", config, 7);
+        let expected = counter();
+        let sector = 20 * SAMPLES_PER_SECTOR..21 * SAMPLES_PER_SECTOR;
+        assert_eq!(played[..sector.start], expected[..sector.start]);
+        assert_eq!(played[sector.end..], expected[sector.end..]);
+        // Only the samples flagged are concealed, interpolated between the ones read right
+        assert_eq!(played[sector.clone()], expected[sector]);
+    }
+
+    /// A fake disc keeping track of where every read starts
+    struct Logged(FakeDisc, Arc<Mutex<Vec<i32>>>);
+
+    impl DiscSource for Logged {
+        fn first_track_num(&self) -> Result<u8> {
+            self.0.first_track_num()
+        }
+
+        fn last_track_num(&self) -> Result<u8> {
+            self.0.last_track_num()
+        }
+
+        fn num_tracks(&self) -> Result<u8> {
+            self.0.num_tracks()
+        }
+
+        fn track_lsn(&self, track: u8) -> Result<i32> {
+            self.0.track_lsn(track)
+        }
+
+        fn track_last_lsn(&self, track: u8) -> Result<i32> {
+            self.0.track_last_lsn(track)
+        }
+
+        fn track_is_audio(&self, track: u8) -> Result<bool> {
+            self.0.track_is_audio(track)
+        }
+
+        fn last_session_lsn(&self) -> Result<i32> {
+            self.0.last_session_lsn()
+        }
+
+        fn read_audio_sectors(&mut self, buf: &mut [u8], lsn: i32, sectors: u32) -> Result<()> {
+            self.1.lock().unwrap().push(lsn);
+            self.0.read_audio_sectors(buf, lsn, sectors)
+        }
+
+        fn eject(&mut self) -> Result<()> {
+            self.0.eject()
+        }
+
+        fn media_changed(&mut self) -> Result<bool> {
+            self.0.media_changed()
+        }
+
+        fn cdtext(&self) -> Result<Vec<CdText>> {
+            self.0.cdtext()
+        }
+    }
+
+    #[test]
+    fn cache_is_flushed_only_to_retry() {
+        let config = ParanoiaConfig {
+            verify: true,
+            c2: false,
+            ..Default::default()
+        };
+        let reads = |spec: &str| {
+            let reads = Arc::new(Mutex::new(Vec::new()));
+            let disc = Logged(spec.parse().unwrap(), reads.clone());
+            let mut paranoia = Paranoia::new(Box::new(disc), config.clone()).unwrap();
+            let mut buf = vec![0; 7 * SECTOR_SIZE];
+            paranoia.read_audio_sectors(&mut buf, 0, 7).unwrap();
+            drop(paranoia);
+            Arc::into_inner(reads).unwrap().into_inner().unwrap()
+        };
+        // Read twice to verify it, without going anywhere else
+        assert_eq!(reads("counter:1"), [0, 0]);
+        // The last sector of the disc is as far as it gets
+        assert!(reads("counter:1;damaged=3").contains(&74));
+    }
+}
@@ -1,131 +1,514 @@
 use std::{
     fs::File,
-    path::PathBuf,
+    io::BufWriter,
+    path::Path,
     sync::{Arc, Mutex},
-    time::Duration,
 };
 
 use color_eyre::Result;
-use symphonia::core::{
-    audio::{Channels, SignalSpec},
-    codecs::{CodecParameters, Decoder, DecoderOptions, CODEC_TYPE_PCM_S16LE},
-    formats::{FormatOptions, FormatReader},
-    io::MediaSourceStream,
-};
-use symphonia_format_wav::WavReader;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, Channels, Signal, SignalSpec};
 
 use crate::{
     action::Action,
-    output::{self, AudioOutput},
-    state::PlayerState,
+    emphasis::Deemphasis,
+    output::{self, AudioOutput, PcmOutput},
+    ring::{Chunk, Consumer},
+    state::{PlayerState, Seek, FRAMES_PER_SECOND, FRAMES_PER_SECTOR},
 };
 
 pub struct Player {
-    format: WavReader,
-    decoder: Box<dyn Decoder>,
+    buffer: AudioBuffer<i16>,
     audio_output: Box<dyn AudioOutput>,
     state: Arc<Mutex<PlayerState>>,
-    file: File,
+    ring: Consumer,
+    last_seek: Option<u32>,
+    /// Whether we have found the marker of the last jump in the ring, everything before it is
+    /// stale and gets dropped
+    synced: bool,
+    /// Frames to drop from the next sector, when jumping in the middle of it
+    skip: usize,
+    /// The filter for the track being played, if it has pre-emphasis
+    deemphasis: Option<Deemphasis>,
+    /// The chunk popped while the state was changing, handled once the player has caught up
+    pending: Option<Chunk>,
 }
 
 impl Player {
-    pub fn new(state: Arc<Mutex<PlayerState>>) -> Result<Self> {
-        let mut codec_params = CodecParameters::new();
-        codec_params
-            .for_codec(CODEC_TYPE_PCM_S16LE)
-            .with_sample_rate(44100)
-            .with_time_base(symphonia::core::units::TimeBase {
-                numer: 1,
-                denom: 44100,
-            })
-            .with_bits_per_sample(16)
-            .with_channels(Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
-            .with_max_frames_per_packet(1152);
-        let decode_opts = DecoderOptions::default();
-        let decoder = symphonia::default::get_codecs().make(&codec_params, &decode_opts)?;
-
-        // This is a description of the audio buffer's sample format
-        // and sample rate.
-        let spec = SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
-
-        // Try to open the audio output.
-        let audio_output = output::try_open(spec, 1152).unwrap();
-
-        let (file, format) = Self::get_reader(1);
-
-        // song_is_ready.recv().unwrap();
+    pub fn new(
+        state: Arc<Mutex<PlayerState>>,
+        audio_output: Box<dyn AudioOutput>,
+        ring: Consumer,
+    ) -> Result<Self> {
+        let buffer = AudioBuffer::new(FRAMES_PER_SECTOR, Self::spec());
+
         Ok(Self {
-            format,
-            decoder,
+            buffer,
             audio_output,
             state,
-            file,
+            ring,
+            last_seek: None,
+            synced: false,
+            skip: 0,
+            deemphasis: None,
+            pending: None,
         })
     }
 
     pub fn handle(&mut self) -> Result<()> {
         loop {
-            let action = {
+            let (action, seek) = {
                 let lock = self.state.lock().unwrap();
-                lock.action.clone()
+                (lock.action, lock.seek)
             };
 
             match action {
-                Action::Play(track) => {
-                    (self.file, self.format) = Self::get_reader(track.into());
-                    // The song finished playing by itself
-                    if self.play() {
-                        let state = self.state.lock().unwrap();
-                        state.next_track();
+                Action::Play(_) => {
+                    if self.last_seek != Some(seek.id) {
+                        self.last_seek = Some(seek.id);
+                        self.synced = false;
                     }
+                    self.play(action, seek);
                 }
-                Action::Pause(_) => {
+                Action::Pause(_) | Action::Stop => {
                     self.state.lock().unwrap().wait_for_change();
                 }
-                Action::Stop => break,
+                Action::Quit => break,
             }
         }
 
         Ok(())
     }
 
-    pub fn play(&mut self) -> bool {
-        // Wait until there is enough data to read
-        while self.file.metadata().unwrap().len() < 1152 * 2 {
-            std::thread::sleep(Duration::from_millis(5));
+    /// Play whatever the reader pushes into the ring, until the state changes
+    pub fn play(&mut self, action: Action, seek: Seek) {
+        let state = self.state.clone();
+        let position = state.lock().unwrap().position.clone();
+        let interrupted = || {
+            let state = state.lock().unwrap();
+            state.action != action || state.seek.id != seek.id
+        };
+
+        while let Some(chunk) = self.pending.take().or_else(|| self.ring.pop(interrupted)) {
+            // Whatever has been read ahead must wait for a pause to end, or be dropped after a
+            // jump, instead of being played till the ring is empty
+            if interrupted() {
+                self.pending = Some(chunk);
+                break;
+            }
+            match chunk {
+                // Stale data read before the last jump
+                Chunk::Track { seek: id, .. } | Chunk::End { seek: id } if id != seek.id => {}
+                Chunk::Sector(_) if !self.synced => {}
+                Chunk::Track { track, .. } if !self.synced => {
+                    self.synced = true;
+                    self.skip = (seek.frame % FRAMES_PER_SECTOR) as usize;
+                    // The sector being played when jumping has moved the position past the jump
+                    *position.write().unwrap() = seek.frame;
+                    self.deemphasis = None;
+                    self.deemphasize(track);
+                }
+                Chunk::Track { track, .. } => {
+                    // The song finished playing by itself. Don't drain the audio output, the next
+                    // song must follow without any gap, exactly as on the disc.
+                    self.skip = 0;
+                    self.deemphasize(track);
+                    state.lock().unwrap().track_ended();
+                }
+                Chunk::End { .. } => {
+                    // The whole disc has been played
+                    // Flush the audio output to finish playing back any leftover samples.
+                    self.audio_output.flush();
+                    self.skip = 0;
+                    state.lock().unwrap().track_ended();
+                }
+                Chunk::Sector(mut sector) => {
+                    if let Some(deemphasis) = &mut self.deemphasis {
+                        deemphasis.process(&mut sector);
+                    }
+                    let volume = state.lock().unwrap().volume;
+                    let sample = |bytes: &[u8]| {
+                        let sample = i16::from_le_bytes([bytes[0], bytes[1]]);
+                        (f64::from(sample) * volume).round() as i16
+                    };
+                    let skip = std::mem::take(&mut self.skip);
+                    let frames = FRAMES_PER_SECTOR as usize - skip;
+                    self.buffer.clear();
+                    self.buffer.render_reserved(Some(frames));
+                    for (i, frame) in sector.chunks_exact(4).skip(skip).enumerate() {
+                        self.buffer.chan_mut(0)[i] = sample(&frame[..2]);
+                        self.buffer.chan_mut(1)[i] = sample(&frame[2..]);
+                    }
+                    self.audio_output
+                        .write(self.buffer.as_audio_buffer_ref())
+                        .unwrap();
+                    *position.write().unwrap() += frames as u64;
+                    state.lock().unwrap().update_index();
+                }
+            }
         }
-        let state_changed = self.state.lock().unwrap().state_changed.clone();
-        let song_finished = loop {
-            if *state_changed.read().unwrap() {
-                break false;
+    }
+
+    /// Set up the de-emphasis of `track`. The filter carries on from the track before, which the
+    /// track follows without a gap.
+    fn deemphasize(&mut self, track: u8) {
+        let preemphasis = self
+            .state
+            .lock()
+            .unwrap()
+            .toc
+            .track(track)
+            .is_some_and(|track| track.preemphasis);
+        self.deemphasis = preemphasis.then(|| self.deemphasis.take().unwrap_or_default());
+    }
+
+    /// Open the sound card, or the file at `pcm_output` to dump the raw samples into
+    pub fn open_output(pcm_output: Option<&Path>) -> Result<Box<dyn AudioOutput>> {
+        Ok(match pcm_output {
+            Some(path) => {
+                let writer = BufWriter::new(File::create(path)?);
+                Box::new(PcmOutput::new(Box::new(writer)))
             }
-            // Get the next packet from the format reader.
-            let packet = match self.format.next_packet() {
-                Ok(packet) => packet,
-                Err(_err) => break true,
-            };
+            None => {
+                // Try to open the audio output.
+                output::try_open(Self::spec(), 1152).unwrap()
+            }
+        })
+    }
+
+    /// This is a description of the audio buffer's sample format
+    /// and sample rate.
+    fn spec() -> SignalSpec {
+        SignalSpec::new(
+            FRAMES_PER_SECOND as u32,
+            Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        )
+    }
+}
 
-            // Decode the packet into audio samples.
-            let decoded = self.decoder.decode(&packet).unwrap();
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        ops::Range,
+        sync::Condvar,
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
 
-            self.audio_output.write(decoded).unwrap()
-        };
+    use super::*;
+    use crate::{
+        config::Config,
+        disc::DiscSource,
+        fake_disc::FakeDisc,
+        read_cd::Reader,
+        ring::{self, SECTOR_SIZE},
+        state::{Position, Request},
+    };
+
+    /// Three tracks of a second each, every stereo sample holding its position on the disc
+    const DISC: &str = "counter:1,counter:1,counter:1";
+    const SECTOR: u32 = FRAMES_PER_SECTOR as u32;
+    const TRACK: u32 = 75 * SECTOR;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Default)]
+    struct Written {
+        pcm: Vec<u8>,
+        /// How many bytes the player may write in total
+        allowed: usize,
+        /// The player is waiting to write more than allowed
+        blocked: bool,
+    }
 
-        // Flush the audio output to finish playing back any leftover samples.
-        self.audio_output.flush();
-        song_finished
+    /// Stands in for the sound card, taking only as many samples as it is allowed to. The
+    /// player is held writing the next sector meanwhile, just as it would be by a full buffer.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<(Mutex<Written>, Condvar)>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (written, changed) = &*self.0;
+            let mut written = written.lock().unwrap();
+            while written.pcm.len() >= written.allowed {
+                written.blocked = true;
+                changed.notify_all();
+                written = changed.wait(written).unwrap();
+            }
+            written.blocked = false;
+            let len = buf.len().min(written.allowed - written.pcm.len());
+            written.pcm.extend_from_slice(&buf[..len]);
+            changed.notify_all();
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
-    fn get_reader(id: usize) -> (File, WavReader) {
-        let filename = PathBuf::from(format!("/tmp/raspi-cd-player/track{id}"));
-        // wait for the file to be created
-        while !filename.exists() {
-            std::thread::sleep(Duration::from_millis(20));
+    impl Sink {
+        /// Let the player write `frames` more stereo samples
+        fn allow(&self, frames: u32) {
+            let (written, changed) = &*self.0;
+            written.lock().unwrap().allowed += frames as usize * 4;
+            changed.notify_all();
         }
-        let file = File::open(filename).unwrap();
-        let source = Box::new(file.try_clone().unwrap());
-        let mss = MediaSourceStream::new(source, Default::default());
-        let format_opts = FormatOptions::default();
-        (file, WavReader::try_new(mss, &format_opts).unwrap())
+
+        /// Wait for the player to have written all it is allowed to, and to be holding the next
+        /// sector
+        fn wait_blocked(&self) {
+            let (written, changed) = &*self.0;
+            let timeout = changed
+                .wait_timeout_while(written.lock().unwrap(), TIMEOUT, |written| {
+                    !written.blocked || written.pcm.len() < written.allowed
+                })
+                .unwrap()
+                .1;
+            assert!(!timeout.timed_out(), "the player has stopped writing");
+        }
+
+        /// The position on the disc of every stereo sample played so far
+        fn played(&self) -> Vec<u32> {
+            let (written, _) = &*self.0;
+            written
+                .lock()
+                .unwrap()
+                .pcm
+                .chunks_exact(4)
+                .map(|frame| u32::from_le_bytes(frame.try_into().unwrap()))
+                .collect()
+        }
+    }
+
+    /// The stereo samples of the disc in `frames`, as they are played
+    fn disc(frames: Range<u32>) -> Vec<u32> {
+        frames.collect()
+    }
+
+    fn wait_until(done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// The reader and the player running on their own threads, as in `main`
+    struct Playback {
+        state: Arc<Mutex<PlayerState>>,
+        sink: Sink,
+        reader: JoinHandle<Result<()>>,
+        player: JoinHandle<Result<()>>,
+    }
+
+    impl Playback {
+        /// Start playing `DISC` into a sink taking `frames` stereo samples
+        fn start(frames: u32) -> Self {
+            Self::start_disc(DISC, frames)
+        }
+
+        /// Start playing the fake disc described by `spec`
+        fn start_disc(spec: &str, frames: u32) -> Self {
+            let (tx, rx) = flume::bounded(2);
+            let state = Arc::new(Mutex::new(PlayerState::new(tx, rx)));
+            let mut config = Config::default();
+            // Nothing to look the disc up in
+            let nowhere = std::env::temp_dir().join("raspi-cd-player-test-nowhere");
+            config.metadata.database = nowhere.clone();
+            config.metadata.providers.clear();
+            config.accuraterip.database = nowhere;
+            config.drive.read_sectors = 4;
+
+            let disc: FakeDisc = spec.parse().unwrap();
+            let (producer, consumer) = ring::ring(16);
+            let sink = Sink::default();
+            sink.allow(frames);
+
+            // The source and the output can't be moved across threads, they are opened by the
+            // threads using them
+            let reader = {
+                let state = state.clone();
+                thread::spawn(move || {
+                    Reader::new(state, Box::new(disc), producer, Arc::new(config))?.handle()
+                })
+            };
+            let player = {
+                let state = state.clone();
+                let output = PcmOutput::new(Box::new(sink.clone()));
+                thread::spawn(move || Player::new(state, Box::new(output), consumer)?.handle())
+            };
+
+            Self {
+                state,
+                sink,
+                reader,
+                player,
+            }
+        }
+
+        fn request(&self, request: Request) {
+            self.state.lock().unwrap().handle_request(request);
+        }
+
+        fn action(&self) -> Action {
+            self.state.lock().unwrap().action
+        }
+
+        fn position(&self) -> u64 {
+            *self.state.lock().unwrap().position.read().unwrap()
+        }
+
+        /// Let the player write out the rest of the disc, and wait for it to stop
+        fn play_to_the_end(&self) {
+            self.sink.allow(u32::MAX);
+            wait_until(|| self.action() == Action::Stop);
+        }
+
+        fn quit(self) {
+            self.sink.allow(u32::MAX);
+            self.request(Request::Quit);
+            self.player.join().unwrap().unwrap();
+            self.reader.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn tracks_follow_each_other_without_gaps() {
+        let playback = Playback::start(0);
+        playback.play_to_the_end();
+        assert_eq!(playback.sink.played(), disc(0..3 * TRACK));
+        playback.quit();
+    }
+
+    #[test]
+    fn next_track() {
+        let playback = Playback::start(10 * SECTOR);
+        playback.sink.wait_blocked();
+        playback.request(Request::NextTrack);
+        assert_eq!(playback.action(), Action::Play(2));
+        playback.play_to_the_end();
+        // The sector being written is played whole, what was read ahead of it is dropped
+        assert_eq!(
+            playback.sink.played(),
+            [disc(0..11 * SECTOR), disc(TRACK..3 * TRACK)].concat()
+        );
+        playback.quit();
+    }
+
+    #[test]
+    fn previous_track() {
+        let playback = Playback::start(TRACK + 10 * SECTOR);
+        playback.sink.wait_blocked();
+        assert_eq!(playback.action(), Action::Play(2));
+        playback.request(Request::PreviousTrack);
+        assert_eq!(playback.action(), Action::Play(1));
+        playback.sink.allow(6 * SECTOR);
+        playback.sink.wait_blocked();
+        assert_eq!(playback.position(), 5 * FRAMES_PER_SECTOR);
+        playback.play_to_the_end();
+        assert_eq!(
+            playback.sink.played(),
+            [disc(0..TRACK + 11 * SECTOR), disc(0..3 * TRACK)].concat()
+        );
+        playback.quit();
+    }
+
+    #[test]
+    fn pause_and_play() {
+        let playback = Playback::start(10 * SECTOR);
+        playback.sink.wait_blocked();
+        playback.request(Request::Pause);
+        playback.sink.allow(u32::MAX);
+        wait_until(|| playback.position() == 11 * FRAMES_PER_SECTOR);
+        // Nothing more is played while paused, even if it has been read ahead
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(playback.sink.played(), disc(0..11 * SECTOR));
+        playback.request(Request::Play);
+        playback.play_to_the_end();
+        assert_eq!(playback.sink.played(), disc(0..3 * TRACK));
+        playback.quit();
+    }
+
+    #[test]
+    fn seek_within_a_sector() {
+        let playback = Playback::start(10 * SECTOR);
+        playback.sink.wait_blocked();
+        // Half a second is half a sector after a sector boundary
+        let seek = FRAMES_PER_SECOND as u32 / 2;
+        playback.request(Request::SetPosition(Position::Time(Duration::from_millis(
+            500,
+        ))));
+        // The sector being written, what is left of the sector seeked into, 5 more sectors
+        let resumed = seek + SECTOR / 2 + 5 * SECTOR;
+        playback.sink.allow(SECTOR + SECTOR / 2 + 5 * SECTOR);
+        playback.sink.wait_blocked();
+        assert_eq!(playback.position(), u64::from(resumed));
+        playback.request(Request::Seek(-500_000));
+        playback.play_to_the_end();
+        assert_eq!(
+            playback.sink.played(),
+            [
+                disc(0..11 * SECTOR),
+                disc(seek..resumed + SECTOR),
+                disc(resumed - seek..3 * TRACK),
+            ]
+            .concat()
+        );
+        playback.quit();
+    }
+
+    #[test]
+    fn only_preemphasized_tracks_are_filtered() {
+        let spec = "sine:1:pre,sine:1";
+        let playback = Playback::start_disc(spec, 0);
+        playback.play_to_the_end();
+
+        let mut disc: FakeDisc = spec.parse().unwrap();
+        let mut pcm = vec![0; 150 * SECTOR_SIZE];
+        disc.read_audio_sectors(&mut pcm, 0, 150).unwrap();
+        Deemphasis::default().process(&mut pcm[..75 * SECTOR_SIZE]);
+        let expected = pcm
+            .chunks_exact(4)
+            .map(|frame| u32::from_le_bytes(frame.try_into().unwrap()));
+        assert!(playback.sink.played().into_iter().eq(expected));
+        playback.quit();
+    }
+
+    #[test]
+    fn index_points_are_looked_for_when_reached() {
+        let playback = Playback::start_disc("counter:1,counter:2@1", 10 * SECTOR);
+        playback.sink.wait_blocked();
+        let indexes = || playback.state.lock().unwrap().toc.index_starts(2);
+        assert_eq!(indexes(), [0]);
+        playback.play_to_the_end();
+        assert_eq!(indexes(), [0, 75]);
+        playback.quit();
+    }
+
+    #[test]
+    fn eject() {
+        let playback = Playback::start(10 * SECTOR);
+        playback.sink.wait_blocked();
+        playback.request(Request::Eject);
+        assert_eq!(playback.action(), Action::Stop);
+        playback.sink.allow(u32::MAX);
+        // The reader opens the tray once stopped
+        wait_until(|| !playback.state.lock().unwrap().eject);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(playback.sink.played(), disc(0..11 * SECTOR));
+
+        // There is nothing left to read
+        playback.request(Request::Play);
+        wait_until(|| playback.reader.is_finished());
+        let Playback {
+            state,
+            reader,
+            player,
+            ..
+        } = playback;
+        let err = reader.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "error reading sector");
+        state.lock().unwrap().handle_request(Request::Quit);
+        player.join().unwrap().unwrap();
     }
 }
@@ -0,0 +1,477 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{bail, ContextCompat},
+    Result,
+};
+use log::{info, warn};
+use ureq::Agent;
+
+use crate::{
+    config::{MetadataConfig, ProviderKind},
+    database::{Database, Release},
+    disc_id::{DiscId, LEAD_IN, SECTORS_PER_SECOND},
+    import,
+    metadata::DiscMetadata,
+    state::PlayerState,
+    toc::Toc,
+};
+
+const USER_AGENT: &str = concat!(
+    "raspi-cd-player/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/danyspin97/raspi-cd-player )"
+);
+
+/// An online service metadata can be looked up from, by disc ID
+pub trait MetadataProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// None if the service doesn't know the disc
+    fn lookup(&self, disc_id: &DiscId) -> Result<Option<Release>>;
+}
+
+/// An HTTP client giving up on a service that doesn't answer within `timeout`
+fn agent(timeout: Duration) -> Agent {
+    ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .user_agent(USER_AGENT)
+        .build()
+}
+
+/// The MusicBrainz web service
+pub struct MusicBrainz {
+    agent: Agent,
+    base_url: String,
+}
+
+impl MusicBrainz {
+    pub fn new(base_url: &str, timeout: Duration) -> Self {
+        Self {
+            agent: agent(timeout),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl MetadataProvider for MusicBrainz {
+    fn name(&self) -> &'static str {
+        "MusicBrainz"
+    }
+
+    fn lookup(&self, disc_id: &DiscId) -> Result<Option<Release>> {
+        let url = format!(
+            "{}/ws/2/discid/{}?inc=recordings+artist-credits+discids&fmt=json",
+            self.base_url, disc_id.musicbrainz
+        );
+        let response = match self.agent.get(&url).call() {
+            Ok(response) => response.into_string()?,
+            // Unknown disc ID
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        // The releases might contain other discs as well
+        Ok(import::musicbrainz_lookup(&response)?
+            .into_iter()
+            .find(|(id, _)| *id == disc_id.musicbrainz)
+            .map(|(_, release)| release))
+    }
+}
+
+/// A server speaking the CDDB protocol over HTTP, such as gnudb
+pub struct Cddb {
+    agent: Agent,
+    base_url: String,
+}
+
+impl Cddb {
+    pub fn new(base_url: &str, timeout: Duration) -> Self {
+        Self {
+            agent: agent(timeout),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Run a CDDB command, returning the response lines
+    fn command(&self, command: &str) -> Result<Vec<String>> {
+        let hello = concat!(
+            "raspi-cd-player+localhost+raspi-cd-player+",
+            env!("CARGO_PKG_VERSION")
+        );
+        let url = format!(
+            "{}/~cddb/cddb.cgi?cmd={command}&hello={hello}&proto=6",
+            self.base_url
+        );
+        let response = self.agent.get(&url).call()?.into_string()?;
+        Ok(response
+            .lines()
+            .map(|line| line.trim_end_matches('\r').to_string())
+            .collect())
+    }
+}
+
+impl MetadataProvider for Cddb {
+    fn name(&self) -> &'static str {
+        "CDDB"
+    }
+
+    fn lookup(&self, disc_id: &DiscId) -> Result<Option<Release>> {
+        let offsets = disc_id
+            .track_lsns
+            .iter()
+            .map(|lsn| (lsn + LEAD_IN).to_string())
+            .collect::<Vec<_>>();
+        let query = format!(
+            "cddb+query+{}+{}+{}+{}",
+            disc_id.freedb_hex(),
+            offsets.len(),
+            offsets.join("+"),
+            (disc_id.lead_out + LEAD_IN) / SECTORS_PER_SECOND
+        );
+        let lines = self.command(&query)?;
+        let status = lines.first().context("empty CDDB response")?;
+        // "200 category discid title" for an exact match, otherwise a list of
+        // "category discid title" lines for multiple matches; the first one is the best
+        let found = match status.get(..3) {
+            Some("200") => status.get(4..).unwrap_or_default().to_string(),
+            Some("210" | "211") => lines.get(1).context("empty CDDB match list")?.clone(),
+            Some("202") => return Ok(None),
+            _ => bail!("CDDB query failed: {status}"),
+        };
+        let mut found = found.split_whitespace();
+        let (Some(category), Some(id)) = (found.next(), found.next()) else {
+            bail!("invalid CDDB match: {status}");
+        };
+
+        let lines = self.command(&format!("cddb+read+{category}+{id}"))?;
+        match lines.first().and_then(|status| status.get(..3)) {
+            Some("210") => {}
+            _ => bail!("CDDB read failed: {}", lines.join("\n")),
+        }
+        // The xmcd file ends with a line with a single dot
+        let xmcd = lines[1..]
+            .iter()
+            .take_while(|line| *line != ".")
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+        let (_, mut release) = import::xmcd(&xmcd)?
+            .into_iter()
+            .next()
+            .context("no disc in the CDDB entry")?;
+        release.genre = release.genre.or_else(|| Some(category.to_string()));
+        Ok(Some(release))
+    }
+}
+
+/// Where the metadata of an inserted disc is looked up: the offline database first, then the
+/// online providers in order
+#[derive(Clone)]
+pub struct MetadataLookup {
+    database: Database,
+    providers: Arc<Vec<Box<dyn MetadataProvider>>>,
+}
+
+impl MetadataLookup {
+    pub fn new(config: &MetadataConfig) -> Self {
+        let providers = config
+            .providers
+            .iter()
+            .map(|provider| -> Box<dyn MetadataProvider> {
+                match provider {
+                    ProviderKind::MusicBrainz => {
+                        Box::new(MusicBrainz::new(&config.musicbrainz_url, config.timeout()))
+                    }
+                    ProviderKind::Gnudb => Box::new(Cddb::new(&config.gnudb_url, config.timeout())),
+                }
+            })
+            .collect();
+        Self {
+            database: Database::new(config.database.clone()),
+            providers: Arc::new(providers),
+        }
+    }
+
+    /// Fill in `metadata` from the offline database. Returns false if the disc isn't there.
+    pub fn offline(&self, toc: &Toc, metadata: &mut DiscMetadata) -> bool {
+        match self.database.lookup(&toc.disc_id()) {
+            Ok(Some(release)) => {
+                metadata.apply(release, toc);
+                true
+            }
+            Ok(None) => false,
+            Err(err) => {
+                warn!("{err}");
+                false
+            }
+        }
+    }
+
+    /// Ask the online providers about the disc, in order, until one knows it. What is found is
+    /// stored in the offline database.
+    pub fn online(&self, disc_id: &DiscId) -> Option<Release> {
+        for provider in self.providers.iter() {
+            let release = match provider.lookup(disc_id) {
+                Ok(Some(release)) => release,
+                Ok(None) => {
+                    info!("{} doesn't know the disc", provider.name());
+                    continue;
+                }
+                Err(err) => {
+                    warn!("unable to look the disc up on {}: {err}", provider.name());
+                    continue;
+                }
+            };
+            info!("metadata found on {}", provider.name());
+            if let Err(err) = self.database.store(&disc_id.musicbrainz, &release) {
+                warn!("{err}");
+            }
+            return Some(release);
+        }
+        None
+    }
+
+    /// Ask the online providers about the disc just loaded in a thread of its own, so that the
+    /// playback never waits for the network
+    pub fn spawn_online(&self, state: Arc<Mutex<PlayerState>>, disc_id: DiscId) {
+        if self.providers.is_empty() {
+            return;
+        }
+        let generation = state.lock().unwrap().disc_generation;
+        let lookup = self.clone();
+        thread::spawn(move || {
+            if let Some(release) = lookup.online(&disc_id) {
+                state.lock().unwrap().update_metadata(generation, release);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::toc::TocTrack;
+
+    /// Short, for the services that never answer
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// A web server on localhost answering the requests in turn with `responses`, a status and a
+    /// body each. Returns its URL and the request lines it has received.
+    fn serve(responses: Vec<(u16, String)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                received
+                    .lock()
+                    .unwrap()
+                    .push(request.trim_end().to_string());
+                // The headers end with an empty line
+                for line in reader.lines() {
+                    if line.unwrap().is_empty() {
+                        break;
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n\
+                     {body}",
+                    body.len()
+                );
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn toc() -> Toc {
+        let track = |number, start, end| TocTrack {
+            number,
+            start,
+            end,
+            audio: true,
+            pregap: start,
+            indexes: Vec::new(),
+            preemphasis: false,
+        };
+        Toc::new(vec![track(1, 0, 18751), track(2, 18751, 30000)], 30000, 0).unwrap()
+    }
+
+    fn disc_id() -> DiscId {
+        toc().disc_id()
+    }
+
+    /// The answer of MusicBrainz for a release with two discs, the second one being `disc_id`
+    fn musicbrainz_release(disc_id: &DiscId) -> String {
+        serde_json::json!({
+            "releases": [{
+                "title": "Album",
+                "date": "1999-03-01",
+                "artist-credit": [{ "name": "Artist", "joinphrase": "" }],
+                "media": [
+                    {
+                        "discs": [{ "id": "another-disc" }],
+                        "tracks": [{ "title": "Elsewhere" }],
+                    },
+                    {
+                        "discs": [{ "id": disc_id.musicbrainz }],
+                        "tracks": [
+                            { "title": "One", "artist-credit": [{ "name": "Artist" }] },
+                            {
+                                "title": "Two",
+                                "artist-credit": [
+                                    { "name": "Artist", "joinphrase": " feat. " },
+                                    { "name": "Guest" },
+                                ],
+                            },
+                        ],
+                    },
+                ],
+            }],
+        })
+        .to_string()
+    }
+
+    /// The xmcd entry CDDB answers a read with
+    fn cddb_entry(disc_id: &DiscId, category: &str) -> String {
+        format!(
+            "210 {category} {id} CD database entry follows (until terminating `.')\r\n\
+             # xmcd\r\nDISCID={id}\r\nDTITLE=Artist / Album\r\nDYEAR=1999\r\n\
+             TTITLE0=One\r\nTTITLE1=Two\r\n.\r\n",
+            id = disc_id.freedb_hex()
+        )
+    }
+
+    fn titles(release: &Release) -> Vec<Option<&str>> {
+        release
+            .tracks
+            .iter()
+            .map(|track| track.title.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn musicbrainz() {
+        let disc_id = disc_id();
+        let (url, requests) = serve(vec![(200, musicbrainz_release(&disc_id))]);
+        let release = MusicBrainz::new(&url, TIMEOUT)
+            .lookup(&disc_id)
+            .unwrap()
+            .unwrap();
+        assert!(requests.lock().unwrap()[0]
+            .starts_with(&format!("GET /ws/2/discid/{}?", disc_id.musicbrainz)));
+        assert_eq!(release.album.as_deref(), Some("Album"));
+        assert_eq!(release.artist.as_deref(), Some("Artist"));
+        assert_eq!(release.year, Some(1999));
+        assert_eq!(titles(&release), [Some("One"), Some("Two")]);
+        let artists = release.tracks.iter().map(|track| track.artist.as_deref());
+        assert!(artists.eq([None, Some("Artist feat. Guest")]));
+    }
+
+    #[test]
+    fn musicbrainz_miss() {
+        let (url, _) = serve(vec![(404, r#"{"error": "Not Found"}"#.to_string())]);
+        assert!(MusicBrainz::new(&url, TIMEOUT)
+            .lookup(&disc_id())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn cddb_exact_match() {
+        let disc_id = disc_id();
+        let id = disc_id.freedb_hex();
+        let (url, requests) = serve(vec![
+            (200, format!("200 rock {id} Artist / Album\r\n")),
+            (200, cddb_entry(&disc_id, "rock")),
+        ]);
+        let release = Cddb::new(&url, TIMEOUT).lookup(&disc_id).unwrap().unwrap();
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].contains(&format!("cmd=cddb+query+{id}+2+150+18901+402&")));
+        assert!(requests[1].contains(&format!("cmd=cddb+read+rock+{id}&")));
+        assert_eq!(release.album.as_deref(), Some("Album"));
+        assert_eq!(release.artist.as_deref(), Some("Artist"));
+        assert_eq!(release.year, Some(1999));
+        // The category stands for the genre
+        assert_eq!(release.genre.as_deref(), Some("rock"));
+        assert_eq!(titles(&release), [Some("One"), Some("Two")]);
+    }
+
+    #[test]
+    fn cddb_multiple_matches() {
+        let disc_id = disc_id();
+        let id = disc_id.freedb_hex();
+        // Exact and inexact matches are listed the same way, the best one first
+        for status in ["210 Found exact matches", "211 Found inexact matches"] {
+            let (url, requests) = serve(vec![
+                (
+                    200,
+                    format!(
+                        "{status}, list follows (until terminating `.')\r\n\
+                         jazz {id} Artist / Album\r\nmisc 0123abcd Other / Album\r\n.\r\n"
+                    ),
+                ),
+                (200, cddb_entry(&disc_id, "jazz")),
+            ]);
+            let release = Cddb::new(&url, TIMEOUT).lookup(&disc_id).unwrap().unwrap();
+            assert!(requests.lock().unwrap()[1].contains(&format!("cmd=cddb+read+jazz+{id}&")));
+            assert_eq!(release.genre.as_deref(), Some("jazz"));
+        }
+    }
+
+    #[test]
+    fn cddb_miss() {
+        let (url, requests) = serve(vec![(200, "202 No match found\r\n".to_string())]);
+        assert!(Cddb::new(&url, TIMEOUT)
+            .lookup(&disc_id())
+            .unwrap()
+            .is_none());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn timeout() {
+        let disc_id = disc_id();
+        // Connections are accepted by the system, but nothing is ever answered
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("http://{}", silent.local_addr().unwrap());
+        assert!(MusicBrainz::new(&silent_url, TIMEOUT)
+            .lookup(&disc_id)
+            .is_err());
+
+        // The next provider is asked instead, and what it knows is stored
+        let id = disc_id.freedb_hex();
+        let (url, _) = serve(vec![
+            (200, format!("200 rock {id} Artist / Album\r\n")),
+            (200, cddb_entry(&disc_id, "rock")),
+        ]);
+        let database = std::env::temp_dir().join(format!("provider-{}", std::process::id()));
+        let lookup = MetadataLookup::new(&MetadataConfig {
+            database: database.clone(),
+            providers: vec![ProviderKind::MusicBrainz, ProviderKind::Gnudb],
+            musicbrainz_url: silent_url,
+            gnudb_url: url,
+            timeout: TIMEOUT.as_secs(),
+        });
+        let release = lookup.online(&disc_id);
+        let mut metadata = DiscMetadata::default();
+        let stored = lookup.offline(&toc(), &mut metadata);
+        fs::remove_dir_all(&database).unwrap();
+        assert_eq!(release.unwrap().album.as_deref(), Some("Album"));
+        assert!(stored);
+        assert_eq!(metadata.album.as_deref(), Some("Album"));
+    }
+}
@@ -1,294 +1,568 @@
 use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    mem::MaybeUninit,
-    path::PathBuf,
+    collections::HashSet,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use color_eyre::{
-    eyre::{bail, ContextCompat},
-    Result,
+use color_eyre::Result;
+use log::{info, warn};
+
+use crate::{
+    accuraterip::{self, AccurateRip, Checksums},
+    action::Action,
+    archive::Archive,
+    config::Config,
+    disc::DiscSource,
+    metadata::DiscMetadata,
+    provider::MetadataLookup,
+    ring::{Chunk, Producer, SECTOR_SIZE},
+    rip,
+    state::{PlayerState, FRAMES_PER_SECTOR},
+    toc::{Toc, TocTrack},
 };
-use libcdio_sys::*;
 
-use crate::{action::Action, state::PlayerState};
+/// How long the reader waits at a time for the encoder of the archive, before checking whether
+/// the player needs anything
+const HAND_OVER_WAIT: Duration = Duration::from_millis(100);
 
 pub struct Song {
-    filename: PathBuf,
-    file: File,
+    /// Sectors from the start of the track that have been read up to
     pub offset: i32,
     pub track_id: usize,
     pub start_lsn: i32,
     pub end_lsn: i32,
     pub ended: bool,
+    /// Every sector read so far, when the whole song is being kept
+    pub keep: Option<Vec<u8>>,
+    /// The checksums of the sectors read so far, when the song is being verified
+    pub checksums: Option<Checksums>,
+    /// How many stereo samples later than requested the audio is read from, to make up for the
+    /// read offset of the drive
+    pub read_offset: i32,
+    /// Where the disc ends; nothing can be read from there on, nor before LSN 0
+    pub lead_out: i32,
 }
 
 impl Song {
-    pub fn new(track_id: usize, (start_lsn, end_lsn): (i32, i32)) -> Result<Self> {
-        let filename = PathBuf::from(format!("/tmp/raspi-cd-player/track{}", track_id));
-        // TODO: Use create_now once it stabilizes
-        let file = File::create(&filename)?;
-        let mut song = Self {
-            filename,
-            file,
+    pub fn new(track_id: usize, (start_lsn, end_lsn): (i32, i32)) -> Self {
+        Self {
             offset: 0,
             track_id,
             start_lsn,
             end_lsn,
             ended: false,
-        };
+            keep: None,
+            checksums: None,
+            read_offset: 0,
+            lead_out: i32::MAX,
+        }
+    }
 
-        let bytes = CDIO_CD_FRAMESIZE_RAW * (end_lsn - start_lsn) as u32;
-        song.write_wav_header(bytes)?;
+    /// Correct the read offset of the drive, `read_offset` stereo samples, on a disc ending at
+    /// `lead_out`. What is shifted in from outside of the disc is silence.
+    pub fn correct_offset(&mut self, read_offset: i32, lead_out: i32) {
+        self.read_offset = read_offset;
+        self.lead_out = lead_out;
+    }
 
-        Ok(song)
+    /// Compute the checksums of the song while reading it, to verify it once read whole. The
+    /// song is the audio track at `position` out of `audio_tracks`.
+    pub fn verify(&mut self, position: u8, audio_tracks: u8) {
+        self.checksums = Some(Checksums::new(
+            position,
+            audio_tracks,
+            (self.end_lsn - self.start_lsn) as u32,
+        ));
     }
 
-    pub fn read(&mut self, cdio: *mut _CdIo, state: Arc<Mutex<PlayerState>>) -> Result<()> {
-        const SEC: u32 = 52;
-
-        let mut curr = self.start_lsn + self.offset;
-        let mut writer = BufWriter::new(&self.file);
-        let state_changed = state.lock().unwrap().state_changed.clone();
-        while curr < self.end_lsn && !*state_changed.read().unwrap() {
-            let mut buf = [0; (CDIO_CD_FRAMESIZE_RAW * SEC) as usize];
-            unsafe {
-                if cdio_read_audio_sectors(
-                    cdio,
-                    buf.as_mut_ptr() as *mut std::ffi::c_void,
-                    curr,
-                    SEC,
-                ) != driver_return_code_t_DRIVER_OP_SUCCESS
-                {
-                    bail!("error reading sector");
-                }
-            }
-            curr += (self.end_lsn - curr) % SEC as i32 + 1;
-            writer.write(&buf).unwrap();
+    /// `pcm` has been read from `offset` on, move past it
+    pub fn advance(&mut self, pcm: &[u8]) {
+        if let Some(keep) = &mut self.keep {
+            keep.extend_from_slice(pcm);
+        }
+        if let Some(checksums) = &mut self.checksums {
+            checksums.update(pcm);
+        }
+        self.offset += (pcm.len() / SECTOR_SIZE) as i32;
+    }
+
+    /// Read the sectors from `offset` on into `buf`, as many as fit, without moving `offset`.
+    /// Returns how many sectors have been read, 0 at the end of the song.
+    pub fn read_chunk(&self, source: &mut dyn DiscSource, buf: &mut [u8]) -> Result<usize> {
+        let curr = self.start_lsn + self.offset;
+        // The samples wanted straddle the sectors on the disc, read the one after as well
+        let shift = self.read_offset * 4;
+        let skip = shift.rem_euclid(SECTOR_SIZE as i32) as usize;
+        let straddling = (skip != 0) as i32;
+        let sectors = ((buf.len() / SECTOR_SIZE) as i32 - straddling).min(self.end_lsn - curr);
+        if sectors <= 0 {
+            return Ok(0);
         }
 
-        if curr >= self.end_lsn {
-            // The song has completely read
-            self.ended = true;
+        let first = curr + shift.div_euclid(SECTOR_SIZE as i32);
+        let end = first + sectors + straddling;
+        let (readable_start, readable_end) = (first.max(0), end.min(self.lead_out));
+        let buf = &mut buf[..(end - first) as usize * SECTOR_SIZE];
+        if readable_start >= readable_end {
+            buf.fill(0);
         } else {
-            // The reading has been interrupted
-            self.offset = curr;
+            let readable = (readable_end - readable_start) as usize;
+            let (before, rest) = buf.split_at_mut((readable_start - first) as usize * SECTOR_SIZE);
+            let (read, after) = rest.split_at_mut(readable * SECTOR_SIZE);
+            before.fill(0);
+            after.fill(0);
+            source.read_audio_sectors(read, readable_start, readable as u32)?;
         }
+        buf.copy_within(skip..skip + sectors as usize * SECTOR_SIZE, 0);
+        Ok(sectors as usize)
+    }
 
-        writer.flush().unwrap();
+    /// Read the song from `offset` and push its sectors into the ring, until it ends or
+    /// `interrupted` returns true. As many sectors as fit in `buf` are read at once.
+    /// With `until_full` it stops as soon as the ring is full, instead of waiting for the player.
+    pub fn read(
+        &mut self,
+        source: &mut dyn DiscSource,
+        buf: &mut [u8],
+        ring: &mut Producer,
+        until_full: bool,
+        interrupted: impl Fn() -> bool,
+    ) -> Result<()> {
+        while !interrupted() {
+            if until_full && ring.slots() < buf.len() / SECTOR_SIZE {
+                return Ok(());
+            }
+            let sectors = self.read_chunk(source, buf)?;
+            if sectors == 0 {
+                // The song has completely read
+                self.ended = true;
+                break;
+            }
+            for sector in buf.chunks_exact(SECTOR_SIZE).take(sectors) {
+                if !ring.push(Chunk::Sector(sector.try_into().unwrap()), &interrupted) {
+                    // The reading has been interrupted, keep track of where we are
+                    return Ok(());
+                }
+                self.advance(sector);
+            }
+        }
 
         Ok(())
     }
 
-    fn write_wav_header(&mut self, bytes: u32) -> Result<()> {
-        const BITDEPTH: u16 = 16;
-        const SAMPLERATE: u32 = 44100;
-        const CHANNELS: u16 = 2;
-        const BLOCKALIGN: u16 = 4;
-        const BYTERATE: u32 = SAMPLERATE * BITDEPTH as u32 / 8;
-        const FORMAT: u16 = 1; // WAVE_FORMAT_PCM
-        const CHUNKSIZE: u32 = 16;
-
-        self.file.write_all("RIFF".as_bytes())?;
-        // This is the file size
-        // 44 is the header size
-        self.file.write_all(&(bytes + 44 - 8).to_le_bytes())?;
-        self.file.write_all("WAVE".as_bytes())?;
-
-        //  Format
-        self.file.write_all("fmt ".as_bytes())?;
-        self.file.write_all(&CHUNKSIZE.to_le_bytes())?;
-        self.file.write_all(&FORMAT.to_le_bytes())?;
-        self.file.write_all(&CHANNELS.to_le_bytes())?;
-        self.file.write_all(&SAMPLERATE.to_le_bytes())?;
-        self.file.write_all(&BYTERATE.to_le_bytes())?;
-        self.file.write_all(&BLOCKALIGN.to_le_bytes())?;
-        self.file.write_all(&BITDEPTH.to_le_bytes())?;
-
-        // Data
-        self.file.write_all("data".as_bytes())?;
-        self.file.write_all(&bytes.to_le_bytes())?;
-
-        self.file.flush()?;
+    /// Read the song from `offset` only to keep it, until it ends or `interrupted` returns true
+    pub fn read_kept(
+        &mut self,
+        source: &mut dyn DiscSource,
+        buf: &mut [u8],
+        interrupted: impl Fn() -> bool,
+    ) -> Result<()> {
+        while !interrupted() {
+            let sectors = self.read_chunk(source, buf)?;
+            if sectors == 0 {
+                self.ended = true;
+                break;
+            }
+            self.keep.get_or_insert_with(Vec::new);
+            self.advance(&buf[..sectors * SECTOR_SIZE]);
+        }
 
         Ok(())
     }
-}
 
-impl Drop for Song {
-    fn drop(&mut self) {
-        std::fs::remove_file(&self.filename).unwrap();
+    /// Move the reading position to `sector` from the start of the track
+    pub fn seek(&mut self, sector: i32) {
+        self.offset = sector.clamp(0, self.end_lsn - self.start_lsn);
+        self.ended = false;
+        if self.offset != 0 {
+            // The start of the song would be missing
+            self.keep = None;
+            self.checksums = None;
+        }
     }
 }
 
 pub struct Reader {
-    cdio: *mut _CdIo,
-    song_sectors: Vec<(i32, i32)>,
-    tracks: u8,
+    source: Box<dyn DiscSource>,
+    toc: Toc,
     state: Arc<Mutex<PlayerState>>,
-    songs: Vec<Song>,
+    ring: Producer,
+    config: Arc<Config>,
+    /// Where the sectors are read into before being pushed into the ring
+    buf: Vec<u8>,
+    /// The song being read, None once the whole disc has been read
+    song: Option<Song>,
+    /// Where the tracks are archived when ripping in the background
+    archive: Option<Archive>,
+    /// What AccurateRip knows about the disc, if anything
+    accuraterip: Option<AccurateRip>,
+    /// The track being read for the archive only, while the player doesn't need anything
+    filling: Option<Song>,
+    last_seek: Option<u32>,
+    /// The tracks whose index points have been looked for
+    indexed: HashSet<u8>,
 }
 
 impl Reader {
-    pub fn new(state: Arc<Mutex<PlayerState>>) -> Result<Self> {
-        let driver_id = Box::new(driver_id_t_DRIVER_LINUX);
-        let drive = Reader::get_drive().context("Can't find a CD-ROM drive with a CD-DA in it")?;
-        let cdio = unsafe { cdio_open(drive, *driver_id) };
-        unsafe {
-            cdio_set_speed(cdio, 1);
+    pub fn new(
+        state: Arc<Mutex<PlayerState>>,
+        mut source: Box<dyn DiscSource>,
+        ring: Producer,
+        config: Arc<Config>,
+    ) -> Result<Self> {
+        let mut toc = Toc::read(source.as_mut())?;
+        if !config.playback.hidden_track {
+            toc.hidden_track = None;
         }
-
-        let first_track = unsafe { cdio_get_first_track_num(cdio) };
-        let last_track = unsafe { cdio_get_last_track_num(cdio) };
-        let tracks = unsafe { cdio_get_num_tracks(cdio) };
-
-        if first_track == 0xFF || last_track == 0xFF {
-            bail!("invalid CD");
+        toc.skip_pregaps = !config.playback.pregaps;
+        if let Some(hidden_track) = &toc.hidden_track {
+            info!(
+                "{} sectors hidden before the first track, playing them as track 0",
+                hidden_track.end - hidden_track.start
+            );
         }
-        let mut toc: Box<[MaybeUninit<msf_t>]> = Box::new_uninit_slice(0xAA + 1);
-
-        for current_track in first_track..=last_track {
-            if unsafe {
-                cdio_get_track_msf(
-                    cdio,
-                    current_track,
-                    toc.get_mut(current_track as usize).unwrap().as_mut_ptr(),
-                )
-            } == 0
-            {
-                bail!("error reading cd");
-            }
-
-            // if unsafe { cdio_get_track_format(cdio, current_track) }
-            //     == track_format_t_TRACK_FORMAT_AUDIO
-            // {
-            //     if current_track != first_track {
-            //         let s = unsafe {
-            //             cdio_audio_get_msf_seconds(
-            //                 toc.get_mut(current_track as usize).unwrap().as_mut_ptr(),
-            //             ) - cdio_audio_get_msf_seconds(
-            //                 toc.get_mut((current_track - 1) as usize)
-            //                     .unwrap()
-            //                     .as_mut_ptr(),
-            //             )
-            //         };
-            //     }
-            // }
+        let emphasized = toc
+            .tracks
+            .iter()
+            .filter(|track| track.preemphasis)
+            .map(|track| track.number.to_string())
+            .collect::<Vec<_>>();
+        if !emphasized.is_empty() {
+            info!(
+                "tracks {} have pre-emphasis, de-emphasizing them",
+                emphasized.join(", ")
+            );
         }
 
-        let toc = unsafe { toc.assume_init() };
-
-        let song_sectors = (first_track..last_track - 1)
-            .map(|i| unsafe {
-                (
-                    cdio_msf_to_lsn(toc.get(i as usize).unwrap()),
-                    cdio_msf_to_lsn(toc.get((i + 1) as usize).unwrap()),
-                )
-            })
-            .collect::<Vec<_>>();
+        let lookup = MetadataLookup::new(&config.metadata);
+        let (metadata, missing) = load_metadata(source.as_ref(), &toc, &lookup)?;
+        log_metadata(&metadata, &toc);
 
-        // Some albus contains a single track only
-        let songs = if tracks > 1 {
-            vec![
-                Song::new(1, song_sectors[0])?,
-                Song::new(2, song_sectors[1])?,
-            ]
-        } else {
-            vec![Song::new(1, song_sectors[0])?]
-        };
+        let disc_id = metadata.disc_id.clone();
+        let accuraterip = disc_id
+            .as_ref()
+            .and_then(|disc_id| accuraterip::lookup(&config.accuraterip.database, disc_id));
+        let archive = config
+            .rip
+            .background
+            .then(|| Archive::new(config.rip.clone(), &metadata, &toc));
 
         // Set the number of tracks for this CD
-        state.lock().unwrap().total_tracks = tracks;
+        state.lock().unwrap().load_disc(toc.clone(), metadata);
+        if let Some(disc_id) = disc_id
+            && missing
+        {
+            info!("disc not found in the metadata database, looking it up online");
+            lookup.spawn_online(state.clone(), disc_id);
+        }
 
         Ok(Self {
-            cdio,
-            song_sectors,
+            source,
+            toc,
             state,
-            tracks,
-            songs,
+            ring,
+            buf: vec![0; SECTOR_SIZE * config.drive.read_sectors as usize],
+            config,
+            song: None,
+            archive,
+            accuraterip,
+            filling: None,
+            last_seek: None,
+            indexed: HashSet::new(),
         })
     }
 
-    pub fn get_drive() -> Option<*const i8> {
-        let mut driver_id = Box::new(driver_id_t_DRIVER_LINUX);
-        let all_cd_drives = unsafe { cdio_get_devices_ret(&mut *driver_id) };
-        let cdda_drives = unsafe {
-            cdio_get_devices_with_cap(
-                all_cd_drives,
-                cdio_fs_t_CDIO_FS_AUDIO.try_into().unwrap(),
-                0,
-            )
+    /// Look for the index points of `track` the first time it is read, rather than for the whole
+    /// disc before anything can be played
+    fn read_index_points(&mut self, track: u8) {
+        if !self.indexed.insert(track) {
+            return;
+        }
+        if let Err(err) = self.toc.read_index_points(self.source.as_mut(), track) {
+            warn!("unable to read the index points of track {track}: {err}");
+        }
+        if let Some(toc_track) = self.toc.track(track)
+            && !toc_track.indexes.is_empty()
+        {
+            let indexes = toc_track.indexes.clone();
+            self.state.lock().unwrap().set_indexes(track, indexes);
+        }
+    }
+
+    /// A song to read `track` into, as much of it as is played
+    fn new_song(&self, track: usize) -> Song {
+        self.song(track, self.toc.sectors(track as u8))
+    }
+
+    /// A song to read `sectors` of `track` into. If they are the whole track, it is verified and
+    /// kept if it still has to be archived.
+    fn song(&self, track: usize, sectors: (i32, i32)) -> Song {
+        let mut song = Song::new(track, sectors);
+        // The data session of an enhanced CD can't be read as audio
+        song.correct_offset(self.config.drive.read_offset, self.toc.audio_end());
+        let whole = self
+            .toc
+            .track(track as u8)
+            .is_some_and(|toc_track| toc_track.sectors() == sectors);
+        // AccurateRip knows nothing about the hidden track, nor about tracks without their gaps
+        let Some(position) = self.toc.audio_position(track as u8).filter(|_| whole) else {
+            return song;
+        };
+        song.verify(position, self.toc.audio_tracks().len() as u8);
+        if self
+            .archive
+            .as_ref()
+            .is_some_and(|archive| archive.wants(track as u8))
+        {
+            song.keep = Some(Vec::new());
+        }
+        song
+    }
+
+    /// `song` has been read till its end: check it against AccurateRip and hand it over to the
+    /// archive, if it has been read whole
+    fn finish_song(&mut self, song: &mut Song) {
+        let track = song.track_id as u8;
+        if let Some(checksums) = song.checksums.take()
+            && let Some(position) = self.toc.audio_position(track)
+        {
+            let verification =
+                accuraterip::verify(self.accuraterip.as_ref(), position, checksums.finish());
+            verification.log(track);
+            self.state
+                .lock()
+                .unwrap()
+                .track_verified(track, verification);
+        }
+        if let Some(archive) = &mut self.archive
+            && let Some(pcm) = song.keep.take()
+        {
+            let metadata = self.state.lock().unwrap().metadata.clone();
+            archive.complete(track, pcm, metadata);
+        }
+    }
+
+    /// Read the tracks that haven't been played yet into the archive, until interrupted.
+    /// Returns false if there is nothing left to archive.
+    fn fill_archive(&mut self, interrupted: impl Fn() -> bool) -> Result<bool> {
+        let Some(archive) = &mut self.archive else {
+            return Ok(false);
         };
-        unsafe { cdio_free_device_list(all_cd_drives) };
-        if cdda_drives.is_null() || (unsafe { *cdda_drives }).is_null() {
-            None
+        // Don't read another track while the last one read is still waiting for the encoder
+        if !archive.hand_over(HAND_OVER_WAIT) {
+            return Ok(true);
+        }
+        let mut song = match self.filling.take() {
+            Some(song) if archive.wants(song.track_id as u8) => song,
+            _ => {
+                // The track being played is kept already
+                let playing = self.song.as_ref().map(|song| song.track_id as u8);
+                let Some(track) = archive.missing(playing) else {
+                    return Ok(false);
+                };
+                info!("archiving track {track} while the player is idle");
+                let sectors = self.toc.track(track).map_or((0, 0), TocTrack::sectors);
+                self.song(track as usize, sectors)
+            }
+        };
+
+        song.read_kept(self.source.as_mut(), &mut self.buf, interrupted)?;
+        if song.ended {
+            self.finish_song(&mut song);
         } else {
-            Some(unsafe { *cdda_drives })
+            self.filling = Some(song);
+        }
+        Ok(true)
+    }
+
+    /// Rip the whole disc, unless interrupted
+    fn rip(&mut self, interrupted: impl Fn() -> bool) {
+        let metadata = self.state.lock().unwrap().metadata.clone();
+        match rip::rip_disc(
+            self.source.as_mut(),
+            &self.toc,
+            &metadata,
+            &self.config,
+            &mut self.buf,
+            interrupted,
+        ) {
+            Ok(files) => info!("ripped {} tracks", files.len()),
+            Err(err) => warn!("ripping failed: {err}"),
         }
     }
 
     pub fn handle(&mut self) -> Result<()> {
         loop {
-            let action = {
+            let (action, seek, state_changed) = {
                 let lock = self.state.lock().unwrap();
                 *lock.state_changed.write().unwrap() = false;
-                lock.action.clone()
+                (lock.action, lock.seek, lock.state_changed.clone())
             };
+            let interrupted = || *state_changed.read().unwrap();
 
             match action {
-                Action::Stop => break,
-                Action::Play(track) => {
-                    let track = track as usize;
-                    // The song to play is different than the current
-                    if track != self.songs[0].track_id {
-                        // The song to play is the next cached song
-                        if track == self.songs[1].track_id {
-                            self.songs.remove(0);
-                        } else {
-                            // Remove both cached songs
-                            self.songs.clear();
-                            // Load whanever songs we need
-                            self.songs
-                                .push(Song::new(track, self.song_sectors[track - 1])?);
+                Action::Quit => break,
+                Action::Stop => {
+                    let mut state = self.state.lock().unwrap();
+                    if std::mem::take(&mut state.eject) {
+                        drop(state);
+                        self.source.eject()?;
+                    } else if std::mem::take(&mut state.rip) {
+                        drop(state);
+                        self.rip(interrupted);
+                    } else if self.song.is_none() && self.last_seek.is_some() {
+                        // The whole disc has been played, the drive is still spinning
+                        drop(state);
+                        if !self.fill_archive(interrupted)? {
+                            self.state.lock().unwrap().wait_for_change();
                         }
-                        let next_track_id = track + 1;
-                        if next_track_id < self.tracks.into() {
-                            // Add the next song to the queue
-                            self.songs.push(Song::new(
-                                next_track_id,
-                                self.song_sectors[next_track_id - 1],
-                            )?);
+                    } else {
+                        state.wait_for_change();
+                    }
+                }
+                // The ring is filled ahead of the player even while paused, as the player holds
+                // on to what has been read ahead until the pause ends, or drops it after a jump
+                Action::Play(_) | Action::Pause(_) => {
+                    if self.last_seek != Some(seek.id) {
+                        // Drop whatever we were reading and start again from where we jumped to
+                        self.read_index_points(seek.track);
+                        let track = seek.track as usize;
+                        let mut song = self.new_song(track);
+                        song.seek((seek.frame / FRAMES_PER_SECTOR) as i32);
+                        if !self.ring.push(
+                            Chunk::Track {
+                                track: seek.track,
+                                seek: seek.id,
+                            },
+                            interrupted,
+                        ) {
+                            continue;
                         }
+                        self.song = Some(song);
+                        self.last_seek = Some(seek.id);
+                    }
+
+                    let paused = matches!(action, Action::Pause(_));
+                    let full = self.ring.slots() < self.buf.len() / SECTOR_SIZE;
+                    match &mut self.song {
+                        // The whole disc has been read, or the player doesn't need anything more
+                        // for now: archive what hasn't been played yet meanwhile
+                        None => {
+                            if !self.fill_archive(interrupted)? {
+                                self.state.lock().unwrap().wait_for_change();
+                            }
+                        }
+                        Some(_) if paused && full => {
+                            if !self.fill_archive(interrupted)? {
+                                self.state.lock().unwrap().wait_for_change();
+                            }
+                        }
+                        Some(song) if song.ended => {
+                            // Mark the track boundary and start reading the next one, skipping
+                            // the data tracks
+                            let next_track = self.toc.next_audio_track(song.track_id as u8);
+                            if let Some(track) = next_track {
+                                self.read_index_points(track);
+                            }
+                            let (chunk, next_song) = match next_track {
+                                Some(track) => (
+                                    Chunk::Track {
+                                        track,
+                                        seek: seek.id,
+                                    },
+                                    Some(self.new_song(track.into())),
+                                ),
+                                None => (Chunk::End { seek: seek.id }, None),
+                            };
+                            if self.ring.push(chunk, interrupted) {
+                                if let Some(mut song) = self.song.take() {
+                                    self.finish_song(&mut song);
+                                }
+                                self.song = next_song;
+                            }
+                        }
+                        Some(song) => song.read(
+                            self.source.as_mut(),
+                            &mut self.buf,
+                            &mut self.ring,
+                            paused,
+                            interrupted,
+                        )?,
                     }
-                    self.read_cd()?;
                 }
-                Action::Pause(_) => self.state.lock().unwrap().wait_for_change(),
             }
         }
 
         Ok(())
     }
+}
 
-    fn read_cd(&mut self) -> Result<()> {
-        // The song hasn't been read yet
-        let mut ended = self.songs[0].ended;
-        if !ended {
-            self.songs[0].read(self.cdio, self.state.clone())?;
-            ended = self.songs[0].ended;
-            // The song has been fully read
-            if ended && self.songs.len() == 2 {
-                // start reading the next
-                self.songs[1].read(self.cdio, self.state.clone())?;
-                ended = self.songs[1].ended;
-            }
-        }
-        // Do this after the block above has been evaluated
-        if ended {
-            // We cached two songs, wait for change
-            self.state.lock().unwrap().wait_for_change();
+/// Read the CD-Text, the catalog number and the ISRCs and compute the disc ID, then look the disc
+/// up in the offline database. Returns the metadata and whether the disc is missing from the
+/// database.
+pub fn load_metadata(
+    source: &dyn DiscSource,
+    toc: &Toc,
+    lookup: &MetadataLookup,
+) -> Result<(DiscMetadata, bool)> {
+    let cdtext = source.cdtext().unwrap_or_else(|err| {
+        warn!("unable to read the CD-Text: {err}");
+        Vec::new()
+    });
+    let mut metadata = DiscMetadata::from_cdtext(cdtext);
+    metadata.disc_id = Some(toc.disc_id());
+    metadata.mcn = source.mcn().unwrap_or_else(|err| {
+        warn!("unable to read the catalog number: {err}");
+        None
+    });
+    for track in toc.audio_tracks() {
+        match source.track_isrc(track) {
+            Ok(Some(isrc)) => metadata.tracks.entry(track).or_default().isrc = Some(isrc),
+            Ok(None) => {}
+            Err(err) => warn!("unable to read the ISRC of track {track}: {err}"),
         }
+    }
+    let missing = !lookup.offline(toc, &mut metadata);
+    Ok((metadata, missing))
+}
 
-        Ok(())
+fn log_metadata(metadata: &DiscMetadata, toc: &Toc) {
+    if let Some(disc_id) = &metadata.disc_id {
+        info!(
+            "disc ID {} (FreeDB {})",
+            disc_id.musicbrainz,
+            disc_id.freedb_hex()
+        );
+    }
+    if !metadata.cdtext.is_empty() {
+        let languages = metadata
+            .cdtext
+            .iter()
+            .map(|block| block.language.as_str())
+            .collect::<Vec<_>>();
+        info!("CD-Text in {}", languages.join(", "));
+    }
+    if let Some(mcn) = &metadata.mcn {
+        info!("catalog number {mcn}");
+    }
+    // The ISRCs alone don't tell anything about the tracks
+    let named = metadata
+        .tracks
+        .values()
+        .any(|track| track.title.is_some() || track.artist.is_some());
+    if metadata.album.is_none() && !named {
+        info!("disc with {} tracks and no metadata", toc.tracks.len());
+        return;
+    }
+    info!(
+        "{} by {}",
+        metadata.album.as_deref().unwrap_or("Unknown album"),
+        metadata.artist.as_deref().unwrap_or("unknown artist")
+    );
+    for track in toc.audio_tracks() {
+        match metadata.track_artist(track) {
+            Some(artist) => info!("{track}. {} - {artist}", metadata.track_title(track)),
+            None => info!("{track}. {}", metadata.track_title(track)),
+        }
     }
 }
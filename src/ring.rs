@@ -0,0 +1,119 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use libcdio_sys::CDIO_CD_FRAMESIZE_RAW;
+use rtrb::{PushError, RingBuffer};
+
+pub const SECTOR_SIZE: usize = CDIO_CD_FRAMESIZE_RAW as usize;
+
+/// How long a side waits for the other one before checking again if it has been interrupted
+const WAIT_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// What the reader hands to the player. Track boundaries are marked in-band, each marker carrying
+/// the id of the seek (see `state::Seek`) it belongs to, so that the player can drop the stale data
+/// read before a jump.
+// The ring allocates its slots once, storing the sectors inline avoids an allocation per sector
+#[allow(clippy::large_enum_variant)]
+pub enum Chunk {
+    /// The following sectors belong to `track`
+    Track { track: u8, seek: u32 },
+    /// Raw CD-DA: interleaved stereo samples, signed 16-bit little endian
+    Sector([u8; SECTOR_SIZE]),
+    /// The whole disc has been read
+    End { seek: u32 },
+}
+
+/// Threads parked waiting for the other side of the ring
+#[derive(Default)]
+struct Waiting {
+    producer: Mutex<Option<Thread>>,
+    consumer: Mutex<Option<Thread>>,
+}
+
+impl Waiting {
+    fn wait(slot: &Mutex<Option<Thread>>, ready: impl Fn() -> bool) {
+        *slot.lock().unwrap() = Some(thread::current());
+        // Check again after registering, the other side might have just woken nobody up
+        if !ready() {
+            thread::park_timeout(WAIT_TIMEOUT);
+        }
+        slot.lock().unwrap().take();
+    }
+
+    fn wake(slot: &Mutex<Option<Thread>>) {
+        if let Some(thread) = slot.lock().unwrap().take() {
+            thread.unpark();
+        }
+    }
+}
+
+/// Create a bounded lock-free ring of `capacity` chunks between the reader and the player
+pub fn ring(capacity: usize) -> (Producer, Consumer) {
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let waiting = Arc::new(Waiting::default());
+    (
+        Producer {
+            inner: producer,
+            waiting: waiting.clone(),
+        },
+        Consumer {
+            inner: consumer,
+            waiting,
+        },
+    )
+}
+
+pub struct Producer {
+    inner: rtrb::Producer<Chunk>,
+    waiting: Arc<Waiting>,
+}
+
+impl Producer {
+    /// Push `chunk`, waiting for the player to make room for it. Returns false if the chunk has
+    /// been dropped because `interrupted` returned true, or the player is gone.
+    pub fn push(&mut self, mut chunk: Chunk, interrupted: impl Fn() -> bool) -> bool {
+        loop {
+            match self.inner.push(chunk) {
+                Ok(()) => {
+                    Waiting::wake(&self.waiting.consumer);
+                    return true;
+                }
+                Err(PushError::Full(rejected)) => chunk = rejected,
+            }
+            if interrupted() || self.inner.is_abandoned() {
+                return false;
+            }
+            Waiting::wait(&self.waiting.producer, || !self.inner.is_full());
+        }
+    }
+
+    /// How many chunks can be pushed without waiting
+    pub fn slots(&self) -> usize {
+        self.inner.slots()
+    }
+}
+
+pub struct Consumer {
+    inner: rtrb::Consumer<Chunk>,
+    waiting: Arc<Waiting>,
+}
+
+impl Consumer {
+    /// Pop the next chunk, waiting for the reader to push it. Returns None if `interrupted`
+    /// returned true, or the reader is gone and the ring is empty.
+    pub fn pop(&mut self, interrupted: impl Fn() -> bool) -> Option<Chunk> {
+        loop {
+            if let Ok(chunk) = self.inner.pop() {
+                Waiting::wake(&self.waiting.producer);
+                return Some(chunk);
+            }
+            if interrupted() || self.inner.is_abandoned() {
+                return None;
+            }
+            Waiting::wait(&self.waiting.consumer, || !self.inner.is_empty());
+        }
+    }
+}
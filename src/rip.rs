@@ -0,0 +1,698 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Report, Result,
+};
+use flacenc::{
+    bitsink::ByteSink,
+    component::BitRepr,
+    error::{SourceError, Verify},
+    source::{Fill, Source},
+};
+use log::info;
+
+use crate::{
+    accuraterip::{self, Verification},
+    config::{Config, RipConfig},
+    disc::DiscSource,
+    emphasis::Deemphasis,
+    metadata::DiscMetadata,
+    read_cd::Song,
+    ring::SECTOR_SIZE,
+    toc::Toc,
+};
+
+/// The placeholders a naming template can use
+const PLACEHOLDERS: &[&str] = &[
+    "albumartist",
+    "album",
+    "artist",
+    "title",
+    "track",
+    "year",
+    "genre",
+    "discid",
+];
+
+const VENDOR: &str = concat!("raspi-cd-player ", env!("CARGO_PKG_VERSION"));
+/// The FLAC metadata block type of Vorbis comments
+const VORBIS_COMMENT: u8 = 4;
+
+/// Fill `template`, calling `value` for every placeholder in it
+fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("unterminated placeholder in {template}");
+        };
+        let name = &rest[start + 1..start + end];
+        let Some(value) = value(name) else {
+            bail!("unknown placeholder {{{name}}}");
+        };
+        rendered.push_str(&sanitize(&value));
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Make sure that a value doesn't add directories to the path
+fn sanitize(value: &str) -> String {
+    let value = value.trim().replace(['/', '\0'], "_");
+    match value.strip_prefix('.') {
+        Some(rest) => format!("_{rest}"),
+        None => value,
+    }
+}
+
+/// Check that a naming template only uses known placeholders
+pub fn check_template(template: &str) -> Result<()> {
+    render(template, |name| {
+        PLACEHOLDERS.contains(&name).then(String::new)
+    })?;
+    Ok(())
+}
+
+fn album_artist(metadata: &DiscMetadata) -> String {
+    metadata
+        .artist
+        .clone()
+        .unwrap_or_else(|| "Unknown Artist".to_string())
+}
+
+fn album(metadata: &DiscMetadata) -> String {
+    metadata
+        .album
+        .clone()
+        .unwrap_or_else(|| "Unknown Album".to_string())
+}
+
+/// Where `track` is ripped to, following the naming template
+pub fn track_path(config: &RipConfig, metadata: &DiscMetadata, track: u8) -> Result<PathBuf> {
+    let path = render(&config.template, |name| {
+        Some(match name {
+            "albumartist" => album_artist(metadata),
+            "album" => album(metadata),
+            "artist" => metadata
+                .track_artist(track)
+                .unwrap_or_else(|| album_artist(metadata)),
+            "title" => metadata.track_title(track),
+            "track" => format!("{track:02}"),
+            "year" => metadata
+                .year
+                .map(|year| year.to_string())
+                .unwrap_or_default(),
+            "genre" => metadata.genre.clone().unwrap_or_default(),
+            "discid" => metadata
+                .disc_id
+                .as_ref()
+                .map(|disc_id| disc_id.freedb_hex())
+                .unwrap_or_default(),
+            _ => return None,
+        })
+    })?;
+    Ok(config.directory.join(format!("{path}.flac")))
+}
+
+/// The Vorbis comments of `track`, out of `total_tracks`
+pub fn track_tags(
+    metadata: &DiscMetadata,
+    track: u8,
+    total_tracks: u8,
+) -> Vec<(&'static str, String)> {
+    let track_metadata = metadata.track(track);
+    let mut tags = vec![
+        ("TITLE", Some(metadata.track_title(track))),
+        ("ARTIST", metadata.track_artist(track)),
+        ("ALBUM", metadata.album.clone()),
+        ("ALBUMARTIST", metadata.artist.clone()),
+        ("TRACKNUMBER", Some(track.to_string())),
+        ("TRACKTOTAL", Some(total_tracks.to_string())),
+        ("DATE", metadata.year.map(|year| year.to_string())),
+        ("GENRE", metadata.genre.clone()),
+        (
+            "COMPOSER",
+            track_metadata.and_then(|track| track.composer.clone()),
+        ),
+        (
+            "LYRICIST",
+            track_metadata.and_then(|track| track.songwriter.clone()),
+        ),
+        ("ISRC", metadata.track_isrc(track)),
+        // The catalog number is the barcode of the disc
+        ("BARCODE", metadata.mcn.clone()),
+    ];
+    if let Some(disc_id) = &metadata.disc_id {
+        tags.push(("MUSICBRAINZ_DISCID", Some(disc_id.musicbrainz.clone())));
+        tags.push(("DISCID", Some(disc_id.freedb_hex())));
+    }
+    tags.into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+}
+
+/// Feeds the encoder with CD-DA, a chunk of sectors at a time
+struct SectorSource<F> {
+    next_chunk: F,
+    /// Interleaved samples read but not encoded yet
+    samples: Vec<i32>,
+    /// The encoder can't be told why the samples have ended, keep it here
+    error: Option<Report>,
+}
+
+/// The encoder takes its source by value, while the error has to be looked at afterwards
+struct Feeder<'a, F>(&'a mut SectorSource<F>);
+
+impl<F: FnMut() -> Result<Option<Vec<u8>>>> Source for Feeder<'_, F> {
+    fn channels(&self) -> usize {
+        2
+    }
+
+    fn bits_per_sample(&self) -> usize {
+        16
+    }
+
+    fn sample_rate(&self) -> usize {
+        44100
+    }
+
+    fn read_samples<D: Fill>(
+        &mut self,
+        block_size: usize,
+        dest: &mut D,
+    ) -> Result<usize, SourceError> {
+        let source = &mut *self.0;
+        let wanted = block_size * 2;
+        while source.error.is_none() && source.samples.len() < wanted {
+            match (source.next_chunk)() {
+                Ok(Some(chunk)) => source.samples.extend(
+                    chunk
+                        .chunks_exact(2)
+                        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as i32),
+                ),
+                Ok(None) => break,
+                Err(err) => source.error = Some(err),
+            }
+        }
+        if source.error.is_some() {
+            return Ok(0);
+        }
+
+        let read = wanted.min(source.samples.len());
+        dest.fill_interleaved(&source.samples[..read])?;
+        source.samples.drain(..read);
+        Ok(read / 2)
+    }
+}
+
+/// Encode the CD-DA returned by `next_chunk` into a FLAC file with `tags`. `next_chunk` returns
+/// None once there is nothing left.
+pub fn encode(
+    next_chunk: impl FnMut() -> Result<Option<Vec<u8>>>,
+    tags: &[(&str, String)],
+) -> Result<Vec<u8>> {
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, err)| eyre!("invalid FLAC encoder configuration: {err:?}"))?;
+    let mut source = SectorSource {
+        next_chunk,
+        samples: Vec::new(),
+        error: None,
+    };
+    let stream =
+        flacenc::encode_with_fixed_block_size(&config, Feeder(&mut source), config.block_size)
+            .map_err(|err| eyre!("unable to encode FLAC: {err:?}"));
+    if let Some(err) = source.error {
+        return Err(err);
+    }
+    let stream = stream?;
+
+    let mut sink = ByteSink::new();
+    stream
+        .write(&mut sink)
+        .map_err(|err| eyre!("unable to write FLAC: {err:?}"))?;
+    add_vorbis_comments(sink.as_slice(), tags)
+}
+
+/// Append a VORBIS_COMMENT block to the metadata blocks of an encoded FLAC stream
+fn add_vorbis_comments(flac: &[u8], tags: &[(&str, String)]) -> Result<Vec<u8>> {
+    if !flac.starts_with(b"fLaC") {
+        bail!("the encoder didn't produce a FLAC stream");
+    }
+    // Every metadata block has a 4 bytes header: last block flag and type, then 24 bits of length
+    let mut offset = 4;
+    let last_header = loop {
+        let Some(header) = flac.get(offset..offset + 4) else {
+            bail!("truncated FLAC metadata");
+        };
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        if header[0] & 0x80 != 0 {
+            break offset;
+        }
+        offset += 4 + length;
+    };
+    let length = u32::from_be_bytes([
+        0,
+        flac[last_header + 1],
+        flac[last_header + 2],
+        flac[last_header + 3],
+    ]);
+    let audio = last_header + 4 + length as usize;
+
+    let mut comments = Vec::new();
+    comments.extend((VENDOR.len() as u32).to_le_bytes());
+    comments.extend(VENDOR.as_bytes());
+    comments.extend((tags.len() as u32).to_le_bytes());
+    for (key, value) in tags {
+        let comment = format!("{key}={value}");
+        comments.extend((comment.len() as u32).to_le_bytes());
+        comments.extend(comment.as_bytes());
+    }
+    if comments.len() >= 1 << 24 {
+        bail!("the tags don't fit in a FLAC metadata block");
+    }
+
+    let mut tagged = Vec::with_capacity(flac.len() + 4 + comments.len());
+    tagged.extend(&flac[..audio]);
+    // The comments are the last block now
+    tagged[last_header] &= 0x7f;
+    tagged.push(0x80 | VORBIS_COMMENT);
+    tagged.extend(&(comments.len() as u32).to_be_bytes()[1..]);
+    tagged.extend(comments);
+    tagged.extend(&flac[audio..]);
+    Ok(tagged)
+}
+
+/// Write `flac` to `path`, creating the directories it is in
+pub fn write_track(path: &Path, flac: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("unable to create {}", dir.display()))?;
+    }
+    fs::write(path, flac).with_context(|| format!("unable to write {}", path.display()))
+}
+
+/// Quote a CUE sheet value; it can't contain quotes itself
+fn cue_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "'"))
+}
+
+/// Write a CUE sheet describing the tracks ripped to `files`, by track number, next to the first
+/// one. The tracks in `emphasized` have been ripped with their pre-emphasis and are flagged so.
+pub fn write_cue(
+    metadata: &DiscMetadata,
+    files: &[(u8, PathBuf)],
+    emphasized: &[u8],
+) -> Result<PathBuf> {
+    let Some(dir) = files.first().and_then(|(_, file)| file.parent()) else {
+        bail!("no track has been ripped");
+    };
+    let path = dir.join(format!("{}.cue", sanitize(&album(metadata))));
+
+    let mut cue = String::new();
+    if let Some(mcn) = &metadata.mcn {
+        writeln!(cue, "CATALOG {mcn}")?;
+    }
+    if let Some(genre) = &metadata.genre {
+        writeln!(cue, "REM GENRE {}", cue_quote(genre))?;
+    }
+    if let Some(year) = metadata.year {
+        writeln!(cue, "REM DATE {year}")?;
+    }
+    if let Some(disc_id) = &metadata.disc_id {
+        writeln!(cue, "REM DISCID {}", disc_id.freedb_hex().to_uppercase())?;
+    }
+    writeln!(cue, "PERFORMER {}", cue_quote(&album_artist(metadata)))?;
+    writeln!(cue, "TITLE {}", cue_quote(&album(metadata)))?;
+    for &(track, ref file) in files {
+        let file = file.strip_prefix(dir).unwrap_or(file);
+        writeln!(cue, "FILE {} WAVE", cue_quote(&file.to_string_lossy()))?;
+        writeln!(cue, "  TRACK {track:02} AUDIO")?;
+        if emphasized.contains(&track) {
+            writeln!(cue, "    FLAGS PRE")?;
+        }
+        if let Some(isrc) = metadata.track_isrc(track) {
+            writeln!(cue, "    ISRC {isrc}")?;
+        }
+        writeln!(cue, "    TITLE {}", cue_quote(&metadata.track_title(track)))?;
+        if let Some(artist) = metadata.track_artist(track) {
+            writeln!(cue, "    PERFORMER {}", cue_quote(&artist))?;
+        }
+        writeln!(cue, "    INDEX 01 00:00:00")?;
+    }
+
+    fs::write(&path, cue).with_context(|| format!("unable to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Write the rip log next to the tracks ripped to `files`, by track number, with how every one of
+/// them has been verified
+pub fn write_log(
+    metadata: &DiscMetadata,
+    files: &[(u8, PathBuf)],
+    verifications: &[Verification],
+) -> Result<PathBuf> {
+    let Some(dir) = files.first().and_then(|(_, file)| file.parent()) else {
+        bail!("no track has been ripped");
+    };
+    let path = dir.join(format!("{}.log", sanitize(&album(metadata))));
+
+    let mut log = String::new();
+    writeln!(log, "{VENDOR}")?;
+    writeln!(log)?;
+    writeln!(log, "Artist: {}", album_artist(metadata))?;
+    writeln!(log, "Album: {}", album(metadata))?;
+    if let Some(disc_id) = &metadata.disc_id {
+        writeln!(
+            log,
+            "Disc ID: {} (FreeDB {})",
+            disc_id.musicbrainz,
+            disc_id.freedb_hex()
+        )?;
+        writeln!(log, "AccurateRip: {}", accuraterip::file_name(disc_id))?;
+    }
+    writeln!(log)?;
+    for ((track, file), verification) in files.iter().zip(verifications) {
+        let checksums = &verification.checksums;
+        writeln!(log, "Track {track:02}: {}", file.display())?;
+        writeln!(log, "  CRC32: {:08X}", checksums.crc32)?;
+        writeln!(
+            log,
+            "  AccurateRip: v1 {:08X}, v2 {:08X}",
+            checksums.accuraterip_v1, checksums.accuraterip_v2
+        )?;
+        writeln!(log, "  Result: {}", verification.accuracy)?;
+    }
+
+    fs::write(&path, log).with_context(|| format!("unable to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Rip every audio track in `toc` to FLAC, reading as many sectors at once as fit in `buf`,
+/// and verify them against AccurateRip. The pre-emphasized tracks are de-emphasized if the
+/// configuration says so, after verifying them. Stops as soon as `interrupted` returns true.
+/// Returns the files written.
+pub fn rip_disc(
+    source: &mut dyn DiscSource,
+    toc: &Toc,
+    metadata: &DiscMetadata,
+    config: &Config,
+    buf: &mut [u8],
+    interrupted: impl Fn() -> bool,
+) -> Result<Vec<PathBuf>> {
+    let total_tracks = toc.last_track();
+    let accuraterip = metadata
+        .disc_id
+        .as_ref()
+        .and_then(|disc_id| accuraterip::lookup(&config.accuraterip.database, disc_id));
+    let audio_tracks = toc.audio_tracks().len() as u8;
+    let mut files = Vec::new();
+    let mut verifications = Vec::new();
+    // AccurateRip counts the audio tracks alone
+    let mut position = 0;
+    for toc_track in &toc.tracks {
+        let track = toc_track.number;
+        if !toc_track.audio {
+            info!("skipping data track {track}");
+            continue;
+        }
+        position += 1;
+        let path = track_path(&config.rip, metadata, track)?;
+        info!("ripping track {track} to {}", path.display());
+
+        let mut song = Song::new(track as usize, toc_track.sectors());
+        song.verify(position, audio_tracks);
+        // The data session of an enhanced CD can't be read as audio
+        song.correct_offset(config.drive.read_offset, toc.audio_end());
+        let mut deemphasis =
+            (config.rip.deemphasis && toc_track.preemphasis).then(Deemphasis::default);
+        let flac = encode(
+            || {
+                if interrupted() {
+                    bail!("ripping interrupted");
+                }
+                let read = song.read_chunk(source, buf)?;
+                let pcm = &buf[..read * SECTOR_SIZE];
+                song.advance(pcm);
+                let mut pcm = pcm.to_vec();
+                if let Some(deemphasis) = &mut deemphasis {
+                    deemphasis.process(&mut pcm);
+                }
+                Ok((read > 0).then_some(pcm))
+            },
+            &track_tags(metadata, track, total_tracks),
+        )?;
+        write_track(&path, &flac)?;
+        files.push((track, path));
+
+        if let Some(checksums) = song.checksums.take() {
+            let verification =
+                accuraterip::verify(accuraterip.as_ref(), position, checksums.finish());
+            verification.log(track);
+            verifications.push(verification);
+        }
+    }
+
+    if config.rip.cue {
+        let emphasized = toc
+            .tracks
+            .iter()
+            .filter(|track| track.preemphasis && !config.rip.deemphasis)
+            .map(|track| track.number)
+            .collect::<Vec<_>>();
+        let cue = write_cue(metadata, &files, &emphasized)?;
+        info!("CUE sheet written to {}", cue.display());
+    }
+    let log = write_log(metadata, &files, &verifications)?;
+    info!("rip log written to {}", log.display());
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake_disc::FakeDisc, metadata::TrackMetadata};
+
+    fn metadata() -> DiscMetadata {
+        let mut metadata = DiscMetadata {
+            album: Some("Album".to_string()),
+            artist: Some("Artist".to_string()),
+            ..DiscMetadata::default()
+        };
+        for (track, title) in [(1, "One"), (2, "Two")] {
+            metadata.tracks.insert(
+                track,
+                TrackMetadata {
+                    title: Some(title.to_string()),
+                    ..TrackMetadata::default()
+                },
+            );
+        }
+        metadata
+    }
+
+    #[test]
+    fn values_stay_in_their_directory() {
+        let config = RipConfig {
+            directory: PathBuf::from("/music"),
+            ..RipConfig::default()
+        };
+        let mut metadata = metadata();
+        metadata.artist = Some("AC/DC".to_string());
+        metadata.album = Some("../Album".to_string());
+        metadata.tracks.get_mut(&1).unwrap().title = Some(" .hidden ".to_string());
+        assert_eq!(
+            track_path(&config, &metadata, 1).unwrap(),
+            Path::new("/music/AC_DC/_._Album/01 - _hidden.flac")
+        );
+    }
+
+    #[test]
+    fn placeholders() {
+        assert!(check_template("{albumartist}/{album}/{track} - {title}").is_ok());
+        assert!(check_template("no placeholders").is_ok());
+        let err = check_template("{artist}/{composer}").unwrap_err();
+        assert_eq!(err.to_string(), "unknown placeholder {composer}");
+        let err = check_template("{artist}/{title").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated placeholder in {artist}/{title"
+        );
+    }
+
+    /// A metadata block of a FLAC stream
+    struct Block<'a> {
+        last: bool,
+        kind: u8,
+        content: &'a [u8],
+    }
+
+    /// The metadata blocks of a FLAC stream, with what follows them: the audio
+    fn metadata_blocks(flac: &[u8]) -> (Vec<Block<'_>>, &[u8]) {
+        assert!(flac.starts_with(b"fLaC"));
+        let mut blocks = Vec::new();
+        let mut rest = &flac[4..];
+        loop {
+            let length = u32::from_be_bytes([0, rest[1], rest[2], rest[3]]) as usize;
+            let last = rest[0] & 0x80 != 0;
+            blocks.push(Block {
+                last,
+                kind: rest[0] & 0x7f,
+                content: &rest[4..4 + length],
+            });
+            rest = &rest[4 + length..];
+            if last {
+                return (blocks, rest);
+            }
+        }
+    }
+
+    #[test]
+    fn vorbis_comments_are_the_last_block() {
+        // STREAMINFO and PADDING, then the audio frames
+        let mut flac = b"fLaC".to_vec();
+        flac.extend([0, 0, 0, 34]);
+        flac.extend([0x11; 34]);
+        flac.extend([0x80 | 1, 0, 0, 8]);
+        flac.extend([0; 8]);
+        flac.extend(b"frames");
+
+        let tags = [
+            ("TITLE", "One".to_string()),
+            ("ARTIST", "Artist".to_string()),
+        ];
+        let tagged = add_vorbis_comments(&flac, &tags).unwrap();
+        let (blocks, audio) = metadata_blocks(&tagged);
+        assert_eq!(audio, b"frames");
+        let flags = blocks
+            .iter()
+            .map(|block| (block.last, block.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(flags, [(false, 0), (false, 1), (true, VORBIS_COMMENT)]);
+        assert_eq!(blocks[0].content, [0x11; 34]);
+
+        // Little endian lengths, unlike the block headers
+        let mut comments = blocks[2].content;
+        assert_eq!(field(&mut comments), VENDOR.as_bytes());
+        assert_eq!(number(&mut comments), 2);
+        assert_eq!(field(&mut comments), b"TITLE=One");
+        assert_eq!(field(&mut comments), b"ARTIST=Artist");
+        assert!(comments.is_empty());
+    }
+
+    /// Take a little endian 32 bits number off `bytes`
+    fn number(bytes: &mut &[u8]) -> u32 {
+        let (number, rest) = bytes.split_at(4);
+        *bytes = rest;
+        u32::from_le_bytes(number.try_into().unwrap())
+    }
+
+    /// Take a field and its length off `bytes`
+    fn field<'a>(bytes: &mut &'a [u8]) -> &'a [u8] {
+        let length = number(bytes) as usize;
+        let (field, rest) = bytes.split_at(length);
+        *bytes = rest;
+        field
+    }
+
+    #[test]
+    fn not_flac() {
+        assert!(add_vorbis_comments(b"RIFF", &[]).is_err());
+        // The last block flag is never found
+        assert!(add_vorbis_comments(b"fLaC\0\0\0\x02ab", &[]).is_err());
+    }
+
+    #[test]
+    fn cue_sheet() {
+        let directory = std::env::temp_dir().join(format!("rip-cue-{}", std::process::id()));
+        let mut config = Config::default();
+        config.rip.directory = directory.clone();
+        config.accuraterip.database = directory.join("nowhere");
+        let mut disc: FakeDisc = "sine:1:pre,sine:1".parse().unwrap();
+        let toc = Toc::read(&mut disc).unwrap();
+        let mut metadata = metadata();
+        metadata.disc_id = Some(toc.disc_id());
+        metadata.mcn = Some("0123456789012".to_string());
+        metadata.tracks.get_mut(&1).unwrap().isrc = Some("USABC9900001".to_string());
+
+        let mut buf = vec![0; SECTOR_SIZE * 8];
+        let files = rip_disc(&mut disc, &toc, &metadata, &config, &mut buf, || false).unwrap();
+        let album = directory.join("Artist/Album");
+        let cue = fs::read_to_string(album.join("Album.cue"));
+        let ripped = files.iter().all(|file| file.exists());
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            files,
+            [album.join("01 - One.flac"), album.join("02 - Two.flac")]
+        );
+        assert!(ripped);
+        let discid = toc.disc_id().freedb_hex().to_uppercase();
+        assert_eq!(
+            cue.unwrap(),
+            format!(
+                "CATALOG 0123456789012\n\
+                 REM DISCID {discid}\n\
+                 PERFORMER \"Artist\"\n\
+                 TITLE \"Album\"\n\
+                 FILE \"01 - One.flac\" WAVE\n\
+                 \x20 TRACK 01 AUDIO\n\
+                 \x20   FLAGS PRE\n\
+                 \x20   ISRC USABC9900001\n\
+                 \x20   TITLE \"One\"\n\
+                 \x20   PERFORMER \"Artist\"\n\
+                 \x20   INDEX 01 00:00:00\n\
+                 FILE \"02 - Two.flac\" WAVE\n\
+                 \x20 TRACK 02 AUDIO\n\
+                 \x20   TITLE \"Two\"\n\
+                 \x20   PERFORMER \"Artist\"\n\
+                 \x20   INDEX 01 00:00:00\n"
+            )
+        );
+    }
+
+    /// The tracks of `disc` ripped with `deemphasis`
+    fn rip(disc: &str, deemphasis: bool) -> Vec<Vec<u8>> {
+        let directory = std::env::temp_dir().join(format!(
+            "rip-deemphasis-{deemphasis}-{}",
+            std::process::id()
+        ));
+        let mut config = Config::default();
+        config.rip.directory = directory.clone();
+        config.rip.deemphasis = deemphasis;
+        config.accuraterip.database = directory.join("nowhere");
+        let mut disc: FakeDisc = disc.parse().unwrap();
+        let toc = Toc::read(&mut disc).unwrap();
+        let mut buf = vec![0; SECTOR_SIZE * 8];
+        let files = rip_disc(&mut disc, &toc, &metadata(), &config, &mut buf, || false).unwrap();
+        let flac = files.iter().map(|file| fs::read(file).unwrap()).collect();
+        fs::remove_dir_all(&directory).unwrap();
+        flac
+    }
+
+    #[test]
+    fn deemphasis() {
+        let spec = "sine:1:pre,sine:1";
+        let mut disc: FakeDisc = spec.parse().unwrap();
+        let mut pcm = vec![0; 150 * SECTOR_SIZE];
+        disc.read_audio_sectors(&mut pcm, 0, 150).unwrap();
+        let (first, second) = pcm.split_at(75 * SECTOR_SIZE);
+        let mut deemphasized = first.to_vec();
+        Deemphasis::default().process(&mut deemphasized);
+        let flac = |track, pcm: &[u8]| {
+            let mut chunks = pcm.chunks(SECTOR_SIZE * 8);
+            let tags = track_tags(&metadata(), track, 2);
+            encode(|| Ok(chunks.next().map(<[u8]>::to_vec)), &tags).unwrap()
+        };
+
+        // Only if asked to, and only the tracks with pre-emphasis
+        assert_eq!(rip(spec, false), [flac(1, first), flac(2, second)]);
+        assert_eq!(rip(spec, true), [flac(1, &deemphasized), flac(2, second)]);
+    }
+}
@@ -1,26 +1,117 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use flume::{Receiver, Sender};
 use std::sync::{MutexGuard, RwLock};
 
-use crate::action::Action;
+use crate::{
+    accuraterip::Verification, action::Action, database::Release, metadata::DiscMetadata, toc::Toc,
+};
+
+/// Stereo samples per second of CD-DA
+pub const FRAMES_PER_SECOND: u64 = 44100;
+/// Stereo samples in a single CD-DA sector
+pub const FRAMES_PER_SECTOR: u64 = 588;
 
 pub enum Request {
     TogglePlay,
+    Play,
+    Pause,
+    Stop,
     NextTrack,
     PreviousTrack,
+    /// Move to the next index point of the current track
+    NextIndex,
+    /// Move to the start of the index point before the current one
+    PreviousIndex,
     SeekForward,
     SeekBackward,
+    /// Move the playback position by this many microseconds, backwards if negative
+    Seek(i64),
+    /// Move the playback position to an absolute position within the current track
+    SetPosition(Position),
+    /// Stop playing and open the tray
+    Eject,
+    /// Stop playing and rip the whole disc
+    Rip,
     None,
     Quit,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub enum Position {
+    Sector(u32),
+    Time(Duration),
+}
+
+impl Position {
+    /// The position as a number of stereo samples from the start of the track
+    pub fn frames(self) -> u64 {
+        match self {
+            Position::Sector(sector) => sector as u64 * FRAMES_PER_SECTOR,
+            Position::Time(time) => {
+                (time.as_micros() * FRAMES_PER_SECOND as u128 / 1_000_000) as u64
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopStatus {
+    None,
+    /// Play the current track again and again
+    Track,
+    /// Start again from the first track after the last one
+    Playlist,
+}
+
+/// The last position jumped to, by seeking or changing track; the reader and the player keep track
+/// of the `id` they have already handled
+#[derive(Clone, Copy, Debug)]
+pub struct Seek {
+    pub id: u32,
+    pub track: u8,
+    pub frame: u64,
+}
+
 pub struct PlayerState {
     pub action: Action,
     pub state_changed: Arc<RwLock<bool>>,
+    /// The last track that can be played
     pub total_tracks: u8,
+    pub toc: Toc,
+    /// Bumped every time a new disc is loaded
+    pub disc_generation: u32,
+    /// What we know about the disc, from CD-Text or otherwise
+    pub metadata: DiscMetadata,
+    /// How the tracks read from start to end compare to AccurateRip, by track number
+    pub verification: BTreeMap<u8, Verification>,
+    pub seek: Seek,
+    /// How far SeekForward and SeekBackward move the playback position
+    pub seek_step: Duration,
+    /// Stereo samples played so far in the current track, updated by the player
+    pub position: Arc<RwLock<u64>>,
+    /// The index point of the current track being played, 0 in a pregap
+    pub index: u8,
+    pub loop_status: LoopStatus,
+    pub shuffle: bool,
+    /// The order tracks are played in when shuffling
+    shuffle_order: Vec<u8>,
+    /// Software volume, between 0 and 1
+    pub volume: f64,
+    /// Set when the disc has been asked to be ejected, the reader does it once stopped
+    pub eject: bool,
+    /// Set when the disc has been asked to be ripped, the reader does it once stopped
+    pub rip: bool,
+    /// Set when the whole program has been asked to exit
+    pub exiting: bool,
     changed: Sender<()>,
     wait_change: Receiver<()>,
+    /// Notified on every change, for those who need to tell the world about it
+    watchers: Vec<Sender<()>>,
 }
 
 unsafe impl Sync for PlayerState {}
@@ -33,8 +124,108 @@ impl PlayerState {
             changed: tx,
             wait_change: rx,
             total_tracks: 0,
+            toc: Toc::default(),
+            disc_generation: 0,
+            metadata: DiscMetadata::default(),
+            verification: BTreeMap::new(),
+            seek: Seek {
+                id: 0,
+                track: 1,
+                frame: 0,
+            },
+            seek_step: Duration::from_secs(10),
+            position: Arc::new(RwLock::new(0)),
+            index: 1,
+            loop_status: LoopStatus::None,
+            shuffle: false,
+            shuffle_order: Vec::new(),
+            volume: 1.0,
+            eject: false,
+            rip: false,
+            exiting: false,
+            watchers: Vec::new(),
         }
     }
+
+    /// Get notified every time the state changes
+    pub fn watch(&mut self) -> Receiver<()> {
+        let (tx, rx) = flume::bounded(1);
+        self.watchers.push(tx);
+        rx
+    }
+
+    fn notify_watchers(&self) {
+        for watcher in &self.watchers {
+            let _ = watcher.try_send(());
+        }
+    }
+
+    /// A new disc has been loaded, with `toc`
+    pub fn load_disc(&mut self, toc: Toc, metadata: DiscMetadata) {
+        self.total_tracks = toc.last_track();
+        self.toc = toc;
+        self.metadata = metadata;
+        self.verification.clear();
+        self.disc_generation = self.disc_generation.wrapping_add(1);
+        self.shuffle_order.clear();
+        self.eject = false;
+        self.rip = false;
+        // Mixed mode discs start with a data track, start from their first audio track instead
+        if let Action::Play(track) | Action::Pause(track) = self.action
+            && !self.toc.is_audio(track)
+            && let Some(&first_track) = self.toc.audio_tracks().first()
+        {
+            self.seek.track = first_track;
+            self.action = match self.action {
+                Action::Pause(_) => Action::Pause(first_track),
+                _ => Action::Play(first_track),
+            };
+        }
+        self.notify_watchers();
+    }
+
+    /// Metadata about the disc loaded as `generation` has been found, if that disc is still in
+    pub fn update_metadata(&mut self, generation: u32, release: Release) {
+        if self.disc_generation == generation {
+            self.metadata.apply(release, &self.toc);
+            self.notify_watchers();
+        }
+    }
+
+    /// The index points of `track` have been found, after the disc was loaded
+    pub fn set_indexes(&mut self, track: u8, indexes: Vec<i32>) {
+        if let Some(toc_track) = self
+            .toc
+            .tracks
+            .iter_mut()
+            .find(|toc_track| toc_track.number == track)
+        {
+            toc_track.indexes = indexes;
+            self.update_index();
+        }
+    }
+
+    /// `track` has been read whole and checked
+    pub fn track_verified(&mut self, track: u8, verification: Verification) {
+        self.verification.insert(track, verification);
+        self.notify_watchers();
+    }
+
+    pub fn set_loop_status(&mut self, loop_status: LoopStatus) {
+        self.loop_status = loop_status;
+        self.notify_watchers();
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        self.shuffle_order.clear();
+        self.notify_watchers();
+    }
+
+    pub fn set_volume(&mut self, volume: f64) {
+        self.volume = volume.clamp(0., 1.);
+        self.notify_watchers();
+    }
     pub fn wait_for_change(self: MutexGuard<Self>) {
         let wait_change = self.wait_change.clone();
         drop(self);
@@ -46,56 +237,336 @@ impl PlayerState {
         *self.state_changed.write().unwrap() = true;
         let _ = self.changed.try_send(());
         let _ = self.changed.try_send(());
+        self.notify_watchers();
+    }
+
+    /// The order the tracks are played in, shuffled or not, starting from `track` when shuffling
+    fn play_order(&mut self, track: u8) -> Vec<u8> {
+        let audio_tracks = self.toc.audio_tracks();
+        if !self.shuffle {
+            return audio_tracks;
+        }
+        if self.shuffle_order.len() != audio_tracks.len() {
+            self.shuffle_order = shuffle_tracks(audio_tracks, track);
+        }
+        self.shuffle_order.clone()
+    }
+
+    fn following_track(&mut self, track: u8) -> Option<u8> {
+        // The hidden track leads into the disc
+        if track == 0 {
+            return self.play_order(1).first().copied();
+        }
+        let order = self.play_order(track);
+        let i = order.iter().position(|&t| t == track)?;
+        order
+            .get(i + 1)
+            .copied()
+            .or_else(|| (self.loop_status == LoopStatus::Playlist).then_some(order[0]))
+    }
+
+    fn preceding_track(&mut self, track: u8) -> Option<u8> {
+        let order = self.play_order(track);
+        let i = order.iter().position(|&t| t == track)?;
+        match i.checked_sub(1) {
+            Some(i) => Some(order[i]),
+            // The hidden track is before the first one, when playing in order
+            None => (track == 1 && !self.shuffle && self.toc.hidden_track.is_some()).then_some(0),
+        }
+    }
+
+    /// Whether Next would move to another track rather than stop, taking the loop status and
+    /// the shuffle order into account
+    pub fn has_following_track(&mut self) -> bool {
+        match self.action {
+            Action::Play(track) | Action::Pause(track) => self.following_track(track).is_some(),
+            Action::Stop | Action::Quit => false,
+        }
+    }
+
+    pub fn next_track(mut self: MutexGuard<Self>) {
+        match self.action {
+            Action::Play(track) | Action::Pause(track) => match self.following_track(track) {
+                Some(next_track) => self.play_track(next_track),
+                // The CD has finished
+                None => self.change_action(Action::Stop),
+            },
+            Action::Stop | Action::Quit => {}
+        }
+    }
+
+    /// The player has reached the end of the current track, which flows into the next one
+    /// without any jump, unless we are looping or shuffling
+    pub fn track_ended(mut self: MutexGuard<Self>) {
+        if let Action::Play(track) = self.action {
+            *self.position.write().unwrap() = 0;
+            if self.loop_status == LoopStatus::Track {
+                self.play_track(track);
+                return;
+            }
+            // The reader reads ahead the audio tracks in disc order
+            let read_ahead = self.toc.next_audio_track(track);
+            match self.following_track(track) {
+                Some(next_track) if Some(next_track) == read_ahead => {
+                    self.change_action(Action::Play(next_track))
+                }
+                // The reader has read ahead the wrong track
+                Some(next_track) => self.play_track(next_track),
+                // The CD has finished
+                None => self.change_action(Action::Stop),
+            }
+        }
     }
 
-    pub fn next_track(self: MutexGuard<Self>) {
+    pub fn prev_track(mut self: MutexGuard<Self>) {
         match self.action {
-            Action::Play(track) | Action::Pause(track) => {
-                let next_track = track + 1;
-                let action = if next_track < self.total_tracks.into() {
-                    Action::Play(next_track)
-                } else {
-                    // The CD has finished
-                    Action::Stop
-                };
-                self.change_action(action);
+            Action::Play(track) | Action::Pause(track) => match self.preceding_track(track) {
+                Some(prev_track) => self.play_track(prev_track),
+                // Restart the first track
+                None => self.seek(0),
+            },
+            Action::Stop => {
+                // Start again from the end of the disc, if it has any audio
+                let total_tracks = self.total_tracks;
+                if let Some(&last_track) = self.play_order(total_tracks).last() {
+                    self.play_track(last_track);
+                }
             }
-            Action::Stop => {}
+            Action::Quit => {}
         }
     }
 
-    pub fn prev_track(self: MutexGuard<Self>) {
+    /// The player has moved on, tell the world when it enters another index point
+    pub fn update_index(&mut self) {
+        if let Action::Play(track) | Action::Pause(track) = self.action {
+            let sector = *self.position.read().unwrap() / FRAMES_PER_SECTOR;
+            let index = self.toc.index_at(track, sector as i32);
+            if index != self.index {
+                self.index = index;
+                self.notify_watchers();
+            }
+        }
+    }
+
+    /// Where the index points of the current track start, in stereo samples
+    fn index_frames(&self) -> Vec<u64> {
         match self.action {
-            Action::Play(track) | Action::Pause(track) => {
-                let prev_track = track - 1;
-                let track_to_play = if prev_track >= 1 { prev_track } else { track };
-                self.change_action(Action::Play(track_to_play));
+            Action::Play(track) | Action::Pause(track) => self
+                .toc
+                .index_starts(track)
+                .into_iter()
+                .map(|sector| sector as u64 * FRAMES_PER_SECTOR)
+                .collect(),
+            Action::Stop | Action::Quit => Vec::new(),
+        }
+    }
+
+    pub fn next_index(self: MutexGuard<Self>) {
+        let position = *self.position.read().unwrap();
+        // Past the last index point there is nowhere to go
+        if let Some(frame) = self
+            .index_frames()
+            .into_iter()
+            .find(|&frame| frame > position)
+        {
+            self.seek(frame);
+        }
+    }
+
+    pub fn prev_index(self: MutexGuard<Self>) {
+        let position = *self.position.read().unwrap();
+        let starts = self.index_frames();
+        // From the first index point, back to the start of the track
+        if let Some(current) = starts.iter().rposition(|&frame| frame <= position) {
+            let frame = starts[current.saturating_sub(1)];
+            self.seek(frame);
+        }
+    }
+
+    /// Start playing `track` from its beginning, dropping whatever has been read ahead
+    pub fn play_track(self: MutexGuard<Self>, track: u8) {
+        self.jump(Action::Play(track), 0);
+    }
+
+    /// Move the playback position of the current track to `frame`
+    pub fn seek(self: MutexGuard<Self>, frame: u64) {
+        match self.action {
+            Action::Play(_) | Action::Pause(_) => {
+                let action = self.action;
+                self.jump(action, frame);
             }
-            Action::Stop => {}
+            Action::Stop | Action::Quit => {}
+        }
+    }
+
+    /// Switch to `action`, with the reader and the player starting again from `frame`
+    fn jump(mut self: MutexGuard<Self>, action: Action, frame: u64) {
+        if let Action::Play(track) | Action::Pause(track) = action {
+            self.seek = Seek {
+                id: self.seek.id.wrapping_add(1),
+                track,
+                frame,
+            };
+            *self.position.write().unwrap() = frame;
+            self.index = self.toc.index_at(track, (frame / FRAMES_PER_SECTOR) as i32);
+            self.change_action(action);
         }
     }
 
-    pub fn handle_request(self: MutexGuard<Self>, req: Request) {
+    fn seek_by(self: MutexGuard<Self>, micros: i64) {
+        let position = *self.position.read().unwrap() as i64;
+        let frame = position + micros * FRAMES_PER_SECOND as i64 / 1_000_000;
+        self.seek(frame.max(0) as u64);
+    }
+
+    pub fn handle_request(mut self: MutexGuard<Self>, req: Request) {
         match req {
             Request::TogglePlay => match self.action {
                 Action::Play(track) => {
                     self.change_action(Action::Pause(track));
                 }
+                Action::Pause(_) | Action::Stop => self.handle_request(Request::Play),
+                Action::Quit => {}
+            },
+            Request::Play => match self.action {
                 Action::Pause(track) => {
                     self.change_action(Action::Play(track));
                 }
-                Action::Stop => todo!(),
+                Action::Stop => {
+                    // Start again from the beginning of the disc, if it has any audio
+                    if let Some(&first_track) = self.play_order(1).first() {
+                        self.play_track(first_track);
+                    }
+                }
+                Action::Play(_) | Action::Quit => {}
             },
+            Request::Pause => {
+                if let Action::Play(track) = self.action {
+                    self.change_action(Action::Pause(track));
+                }
+            }
+            Request::Stop => {
+                if let Action::Play(_) | Action::Pause(_) = self.action {
+                    self.change_action(Action::Stop);
+                }
+            }
             Request::NextTrack => {
                 self.next_track();
             }
             Request::PreviousTrack => {
                 self.prev_track();
             }
-            Request::SeekForward => todo!(),
-            Request::SeekBackward => todo!(),
+            Request::NextIndex => {
+                self.next_index();
+            }
+            Request::PreviousIndex => {
+                self.prev_index();
+            }
+            Request::SeekForward => {
+                let step = self.seek_step.as_micros() as i64;
+                self.seek_by(step);
+            }
+            Request::SeekBackward => {
+                let step = self.seek_step.as_micros() as i64;
+                self.seek_by(-step);
+            }
+            Request::Seek(micros) => {
+                self.seek_by(micros);
+            }
+            Request::SetPosition(position) => {
+                self.seek(position.frames());
+            }
+            Request::Eject => {
+                self.eject = true;
+                self.change_action(Action::Stop);
+            }
+            Request::Rip => {
+                self.rip = true;
+                self.change_action(Action::Stop);
+            }
             Request::None => {}
-            Request::Quit => {}
+            Request::Quit => {
+                self.exiting = true;
+                self.change_action(Action::Quit);
+            }
         }
     }
 }
+
+/// Shuffle `tracks`, putting `first` in front
+fn shuffle_tracks(tracks: Vec<u8>, first: u8) -> Vec<u8> {
+    // A xorshift is random enough to shuffle a CD
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+        | 1;
+    let found = tracks.contains(&first);
+    let mut tracks = tracks
+        .into_iter()
+        .filter(|&track| track != first)
+        .collect::<Vec<_>>();
+    for i in (1..tracks.len()).rev() {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        tracks.swap(i, (seed % (i as u64 + 1)) as usize);
+    }
+    if found {
+        tracks.insert(0, first);
+    }
+    tracks
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::fake_disc::FakeDisc;
+
+    fn state(spec: &str) -> Mutex<PlayerState> {
+        let (tx, rx) = flume::bounded(2);
+        let state = Mutex::new(PlayerState::new(tx, rx));
+        let mut disc: FakeDisc = spec.parse().unwrap();
+        let toc = Toc::read(&mut disc).unwrap();
+        state
+            .lock()
+            .unwrap()
+            .load_disc(toc, DiscMetadata::default());
+        state.lock().unwrap().change_action(Action::Stop);
+        state
+    }
+
+    #[test]
+    fn from_stop() {
+        let state = state("data:10,sine:10,sine:10/data:10");
+        state.lock().unwrap().handle_request(Request::Play);
+        assert_eq!(state.lock().unwrap().action, Action::Play(2));
+        state.lock().unwrap().change_action(Action::Stop);
+        state.lock().unwrap().handle_request(Request::PreviousTrack);
+        assert_eq!(state.lock().unwrap().action, Action::Play(3));
+    }
+
+    #[test]
+    fn nothing_to_play() {
+        let state = state("data:10");
+        for request in [Request::Play, Request::PreviousTrack, Request::NextTrack] {
+            state.lock().unwrap().handle_request(request);
+            assert_eq!(state.lock().unwrap().action, Action::Stop);
+        }
+    }
+
+    #[test]
+    fn following_track() {
+        let state = state("sine:10,sine:10/data:10");
+        assert!(!state.lock().unwrap().has_following_track());
+        state.lock().unwrap().change_action(Action::Play(1));
+        assert!(state.lock().unwrap().has_following_track());
+        // The data track isn't played
+        state.lock().unwrap().change_action(Action::Pause(2));
+        assert!(!state.lock().unwrap().has_following_track());
+        state.lock().unwrap().set_loop_status(LoopStatus::Playlist);
+        assert!(state.lock().unwrap().has_following_track());
+    }
+}
@@ -0,0 +1,553 @@
+use std::ops::Range;
+
+use color_eyre::{eyre::bail, Result};
+use log::warn;
+
+use crate::{
+    disc::{DiscSource, SESSION_GAP},
+    disc_id::{DiscId, SECTORS_PER_SECOND},
+};
+
+/// Libcdio's track number for the lead-out
+const LEAD_OUT_TRACK: u8 = 0xAA;
+/// How far before the start of a track its pregap is looked for first; they are mostly two
+/// seconds long
+const PREGAP_WINDOW: i32 = 10 * SECTORS_PER_SECOND;
+
+/// A track as listed in the TOC
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TocTrack {
+    pub number: u8,
+    /// The LSN of the track start (INDEX 01)
+    pub start: i32,
+    /// The first LSN after the track, the pregap of the next one included
+    pub end: i32,
+    /// Data tracks can't be played
+    pub audio: bool,
+    /// Where the pregap (INDEX 00) starts, `start` if there is none. It is played at the end of
+    /// the track before, or as the hidden track before the first one.
+    pub pregap: i32,
+    /// Where INDEX 02 onwards start, marking the parts of the track
+    pub indexes: Vec<i32>,
+    /// The audio was mastered with pre-emphasis, and has to be de-emphasized
+    pub preemphasis: bool,
+}
+
+impl TocTrack {
+    /// The sectors of the track, empty for data tracks: there is nothing to play in them
+    pub fn sectors(&self) -> (i32, i32) {
+        if self.audio {
+            (self.start, self.end)
+        } else {
+            (self.start, self.start)
+        }
+    }
+}
+
+/// The table of contents of a disc
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Toc {
+    /// In order, numbered from `first_track` on without gaps
+    pub tracks: Vec<TocTrack>,
+    /// The LSN of the lead-out of the disc, after the last session
+    pub lead_out: i32,
+    /// The audio hidden in the pregap of the first track, played as track 0
+    pub hidden_track: Option<TocTrack>,
+    /// Whether the tracks end where the pregap of the next one starts, leaving it out
+    pub skip_pregaps: bool,
+}
+
+impl Toc {
+    /// Check and sort out the TOC of a disc. The tracks end where the next one starts at the
+    /// latest, the last one at the lead-out. A track right before the session starting at
+    /// `last_session` ends with its own session instead. The pregaps don't reach back past the
+    /// start of the track before.
+    pub fn new(mut tracks: Vec<TocTrack>, lead_out: i32, last_session: i32) -> Result<Self> {
+        if tracks.is_empty() || tracks.len() > 99 {
+            bail!("a disc must have between 1 and 99 tracks");
+        }
+        for i in 0..tracks.len() {
+            let previous_start = i
+                .checked_sub(1)
+                .map_or(0, |previous| tracks[previous].start + 1);
+            if i > 0 && tracks[i].number != tracks[i - 1].number + 1 {
+                bail!(
+                    "track {} follows track {}",
+                    tracks[i].number,
+                    tracks[i - 1].number
+                );
+            }
+            let next = tracks.get(i + 1).map_or(lead_out, |next| next.start);
+            let track = &mut tracks[i];
+            track.end = track.end.min(next);
+            // The gap between the sessions can't be read
+            if last_session > 0
+                && track.start < last_session
+                && track.end > last_session - SESSION_GAP
+            {
+                track.end = last_session - SESSION_GAP;
+            }
+            if track.start < 0 || track.end <= track.start {
+                bail!("track {} is empty", track.number);
+            }
+            track.pregap = track
+                .pregap
+                .clamp(previous_start.min(track.start), track.start);
+            track
+                .indexes
+                .retain(|&index| index > track.start && index < track.end);
+        }
+        // Audio before the first track, where the CD players never go
+        let hidden_track = tracks
+            .first()
+            .filter(|first| first.number == 1 && first.audio && first.pregap < first.start)
+            .map(|first| TocTrack {
+                number: 0,
+                start: first.pregap,
+                end: first.start,
+                audio: true,
+                pregap: first.pregap,
+                indexes: Vec::new(),
+                // Mastered along with the first track
+                preemphasis: first.preemphasis,
+            });
+        Ok(Self {
+            tracks,
+            lead_out,
+            hidden_track,
+            skip_pregaps: false,
+        })
+    }
+
+    /// Read the TOC of `source`, with the pregaps if it can tell them. The index points are left
+    /// to `read_index_points`.
+    pub fn read(source: &mut dyn DiscSource) -> Result<Self> {
+        let first_track = source.first_track_num()?;
+        let last_track = source.last_track_num()?;
+        let tracks = (first_track..=last_track)
+            .map(|number| {
+                let start = source.track_lsn(number)?;
+                let audio = source.track_is_audio(number)?;
+                Ok(TocTrack {
+                    number,
+                    start,
+                    end: source.track_last_lsn(number)? + 1,
+                    audio,
+                    // Whatever comes before the first track is its pregap
+                    pregap: if number == first_track { 0 } else { start },
+                    indexes: Vec::new(),
+                    preemphasis: audio && source.track_preemphasis(number)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut toc = Self::new(
+            tracks,
+            source.track_lsn(LEAD_OUT_TRACK)?,
+            source.last_session_lsn()?,
+        )?;
+        if let Err(err) = toc.read_pregaps(source) {
+            warn!("unable to read the pregaps: {err}");
+        }
+        Ok(toc)
+    }
+
+    /// Find the pregaps of the audio tracks, from the disc image or the Q subchannel. Only the
+    /// last few seconds of every track are searched, unless the pregap reaches back further.
+    fn read_pregaps(&mut self, source: &mut dyn DiscSource) -> Result<()> {
+        for i in 1..self.tracks.len() {
+            let (previous, track) = (&self.tracks[i - 1], &self.tracks[i]);
+            if !previous.audio || !track.audio {
+                continue;
+            }
+            let pregap = match source.track_pregap_lsn(track.number)? {
+                Some(pregap) => pregap,
+                None => {
+                    // The pregap is within the track before, but its Q subchannel tells the next
+                    // one
+                    let in_pregap = |(number, _)| number == track.number;
+                    let window = (track.start - PREGAP_WINDOW).max(previous.start + 1);
+                    match first_sector(source, window..track.start, in_pregap)? {
+                        Some(pregap) if pregap == window && window > previous.start + 1 => {
+                            match first_sector(source, previous.start + 1..window, in_pregap)? {
+                                Some(pregap) => pregap,
+                                None => return Ok(()),
+                            }
+                        }
+                        Some(pregap) => pregap,
+                        None => return Ok(()),
+                    }
+                }
+            };
+            let pregap = pregap.clamp(previous.start + 1, track.start);
+            self.tracks[i].pregap = pregap;
+        }
+        Ok(())
+    }
+
+    /// Find the index points of `number` from the Q subchannel: the index of its last sector
+    /// tells how many there are, then every one takes a binary search within the track. Done
+    /// when the track is about to be read rather than for the whole disc, as it takes a seek
+    /// for every sector looked at.
+    pub fn read_index_points(&mut self, source: &mut dyn DiscSource, number: u8) -> Result<()> {
+        let Some(i) = number
+            .checked_sub(self.first_track())
+            .map(usize::from)
+            .filter(|_| number > 0)
+        else {
+            return Ok(());
+        };
+        let Some(track) = self.tracks.get(i) else {
+            return Ok(());
+        };
+        let end = self
+            .tracks
+            .get(i + 1)
+            .map_or(track.end, |next| next.pregap.min(track.end));
+        if !track.audio || end <= track.start {
+            return Ok(());
+        }
+        let Some((found, last_index)) = source.track_index(end - 1)? else {
+            return Ok(());
+        };
+        if found != number {
+            return Ok(());
+        }
+        let mut indexes = Vec::new();
+        let mut from = track.start;
+        for index in 2..=last_index {
+            let Some(lsn) = first_sector(source, from..end, |(_, at)| at >= index)? else {
+                return Ok(());
+            };
+            indexes.push(lsn);
+            from = lsn;
+        }
+        self.tracks[i].indexes = indexes;
+        Ok(())
+    }
+
+    pub fn first_track(&self) -> u8 {
+        self.tracks.first().map_or(1, |track| track.number)
+    }
+
+    /// The last track that can be played; the data tracks after it don't count
+    pub fn last_track(&self) -> u8 {
+        self.tracks
+            .iter()
+            .rev()
+            .find(|track| track.audio)
+            .map_or(0, |track| track.number)
+    }
+
+    /// Track 0 is the hidden one, if any
+    pub fn track(&self, number: u8) -> Option<&TocTrack> {
+        if number == 0 {
+            return self.hidden_track.as_ref();
+        }
+        let index = number.checked_sub(self.first_track())?;
+        self.tracks.get(index as usize)
+    }
+
+    /// Whether `number` is a track with any audio
+    pub fn is_audio(&self, number: u8) -> bool {
+        self.track(number).is_some_and(|track| track.audio)
+    }
+
+    /// The tracks that can be played, in order, the hidden one aside
+    pub fn audio_tracks(&self) -> Vec<u8> {
+        self.tracks
+            .iter()
+            .filter(|track| track.audio)
+            .map(|track| track.number)
+            .collect()
+    }
+
+    /// Where `number` is among the audio tracks, from 1, as AccurateRip numbers them
+    pub fn audio_position(&self, number: u8) -> Option<u8> {
+        self.tracks
+            .iter()
+            .filter(|track| track.audio)
+            .position(|track| track.number == number)
+            .map(|i| i as u8 + 1)
+    }
+
+    /// The audio track following `number` on the disc
+    pub fn next_audio_track(&self, number: u8) -> Option<u8> {
+        self.tracks
+            .iter()
+            .find(|track| track.number > number && track.audio)
+            .map(|track| track.number)
+    }
+
+    /// Where INDEX 01 onwards of `number` start, in sectors from the start of the track
+    pub fn index_starts(&self, number: u8) -> Vec<i32> {
+        self.track(number).map_or_else(Vec::new, |track| {
+            [track.start]
+                .iter()
+                .chain(&track.indexes)
+                .map(|lsn| lsn - track.start)
+                .collect()
+        })
+    }
+
+    /// The index `sector` sectors into `number` is in: 0 in the pregap of the next track, which is
+    /// played at the end of this one, and in the hidden track
+    pub fn index_at(&self, number: u8, sector: i32) -> u8 {
+        let Some(track) = self.track(number).filter(|_| number > 0) else {
+            return 0;
+        };
+        if self
+            .track(number + 1)
+            .is_some_and(|next| track.start + sector >= next.pregap)
+        {
+            return 0;
+        }
+        self.index_starts(number)
+            .iter()
+            .filter(|&&start| start <= sector)
+            .count() as u8
+    }
+
+    /// The sectors of `number` that are played, empty for data tracks and tracks that aren't on
+    /// the disc
+    pub fn sectors(&self, number: u8) -> (i32, i32) {
+        let Some(track) = self.track(number) else {
+            return (0, 0);
+        };
+        let (start, end) = track.sectors();
+        match self.track(number + 1) {
+            // The hidden track is a pregap already
+            Some(next) if self.skip_pregaps && number > 0 => {
+                (start, end.min(next.pregap.max(start)))
+            }
+            _ => (start, end),
+        }
+    }
+
+    /// Where the audio of the disc ends, before the data session of an enhanced CD
+    pub fn audio_end(&self) -> i32 {
+        self.track(self.last_track()).map_or(0, |track| track.end)
+    }
+
+    pub fn disc_id(&self) -> DiscId {
+        let track_lsns = self
+            .tracks
+            .iter()
+            .map(|track| track.start)
+            .collect::<Vec<_>>();
+        let audio_lsns = self
+            .tracks
+            .iter()
+            .filter(|track| track.audio)
+            .map(|track| track.start)
+            .collect::<Vec<_>>();
+        DiscId::new(
+            self.first_track(),
+            &track_lsns,
+            self.lead_out,
+            &audio_lsns,
+            self.audio_end(),
+        )
+    }
+}
+
+/// The first sector in `sectors` whose track and index, as told by the Q subchannel, satisfy
+/// `after`, which holds for every sector following it too. `sectors.end` if there is none, None
+/// if the Q subchannel can't be read.
+fn first_sector(
+    source: &mut dyn DiscSource,
+    sectors: Range<i32>,
+    after: impl Fn((u8, u8)) -> bool,
+) -> Result<Option<i32>> {
+    let (mut low, mut high) = (sectors.start, sectors.end);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let Some(position) = source.track_index(middle)? else {
+            return Ok(None);
+        };
+        if after(position) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    Ok(Some(low))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_disc::FakeDisc;
+
+    fn read(spec: &str) -> Toc {
+        let mut disc: FakeDisc = spec.parse().unwrap();
+        Toc::read(&mut disc).unwrap()
+    }
+
+    fn track(number: u8, start: i32, end: i32) -> TocTrack {
+        TocTrack {
+            number,
+            start,
+            end,
+            audio: true,
+            pregap: start,
+            indexes: Vec::new(),
+            preemphasis: false,
+        }
+    }
+
+    #[test]
+    fn last_track_ends_at_the_lead_out() {
+        let toc = read("sine:10,sine:20");
+        assert_eq!(toc.lead_out, 2250);
+        assert_eq!(toc.tracks[0].sectors(), (0, 750));
+        assert_eq!(toc.tracks[1].sectors(), (750, 2250));
+        assert_eq!(toc.audio_end(), 2250);
+
+        // Even if the drive says otherwise
+        let tracks = vec![track(1, 0, 750), track(2, 750, 3000)];
+        let toc = Toc::new(tracks, 2250, 0).unwrap();
+        assert_eq!(toc.tracks[1].end, 2250);
+    }
+
+    #[test]
+    fn single_track() {
+        let toc = read("sine:10");
+        assert_eq!(toc.tracks, vec![track(1, 0, 750)]);
+        assert_eq!(toc.hidden_track, None);
+        assert_eq!((toc.first_track(), toc.last_track()), (1, 1));
+        assert_eq!(toc.audio_tracks(), vec![1]);
+        assert_eq!(toc.next_audio_track(1), None);
+        assert_eq!(toc.sectors(1), (0, 750));
+        assert_eq!(toc.audio_end(), 750);
+    }
+
+    #[test]
+    fn enhanced_cd() {
+        let toc = read("sine:10,sine:20/data:30");
+        let data = 2250 + SESSION_GAP;
+        assert_eq!(toc.tracks[1].sectors(), (750, 2250));
+        assert_eq!(toc.tracks[2].start, data);
+        assert!(!toc.tracks[2].audio);
+        assert_eq!(toc.lead_out, data + 2250);
+        assert_eq!(toc.last_track(), 2);
+        assert_eq!(toc.audio_tracks(), vec![1, 2]);
+        assert_eq!(toc.next_audio_track(2), None);
+        assert_eq!(toc.sectors(3), (data, data));
+        assert_eq!(toc.audio_end(), 2250);
+
+        // The gap between the sessions is left out of the last audio track, which libcdio ends
+        // where the data track starts
+        let tracks = vec![
+            track(1, 0, 750),
+            track(2, 750, data),
+            track(3, data, data + 2250),
+        ];
+        let toc = Toc::new(tracks, data + 2250, data).unwrap();
+        assert_eq!(toc.tracks[1].end, 2250);
+    }
+
+    #[test]
+    fn disc_id_of_an_enhanced_cd() {
+        let plain = read("sine:10,sine:20").disc_id();
+        let enhanced = read("sine:10,sine:20/data:30").disc_id();
+        // MusicBrainz knows only about the audio session, FreeDB about every track
+        assert_eq!(enhanced.musicbrainz, plain.musicbrainz);
+        assert_eq!(
+            (enhanced.audio_lsns, enhanced.audio_end),
+            (vec![0, 750], 2250)
+        );
+        assert_eq!(enhanced.track_lsns, vec![0, 750, 2250 + SESSION_GAP]);
+        assert_eq!(enhanced.freedb & 0xff, 3);
+        assert_ne!(enhanced.freedb, plain.freedb);
+    }
+
+    #[test]
+    fn audio_positions() {
+        let positions = |spec| {
+            let toc = read(spec);
+            (1..=3)
+                .map(|number| toc.audio_position(number))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            positions("data:10,sine:10,sine:10"),
+            [None, Some(1), Some(2)]
+        );
+        assert_eq!(
+            positions("sine:10,sine:10/data:10"),
+            [Some(1), Some(2), None]
+        );
+    }
+
+    #[test]
+    fn pregaps() {
+        let mut toc = read("sine:10,sine:10+2,sine:10+1");
+        let pregaps = toc
+            .tracks
+            .iter()
+            .map(|track| track.pregap)
+            .collect::<Vec<_>>();
+        assert_eq!(pregaps, vec![0, 750, 1650]);
+        assert_eq!(toc.hidden_track, None);
+        // Played at the end of the track before
+        assert_eq!(toc.sectors(1), (0, 900));
+        assert_eq!(toc.sectors(2), (900, 1725));
+        assert_eq!(toc.sectors(3), (1725, 2475));
+
+        toc.skip_pregaps = true;
+        assert_eq!(toc.sectors(1), (0, 750));
+        assert_eq!(toc.sectors(2), (900, 1650));
+        assert_eq!(toc.sectors(3), (1725, 2475));
+    }
+
+    #[test]
+    fn hidden_track() {
+        let toc = read("sine:10+2,sine:10");
+        assert_eq!(toc.tracks[0].pregap, 0);
+        assert_eq!(
+            toc.hidden_track.as_ref().map(TocTrack::sectors),
+            Some((0, 150))
+        );
+        assert_eq!(toc.sectors(0), (0, 150));
+        assert_eq!(toc.sectors(1), (150, 900));
+        assert_eq!(toc.index_at(0, 0), 0);
+        // Not played between the tracks
+        assert_eq!(toc.audio_tracks(), vec![1, 2]);
+    }
+
+    #[test]
+    fn long_pregap() {
+        // Longer than what is searched first
+        let toc = read("sine:20,sine:10+15,sine:10+1");
+        assert_eq!(toc.tracks[1].pregap, 1500);
+        assert_eq!(toc.tracks[2].pregap, 3375);
+        // Within a track shorter than that
+        let toc = read("sine:5,sine:10+2");
+        assert_eq!(toc.tracks[1].pregap, 375);
+    }
+
+    #[test]
+    fn index_points() {
+        let mut disc: FakeDisc = "sine:10,sine:30+2@10@20,sine:10+1".parse().unwrap();
+        let mut toc = Toc::read(&mut disc).unwrap();
+        assert_eq!(toc.tracks[1].start, 900);
+        // Only when asked for
+        assert!(toc.tracks[1].indexes.is_empty());
+        for number in 1..=4 {
+            toc.read_index_points(&mut disc, number).unwrap();
+        }
+        assert_eq!(toc.tracks[1].indexes, vec![1650, 2400]);
+        assert!(toc.tracks[2].indexes.is_empty());
+        assert_eq!(toc.index_starts(1), vec![0]);
+        assert_eq!(toc.index_starts(2), vec![0, 750, 1500]);
+
+        let indexes =
+            [-1, 0, 749, 750, 1499, 1500, 2249, 2250].map(|sector| toc.index_at(2, sector));
+        // Before INDEX 01 is the pregap, after the last index point the pregap of the next track
+        assert_eq!(indexes, [0, 1, 1, 2, 2, 3, 3, 0]);
+        assert_eq!(toc.index_at(1, 749), 1);
+        assert_eq!(toc.index_at(1, 750), 0);
+        assert_eq!(toc.index_at(3, 0), 1);
+        assert_eq!(toc.index_at(4, 0), 0);
+    }
+}
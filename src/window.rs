@@ -0,0 +1,439 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use calloop::LoopSignal;
+use color_eyre::Result;
+use smithay_client_toolkit::{
+    compositor::{CompositorHandler, CompositorState},
+    delegate_compositor, delegate_keyboard, delegate_output, delegate_registry, delegate_seat,
+    delegate_shm, delegate_xdg_shell, delegate_xdg_window,
+    event_loop::WaylandSource,
+    output::{OutputHandler, OutputState},
+    reexports::client::{
+        protocol::{wl_keyboard, wl_output, wl_seat, wl_shm, wl_surface},
+        Connection, QueueHandle,
+    },
+    registry::{ProvidesRegistryState, RegistryState},
+    registry_handlers,
+    seat::{
+        keyboard::{KeyEvent, KeyboardHandler, Modifiers},
+        Capability, SeatHandler, SeatState,
+    },
+    shell::xdg::{
+        window::{Window, WindowConfigure, WindowHandler, XdgWindowState},
+        XdgShellHandler, XdgShellState,
+    },
+    shm::{
+        slot::{Buffer, SlotPool},
+        ShmHandler, ShmState,
+    },
+};
+
+use crate::{
+    config::Binding,
+    state::{PlayerState, Request},
+};
+
+/// Open a window on the Wayland compositor, if there is one, and handle its key presses in a
+/// thread of its own. Closing the window quits the player.
+pub fn spawn(
+    state: Arc<Mutex<PlayerState>>,
+    signal: LoopSignal,
+    bindings: HashMap<String, Binding>,
+) -> Result<JoinHandle<()>> {
+    let conn = Connection::connect_to_env()?;
+
+    Ok(thread::spawn(move || {
+        let event_queue = conn.new_event_queue();
+        let qh = event_queue.handle();
+
+        let mut event_loop = calloop::EventLoop::<SimpleWindow>::try_new().unwrap();
+
+        WaylandSource::new(event_queue)
+            .unwrap()
+            .insert(event_loop.handle())
+            .unwrap();
+
+        let mut simple_window = SimpleWindow {
+            registry_state: RegistryState::new(&conn, &qh),
+            seat_state: SeatState::new(),
+            output_state: OutputState::new(),
+            compositor_state: CompositorState::new(),
+            shm_state: ShmState::new(),
+            xdg_shell_state: XdgShellState::new(),
+            xdg_window_state: XdgWindowState::new(),
+
+            exit: false,
+            first_configure: true,
+            pool: None,
+            width: 256,
+            height: 256,
+            buffer: None,
+            window: None,
+            keyboard: None,
+
+            player_state: state.clone(),
+            bindings,
+        };
+
+        while !simple_window.registry_state.ready() {
+            event_loop.dispatch(None, &mut simple_window).unwrap();
+        }
+
+        let pool = SlotPool::new(
+            simple_window.width as usize * simple_window.height as usize * 4,
+            &simple_window.shm_state,
+        )
+        .expect("Failed to create pool");
+        simple_window.pool = Some(pool);
+
+        let surface = simple_window.compositor_state.create_surface(&qh).unwrap();
+
+        let window = Window::builder()
+            .title("raspi-cd-player")
+            // GitHub does not let projects use the `org.github` domain but the `io.github` domain is fine.
+            .min_size((256, 256))
+            .map(
+                &qh,
+                &simple_window.xdg_shell_state,
+                &mut simple_window.xdg_window_state,
+                surface,
+            )
+            .expect("window creation");
+
+        simple_window.window = Some(window);
+
+        // We don't draw immediately, the configure will notify us when to first draw.
+
+        while !simple_window.exit {
+            event_loop.dispatch(None, &mut simple_window).unwrap();
+        }
+
+        state.lock().unwrap().handle_request(Request::Quit);
+        // Let the main loop notice that we are exiting
+        signal.wakeup();
+    }))
+}
+
+struct SimpleWindow {
+    registry_state: RegistryState,
+    seat_state: SeatState,
+    output_state: OutputState,
+    compositor_state: CompositorState,
+    shm_state: ShmState,
+    xdg_shell_state: XdgShellState,
+    xdg_window_state: XdgWindowState,
+
+    exit: bool,
+    first_configure: bool,
+    pool: Option<SlotPool>,
+    width: u32,
+    height: u32,
+    buffer: Option<Buffer>,
+    window: Option<Window>,
+    keyboard: Option<wl_keyboard::WlKeyboard>,
+
+    player_state: Arc<Mutex<PlayerState>>,
+    /// The characters bound to a request
+    bindings: HashMap<String, Binding>,
+}
+
+impl CompositorHandler for SimpleWindow {
+    fn compositor_state(&mut self) -> &mut CompositorState {
+        &mut self.compositor_state
+    }
+
+    fn scale_factor_changed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _surface: &wl_surface::WlSurface,
+        _new_factor: i32,
+    ) {
+        // Not needed for this example.
+    }
+
+    fn frame(
+        &mut self,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+        _surface: &wl_surface::WlSurface,
+        _time: u32,
+    ) {
+        self.draw(conn, qh);
+    }
+}
+
+impl OutputHandler for SimpleWindow {
+    fn output_state(&mut self) -> &mut OutputState {
+        &mut self.output_state
+    }
+
+    fn new_output(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: wl_output::WlOutput,
+    ) {
+    }
+
+    fn update_output(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: wl_output::WlOutput,
+    ) {
+    }
+
+    fn output_destroyed(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _output: wl_output::WlOutput,
+    ) {
+    }
+}
+
+impl XdgShellHandler for SimpleWindow {
+    fn xdg_shell_state(&mut self) -> &mut XdgShellState {
+        &mut self.xdg_shell_state
+    }
+}
+
+impl WindowHandler for SimpleWindow {
+    fn xdg_window_state(&mut self) -> &mut XdgWindowState {
+        &mut self.xdg_window_state
+    }
+
+    fn request_close(&mut self, _: &Connection, _: &QueueHandle<Self>, _: &Window) {
+        self.exit = true;
+    }
+
+    fn configure(
+        &mut self,
+        conn: &Connection,
+        qh: &QueueHandle<Self>,
+        _window: &Window,
+        configure: WindowConfigure,
+        _serial: u32,
+    ) {
+        match configure.new_size {
+            Some(size) => {
+                self.width = size.0;
+                self.height = size.1;
+                self.buffer = None;
+            }
+            None => {
+                self.width = 256;
+                self.height = 256;
+                self.buffer = None;
+            }
+        }
+
+        // Initiate the first draw.
+        if self.first_configure {
+            self.first_configure = false;
+            self.draw(conn, qh);
+        }
+    }
+}
+
+impl SeatHandler for SimpleWindow {
+    fn seat_state(&mut self) -> &mut SeatState {
+        &mut self.seat_state
+    }
+
+    fn new_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_seat::WlSeat) {}
+
+    fn new_capability(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        seat: wl_seat::WlSeat,
+        capability: Capability,
+    ) {
+        if capability == Capability::Keyboard && self.keyboard.is_none() {
+            let keyboard = self
+                .seat_state
+                .get_keyboard(qh, &seat, None)
+                .expect("Failed to create keyboard");
+            self.keyboard = Some(keyboard);
+        }
+    }
+
+    fn remove_capability(
+        &mut self,
+        _conn: &Connection,
+        _: &QueueHandle<Self>,
+        _: wl_seat::WlSeat,
+        capability: Capability,
+    ) {
+        if capability == Capability::Keyboard && self.keyboard.is_some() {
+            self.keyboard.take().unwrap().release();
+        }
+    }
+
+    fn remove_seat(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_seat::WlSeat) {}
+}
+
+impl KeyboardHandler for SimpleWindow {
+    fn enter(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        _surface: &wl_surface::WlSurface,
+        _: u32,
+        _: &[u32],
+        _keysyms: &[u32],
+    ) {
+    }
+
+    fn leave(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        _surface: &wl_surface::WlSurface,
+        _: u32,
+    ) {
+    }
+
+    fn press_key(
+        &mut self,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        _: u32,
+        event: KeyEvent,
+    ) {
+        let req = match event {
+            KeyEvent {
+                time: _,
+                raw_code: _,
+                keysym: _,
+                utf8,
+            } => match utf8.and_then(|key| self.bindings.get(&key)) {
+                Some(binding) => binding.request(),
+                None => Request::None,
+            },
+        };
+
+        if matches!(req, Request::Quit) {
+            self.exit = true;
+        } else {
+            self.player_state.lock().unwrap().handle_request(req);
+        }
+    }
+
+    fn release_key(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        _: u32,
+        event: KeyEvent,
+    ) {
+    }
+
+    fn update_modifiers(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        _serial: u32,
+        modifiers: Modifiers,
+    ) {
+    }
+}
+
+impl ShmHandler for SimpleWindow {
+    fn shm_state(&mut self) -> &mut ShmState {
+        &mut self.shm_state
+    }
+}
+
+impl SimpleWindow {
+    pub fn draw(&mut self, _conn: &Connection, qh: &QueueHandle<Self>) {
+        if let Some(window) = self.window.as_ref() {
+            let width = self.width;
+            let height = self.height;
+            let stride = self.width as i32 * 4;
+            let pool = self.pool.as_mut().unwrap();
+
+            if self.buffer.is_none() {
+                self.buffer = Some(
+                    pool.create_buffer(
+                        width as i32,
+                        height as i32,
+                        stride,
+                        wl_shm::Format::Argb8888,
+                    )
+                    .expect("create buffer")
+                    .0,
+                );
+                let buffer = self.buffer.as_ref().unwrap();
+
+                let canvas = pool.canvas(buffer).unwrap();
+
+                canvas
+                    .chunks_exact_mut(4)
+                    .enumerate()
+                    .for_each(|(_, chunk)| {
+                        let a = 0xFF;
+                        let r = 0;
+                        let g = 0;
+                        let b = 0;
+                        let color: i32 = (a << 24) + (r << 16) + (g << 8) + b;
+
+                        let array: &mut [u8; 4] = chunk.try_into().unwrap();
+                        *array = color.to_le_bytes();
+                    });
+
+                // Damage the entire window
+                window
+                    .wl_surface()
+                    .damage_buffer(0, 0, self.width as i32, self.height as i32);
+
+                // Request our next frame
+                window.wl_surface().frame(qh, window.wl_surface().clone());
+
+                // Attach and commit to present.
+                buffer
+                    .attach_to(window.wl_surface())
+                    .expect("buffer attach");
+                window.wl_surface().commit();
+            }
+        }
+    }
+}
+
+delegate_compositor!(SimpleWindow);
+delegate_output!(SimpleWindow);
+delegate_shm!(SimpleWindow);
+
+delegate_seat!(SimpleWindow);
+delegate_keyboard!(SimpleWindow);
+
+delegate_xdg_shell!(SimpleWindow);
+delegate_xdg_window!(SimpleWindow);
+
+delegate_registry!(SimpleWindow);
+
+impl ProvidesRegistryState for SimpleWindow {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+    registry_handlers![
+        CompositorState,
+        OutputState,
+        ShmState,
+        SeatState,
+        XdgShellState,
+        XdgWindowState,
+    ];
+}